@@ -0,0 +1,39 @@
+// Builds interface management commands the way the controller does, with the
+// types of the rust_itti library, and prints them as a trace record that
+// `rust_itti --replay` hands to the network interface manager:
+//
+//     cargo run --example netif_controller > netif.jsonl
+//     rust_itti --config netif.json --replay netif.jsonl
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use rust_itti::netif_cmd::{decode_commands, encode_commands, NetIfSettings, NetworkIfCommand};
+use serde_json::json;
+
+fn main() {
+    let commands = vec![
+        NetworkIfCommand::Create(NetIfSettings::new("pdu1")
+            .with_mtu(1456)
+            .with_address(IpAddr::V4(Ipv4Addr::new(172, 26, 100, 101)), 32)
+            .with_route(IpAddr::V4(Ipv4Addr::new(10, 45, 0, 0)), 16)
+            .with_dns(IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8)))),
+        NetworkIfCommand::Update(NetIfSettings::new("pdu1")
+            .with_address(IpAddr::V6(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1)), 64)),
+        NetworkIfCommand::Delete { name: "pdu2".to_string() },
+    ];
+    let data = match encode_commands(&commands) {
+        Ok(data) => data,
+        Err(e) => {
+            eprintln!("commands not encoded: {}", e);
+            return;
+        },
+    };
+    assert_eq!(decode_commands(&data).as_ref(), Ok(&commands));
+    let record = json!({
+        "at_us": 0,
+        "src": null,
+        "dst": { "tag": "NetIfMgmt", "instance": 0 },
+        "correlation_id": 1,
+        "msg": { "NetIfMgmtCommand": { "data": data } },
+    });
+    println!("{}", record);
+}
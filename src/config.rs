@@ -0,0 +1,348 @@
+use std::{fs, io, net::SocketAddr, path::Path, sync::atomic::{AtomicBool, Ordering}};
+
+use serde::Deserialize;
+
+use crate::{itti::{MailboxConfig, OverflowPolicy}, supervisor::RestartPolicy};
+
+/// `debug` adds full dumps of every decoded message to the output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogLevel {
+    #[default]
+    Info,
+    Debug,
+}
+
+static DEBUG: AtomicBool = AtomicBool::new(false);
+
+pub fn set_log_level(level: LogLevel) {
+    DEBUG.store(level == LogLevel::Debug, Ordering::Relaxed);
+}
+
+pub fn debug_enabled() -> bool {
+    DEBUG.load(Ordering::Relaxed)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NasDecoderBackend {
+    /// Full decode to JSON by forking `tshark` for every message
+    #[default]
+    Tshark,
+    /// Only the built-in 5GSM decoder; messages carry no JSON decode
+    Native,
+}
+
+fn one_instance() -> u16 {
+    1
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NasDecoderConfig {
+    #[serde(default)]
+    pub backend: NasDecoderBackend,
+    /// Decoders the messages are sharded across, by UE or PDU session
+    #[serde(default = "one_instance")]
+    pub instances: u16,
+    #[serde(default = "NasDecoderConfig::default_mailbox")]
+    pub mailbox: MailboxConfig,
+    #[serde(default)]
+    pub restart: RestartPolicy,
+}
+
+impl NasDecoderConfig {
+    // Every decode may fork tshark, so a signalling burst is turned away
+    // instead of queueing without limit
+    fn default_mailbox() -> MailboxConfig {
+        MailboxConfig::bounded(256, OverflowPolicy::Reject)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PduSessionMgmtConfig {
+    /// Snapshot the sessions are restored from and saved to, none to not persist
+    #[serde(default = "PduSessionMgmtConfig::default_session_store")]
+    pub session_store: Option<String>,
+    /// Session managers the sessions are sharded across by PDU session ID.
+    /// Each keeps its own snapshot, so changing the count strands sessions
+    #[serde(default = "one_instance")]
+    pub instances: u16,
+    #[serde(default = "PduSessionMgmtConfig::default_mailbox")]
+    pub mailbox: MailboxConfig,
+    #[serde(default)]
+    pub restart: RestartPolicy,
+}
+
+impl PduSessionMgmtConfig {
+    fn default_session_store() -> Option<String> {
+        Some("pdu_sessions.json".to_string())
+    }
+
+    fn default_mailbox() -> MailboxConfig {
+        MailboxConfig::bounded(1024, OverflowPolicy::Block)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TimerConfig {
+    /// Time only moves on `TimerAdvance` messages, e.g. from a replayed
+    /// trace, so timers fire at the same point of every run
+    #[serde(default)]
+    pub virtual_time: bool,
+    #[serde(default = "MailboxConfig::unbounded")]
+    pub mailbox: MailboxConfig,
+    #[serde(default)]
+    pub restart: RestartPolicy,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GtpUdpConfig {
+    #[serde(default = "GtpUdpConfig::default_bind")]
+    pub bind: SocketAddr,
+    /// Seconds between Echo Requests to each peer, 0 to not send any
+    #[serde(default = "GtpUdpConfig::default_echo_interval_secs")]
+    pub echo_interval_secs: u64,
+    /// Unanswered Echo Requests after which a peer is reported down
+    #[serde(default = "GtpUdpConfig::default_echo_max_missed")]
+    pub echo_max_missed: u32,
+    #[serde(default = "MailboxConfig::unbounded")]
+    pub mailbox: MailboxConfig,
+    #[serde(default)]
+    pub restart: RestartPolicy,
+}
+
+impl GtpUdpConfig {
+    fn default_bind() -> SocketAddr {
+        SocketAddr::from(([0, 0, 0, 0], 2152))
+    }
+
+    fn default_echo_interval_secs() -> u64 {
+        60
+    }
+
+    fn default_echo_max_missed() -> u32 {
+        3
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TunMode {
+    /// One interface per PDU session, named after the session, e.g. `pdu1`
+    #[default]
+    PerSession,
+    /// Every session on one interface, each IPv4 address under an alias label
+    Shared,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TunConfig {
+    #[serde(default)]
+    pub mode: TunMode,
+    /// Interface name, or its prefix with one interface per session
+    #[serde(default = "TunConfig::default_name")]
+    pub name: String,
+    /// Used when the network did not send an IPv4 link MTU
+    #[serde(default = "TunConfig::default_mtu")]
+    pub mtu: u16,
+    /// Routing table for traffic from the session addresses, none to leave
+    /// routing alone. Sessions get table `route_table + id` in per-session mode
+    #[serde(default)]
+    pub route_table: Option<u32>,
+    #[serde(default = "MailboxConfig::unbounded")]
+    pub mailbox: MailboxConfig,
+    #[serde(default)]
+    pub restart: RestartPolicy,
+}
+
+impl TunConfig {
+    fn default_name() -> String {
+        "pdu".to_string()
+    }
+
+    // 1500 less the outer IPv4, UDP and GTP-U headers with a PDU Session Container
+    fn default_mtu() -> u16 {
+        1456
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NetIfMgmtConfig {
+    /// Only print the `ip` operations of each command, touching nothing
+    #[serde(default)]
+    pub dry_run: bool,
+    #[serde(default = "MailboxConfig::unbounded")]
+    pub mailbox: MailboxConfig,
+    #[serde(default)]
+    pub restart: RestartPolicy,
+}
+
+/// There is no listener task yet, a listener section only says so at startup
+/// and takes no `mailbox` or `restart`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerConfig {
+    pub bind: SocketAddr,
+}
+
+/// A task runs when its section is present. Every section also takes the
+/// `restart` policy of the task, by default five restarts a minute. Unknown
+/// sections and keys are refused, so a misspelt one does not just leave its
+/// task stopped.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TasksConfig {
+    pub nas_decoder: Option<NasDecoderConfig>,
+    pub pdu_session_mgmt: Option<PduSessionMgmtConfig>,
+    pub timer: Option<TimerConfig>,
+    pub gtp_udp: Option<GtpUdpConfig>,
+    pub listener: Option<ListenerConfig>,
+    pub tun: Option<TunConfig>,
+    pub netif_mgmt: Option<NetIfMgmtConfig>,
+}
+
+impl Default for TasksConfig {
+    /// The signalling tasks, as started without a configuration file.
+    fn default() -> TasksConfig {
+        TasksConfig {
+            nas_decoder: Some(NasDecoderConfig {
+                backend: NasDecoderBackend::default(),
+                instances: one_instance(),
+                mailbox: NasDecoderConfig::default_mailbox(),
+                restart: RestartPolicy::default(),
+            }),
+            pdu_session_mgmt: Some(PduSessionMgmtConfig {
+                session_store: PduSessionMgmtConfig::default_session_store(),
+                instances: one_instance(),
+                mailbox: PduSessionMgmtConfig::default_mailbox(),
+                restart: RestartPolicy::default(),
+            }),
+            timer: Some(TimerConfig {
+                virtual_time: false,
+                mailbox: MailboxConfig::unbounded(),
+                restart: RestartPolicy::default(),
+            }),
+            gtp_udp: None,
+            listener: None,
+            tun: None,
+            netif_mgmt: None,
+        }
+    }
+}
+
+/// Contents of the JSON file given with `--config`, e.g.
+///
+/// ```json
+/// {
+///     "log_level": "debug",
+///     "tasks": {
+///         "nas_decoder": { "backend": "native", "instances": 4, "mailbox": { "capacity": 64, "policy": "drop_oldest" } },
+///         "pdu_session_mgmt": { "session_store": null },
+///         "timer": {},
+///         "gtp_udp": { "bind": "0.0.0.0:2152", "echo_interval_secs": 60, "echo_max_missed": 3,
+///                      "restart": { "one_for_one": { "max_restarts": 10, "window_secs": 60 } } },
+///         "listener": { "bind": "127.0.0.1:9000" },
+///         "tun": { "mode": "per_session", "name": "pdu", "mtu": 1456, "route_table": 100 },
+///         "netif_mgmt": { "dry_run": true, "restart": "never" }
+///     }
+/// }
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct IttiConfig {
+    #[serde(default)]
+    pub log_level: LogLevel,
+    #[serde(default)]
+    pub tasks: TasksConfig,
+}
+
+impl IttiConfig {
+    pub fn load(path: impl AsRef<Path>) -> io::Result<IttiConfig> {
+        let data = fs::read(path)?;
+        Ok(serde_json::from_slice(&data)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn parse(json: &str) -> Result<IttiConfig, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+
+    #[test]
+    fn full_config_sets_every_task() {
+        let config = parse(r#"{
+            "log_level": "debug",
+            "tasks": {
+                "nas_decoder": { "backend": "native", "instances": 4, "mailbox": { "capacity": 64, "policy": "drop_oldest" } },
+                "pdu_session_mgmt": { "session_store": null, "instances": 2 },
+                "timer": { "virtual_time": true },
+                "gtp_udp": { "bind": "127.0.0.1:2152", "echo_interval_secs": 0, "echo_max_missed": 5,
+                             "restart": { "one_for_one": { "max_restarts": 10, "window_secs": 30 } } },
+                "listener": { "bind": "127.0.0.1:9000" },
+                "tun": { "mode": "shared", "name": "uesimtun", "mtu": 1400, "route_table": 100 },
+                "netif_mgmt": { "dry_run": true, "restart": "never" }
+            }
+        }"#).unwrap();
+        assert_eq!(config.log_level, LogLevel::Debug);
+        let tasks = config.tasks;
+        let nas_decoder = tasks.nas_decoder.unwrap();
+        assert_eq!((nas_decoder.backend, nas_decoder.instances), (NasDecoderBackend::Native, 4));
+        assert_eq!((nas_decoder.mailbox.capacity, nas_decoder.mailbox.policy), (Some(64), OverflowPolicy::DropOldest));
+        let pdu_session_mgmt = tasks.pdu_session_mgmt.unwrap();
+        assert_eq!((pdu_session_mgmt.session_store, pdu_session_mgmt.instances), (None, 2));
+        assert!(tasks.timer.unwrap().virtual_time);
+        let gtp_udp = tasks.gtp_udp.unwrap();
+        assert_eq!(gtp_udp.bind, SocketAddr::from(([127, 0, 0, 1], 2152)));
+        assert_eq!((gtp_udp.echo_interval_secs, gtp_udp.echo_max_missed), (0, 5));
+        assert_eq!(gtp_udp.restart, RestartPolicy::OneForOne { max_restarts: 10, window: Duration::from_secs(30) });
+        assert_eq!(tasks.listener.unwrap().bind, SocketAddr::from(([127, 0, 0, 1], 9000)));
+        let tun = tasks.tun.unwrap();
+        assert_eq!((tun.mode, tun.name.as_str(), tun.mtu, tun.route_table), (TunMode::Shared, "uesimtun", 1400, Some(100)));
+        let netif_mgmt = tasks.netif_mgmt.unwrap();
+        assert!(netif_mgmt.dry_run);
+        assert_eq!(netif_mgmt.restart, RestartPolicy::Never);
+    }
+
+    #[test]
+    fn minimal_config_takes_the_defaults() {
+        // Without a task list, the signalling tasks
+        let tasks = parse("{}").unwrap().tasks;
+        assert!(tasks.nas_decoder.is_some() && tasks.pdu_session_mgmt.is_some() && tasks.timer.is_some());
+        assert!(tasks.gtp_udp.is_none() && tasks.listener.is_none() && tasks.tun.is_none() && tasks.netif_mgmt.is_none());
+        // With one, only the tasks listed, each with its defaults
+        let config = parse(r#"{ "tasks": { "gtp_udp": {} } }"#).unwrap();
+        assert_eq!(config.log_level, LogLevel::Info);
+        let tasks = config.tasks;
+        assert!(tasks.nas_decoder.is_none() && tasks.pdu_session_mgmt.is_none() && tasks.timer.is_none());
+        let gtp_udp = tasks.gtp_udp.unwrap();
+        assert_eq!(gtp_udp.bind, SocketAddr::from(([0, 0, 0, 0], 2152)));
+        assert_eq!((gtp_udp.echo_interval_secs, gtp_udp.echo_max_missed), (60, 3));
+        assert_eq!(gtp_udp.mailbox.capacity, None);
+        assert_eq!(gtp_udp.restart, RestartPolicy::default());
+    }
+
+    #[test]
+    fn misspelt_section_or_key_is_refused() {
+        for (json, misspelt) in [
+            (r#"{ "tasks": { "gtpu_udp": {} } }"#, "gtpu_udp"),
+            (r#"{ "task": {} }"#, "task"),
+            (r#"{ "tasks": { "gtp_udp": { "echo_interval": 10 } } }"#, "echo_interval"),
+            (r#"{ "tasks": { "tun": { "mailbox": { "capacty": 8 } } } }"#, "capacty"),
+            (r#"{ "tasks": { "timer": { "restart": { "one_for_one": { "max_restart": 1, "window_secs": 1 } } } } }"#, "max_restart"),
+            (r#"{ "tasks": { "listener": { "bind": "127.0.0.1:9000", "mailbox": {} } } }"#, "mailbox"),
+        ] {
+            let e = parse(json).unwrap_err();
+            assert!(e.to_string().contains(&format!("unknown field `{}`", misspelt)), "{}: {}", json, e);
+        }
+    }
+}
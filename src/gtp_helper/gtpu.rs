@@ -0,0 +1,444 @@
+use std::net::IpAddr;
+
+pub type Teid = u32;
+
+pub const GTPU_PORT: u16 = 2152;
+
+/**
+ * 3GPP TS 29281 6.1, Table 6.1-1: GTP-U message types
+ */
+pub const GTPU_ECHO_REQUEST: u8 = 1;
+pub const GTPU_ECHO_RESPONSE: u8 = 2;
+pub const GTPU_ERROR_INDICATION: u8 = 26;
+pub const GTPU_END_MARKER: u8 = 254;
+pub const GTPU_G_PDU: u8 = 255;
+
+/**
+ * 3GPP TS 29281 8.1, Table 8.1-1: information element types. Types below
+ * 128 are TV with a fixed length, the others TLV with a 2 octet length.
+ */
+pub const GTPU_IE_RECOVERY: u8 = 14;
+pub const GTPU_IE_TEID_DATA_I: u8 = 16;
+pub const GTPU_IE_PEER_ADDRESS: u8 = 133;
+const GTPU_IE_TLV: u8 = 128;
+
+/**
+ * 3GPP TS 29281 5.2.1, Figure 5.2.1-3: next extension header types
+ */
+pub const GTPU_EXT_PDU_SESSION_CONTAINER: u8 = 0x85;
+
+const GTPU_VERSION_1: u8 = 0x20;
+const GTPU_PROTOCOL_TYPE_GTP: u8 = 0x10;
+const GTPU_FLAG_E: u8 = 0x04;
+const GTPU_FLAG_S: u8 = 0x02;
+const GTPU_FLAG_PN: u8 = 0x01;
+const GTPU_MANDATORY_HEADER_LEN: usize = 8;
+const GTPU_OPTIONAL_HEADER_LEN: usize = 4;
+const GTPU_NO_MORE_EXTENSION_HEADERS: u8 = 0x00;
+
+/// One extension header, content without its length and next type octets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GtpuExtensionHeader {
+    pub header_type: u8,
+    pub content: Vec<u8>,
+}
+
+/**
+ * 3GPP TS 29281 5.1: GTPv1-U header. The optional fields are present on the
+ * wire as soon as one of them is, so a header with extension headers but no
+ * sequence number carries sequence number 0.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GtpuHeader {
+    pub message_type: u8,
+    pub teid: Teid,
+    pub sequence_number: Option<u16>,
+    pub npdu_number: Option<u8>,
+    pub extension_headers: Vec<GtpuExtensionHeader>,
+}
+
+impl GtpuHeader {
+    pub fn new(message_type: u8, teid: Teid) -> GtpuHeader {
+        GtpuHeader {
+            message_type,
+            teid,
+            sequence_number: None,
+            npdu_number: None,
+            extension_headers: vec![],
+        }
+    }
+
+    /// Splits a GTP-U packet into its header and payload. Bytes after the
+    /// length announced in the header are ignored.
+    pub fn decode(data: &[u8]) -> Option<(GtpuHeader, &[u8])> {
+        if data.len() < GTPU_MANDATORY_HEADER_LEN {
+            return None;
+        }
+        let flags = data[0];
+        if flags & 0xe0 != GTPU_VERSION_1 || flags & GTPU_PROTOCOL_TYPE_GTP == 0 {
+            return None;
+        }
+        let length = u16::from_be_bytes([data[2], data[3]]) as usize;
+        let data = data.get(..GTPU_MANDATORY_HEADER_LEN + length)?;
+        let mut header = GtpuHeader::new(data[1], u32::from_be_bytes([data[4], data[5], data[6], data[7]]));
+        let mut index = GTPU_MANDATORY_HEADER_LEN;
+        if flags & (GTPU_FLAG_E | GTPU_FLAG_S | GTPU_FLAG_PN) == 0 {
+            return Some((header, &data[index..]));
+        }
+        let optional = data.get(index..index + GTPU_OPTIONAL_HEADER_LEN)?;
+        if flags & GTPU_FLAG_S != 0 {
+            header.sequence_number = Some(u16::from_be_bytes([optional[0], optional[1]]));
+        }
+        if flags & GTPU_FLAG_PN != 0 {
+            header.npdu_number = Some(optional[2]);
+        }
+        let mut next_type = if flags & GTPU_FLAG_E != 0 { optional[3] } else { GTPU_NO_MORE_EXTENSION_HEADERS };
+        index += GTPU_OPTIONAL_HEADER_LEN;
+        while next_type != GTPU_NO_MORE_EXTENSION_HEADERS {
+            // Length in units of 4 octets, covering the length and next type octets
+            let length = *data.get(index)? as usize * 4;
+            if length == 0 {
+                return None;
+            }
+            let extension = data.get(index..index + length)?;
+            header.extension_headers.push(GtpuExtensionHeader {
+                header_type: next_type,
+                content: extension[1..length - 1].to_vec(),
+            });
+            next_type = extension[length - 1];
+            index += length;
+        }
+        Some((header, &data[index..]))
+    }
+
+    /// Builds the packet. Extension header contents are zero padded to a
+    /// multiple of 4 octets.
+    pub fn encode(&self, payload: &[u8]) -> Vec<u8> {
+        let mut flags = GTPU_VERSION_1 | GTPU_PROTOCOL_TYPE_GTP;
+        if !self.extension_headers.is_empty() {
+            flags |= GTPU_FLAG_E;
+        }
+        if self.sequence_number.is_some() {
+            flags |= GTPU_FLAG_S;
+        }
+        if self.npdu_number.is_some() {
+            flags |= GTPU_FLAG_PN;
+        }
+        let mut data = vec![flags, self.message_type, 0, 0];
+        data.extend_from_slice(&self.teid.to_be_bytes());
+        if flags & (GTPU_FLAG_E | GTPU_FLAG_S | GTPU_FLAG_PN) != 0 {
+            data.extend_from_slice(&self.sequence_number.unwrap_or(0).to_be_bytes());
+            data.push(self.npdu_number.unwrap_or(0));
+            data.push(self.extension_headers.first().map_or(GTPU_NO_MORE_EXTENSION_HEADERS, |extension| extension.header_type));
+            for (i, extension) in self.extension_headers.iter().enumerate() {
+                let units = (extension.content.len() + 2).div_ceil(4);
+                data.push(units as u8);
+                data.extend_from_slice(&extension.content);
+                data.resize(data.len() + units * 4 - 2 - extension.content.len(), 0);
+                data.push(self.extension_headers.get(i + 1).map_or(GTPU_NO_MORE_EXTENSION_HEADERS, |next| next.header_type));
+            }
+        }
+        data.extend_from_slice(payload);
+        let length = (data.len() - GTPU_MANDATORY_HEADER_LEN) as u16;
+        data[2..4].copy_from_slice(&length.to_be_bytes());
+        data
+    }
+
+    /// 3GPP TS 29281 5.1: echo messages and Error Indication carry a sequence
+    /// number and TEID 0.
+    pub fn path_message(message_type: u8, sequence_number: u16) -> GtpuHeader {
+        GtpuHeader {
+            sequence_number: Some(sequence_number),
+            ..GtpuHeader::new(message_type, 0)
+        }
+    }
+
+    pub fn pdu_session_container(&self) -> Option<PduSessionContainer> {
+        self.extension_headers
+            .iter()
+            .find(|extension| extension.header_type == GTPU_EXT_PDU_SESSION_CONTAINER)
+            .and_then(PduSessionContainer::decode)
+    }
+}
+
+pub fn recovery_ie(restart_counter: u8) -> [u8; 2] {
+    [GTPU_IE_RECOVERY, restart_counter]
+}
+
+/// Restart counter of the Recovery IE, the only mandatory IE of an Echo
+/// Response and so the first one.
+pub fn recovery_restart_counter(ies: &[u8]) -> Option<u8> {
+    match ies {
+        [GTPU_IE_RECOVERY, restart_counter, ..] => Some(*restart_counter),
+        _ => None,
+    }
+}
+
+/**
+ * 3GPP TS 29281 7.3.1: Error Indication, the TEID of a G-PDU the sender had
+ * no tunnel for and the address that G-PDU was sent to.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GtpuErrorIndication {
+    pub teid: Teid,
+    pub peer_address: IpAddr,
+}
+
+impl GtpuErrorIndication {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut ies = vec![GTPU_IE_TEID_DATA_I];
+        ies.extend_from_slice(&self.teid.to_be_bytes());
+        let address = match self.peer_address {
+            IpAddr::V4(address) => address.octets().to_vec(),
+            IpAddr::V6(address) => address.octets().to_vec(),
+        };
+        ies.push(GTPU_IE_PEER_ADDRESS);
+        ies.extend_from_slice(&(address.len() as u16).to_be_bytes());
+        ies.extend_from_slice(&address);
+        ies
+    }
+
+    pub fn decode(ies: &[u8]) -> Option<GtpuErrorIndication> {
+        let mut teid = None;
+        let mut peer_address = None;
+        let mut index = 0;
+        while index < ies.len() {
+            let ie_type = ies[index];
+            let (value, next) = if ie_type >= GTPU_IE_TLV {
+                let length = u16::from_be_bytes([*ies.get(index + 1)?, *ies.get(index + 2)?]) as usize;
+                (ies.get(index + 3..index + 3 + length)?, index + 3 + length)
+            } else {
+                let length = match ie_type {
+                    GTPU_IE_RECOVERY => 1,
+                    GTPU_IE_TEID_DATA_I => 4,
+                    // The length of other TV types is unknown, so nothing after them can be read
+                    _ => break,
+                };
+                (ies.get(index + 1..index + 1 + length)?, index + 1 + length)
+            };
+            match ie_type {
+                GTPU_IE_TEID_DATA_I => teid = Some(u32::from_be_bytes(value.try_into().ok()?)),
+                GTPU_IE_PEER_ADDRESS => peer_address = match value.len() {
+                    4 => Some(IpAddr::from(<[u8; 4]>::try_from(value).ok()?)),
+                    16 => Some(IpAddr::from(<[u8; 16]>::try_from(value).ok()?)),
+                    _ => return None,
+                },
+                _ => {},
+            }
+            index = next;
+        }
+        Some(GtpuErrorIndication { teid: teid?, peer_address: peer_address? })
+    }
+}
+
+/**
+ * 3GPP TS 38415 5.5.2: PDU type of the PDU Session Container, DL PDU SESSION
+ * INFORMATION (0) from the UPF and UL PDU SESSION INFORMATION (1) towards it.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PduSessionInfoType {
+    Downlink = 0,
+    Uplink = 1,
+}
+
+/**
+ * 3GPP TS 38415 5.5.2.1/5.5.2.2: the octets after the PDU type carry the QFI
+ * in their low 6 bits, the downlink one also the RQI in bit 7. Optional
+ * fields after them are not decoded and never encoded.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PduSessionContainer {
+    pub pdu_type: PduSessionInfoType,
+    pub qfi: u8,
+    pub rqi: bool,
+}
+
+impl PduSessionContainer {
+    pub fn uplink(qfi: u8) -> PduSessionContainer {
+        PduSessionContainer {
+            pdu_type: PduSessionInfoType::Uplink,
+            qfi,
+            rqi: false,
+        }
+    }
+
+    pub fn decode(extension: &GtpuExtensionHeader) -> Option<PduSessionContainer> {
+        let content = extension.content.get(..2)?;
+        let pdu_type = match content[0] >> 4 {
+            0 => PduSessionInfoType::Downlink,
+            1 => PduSessionInfoType::Uplink,
+            _ => return None,
+        };
+        Some(PduSessionContainer {
+            pdu_type,
+            qfi: content[1] & 0x3f,
+            rqi: pdu_type == PduSessionInfoType::Downlink && content[1] & 0x40 != 0,
+        })
+    }
+
+    pub fn encode(&self) -> GtpuExtensionHeader {
+        let mut qfi = self.qfi & 0x3f;
+        if self.rqi && self.pdu_type == PduSessionInfoType::Downlink {
+            qfi |= 0x40;
+        }
+        GtpuExtensionHeader {
+            header_type: GTPU_EXT_PDU_SESSION_CONTAINER,
+            content: vec![(self.pdu_type as u8) << 4, qfi],
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAYLOAD: [u8; 4] = [0x45, 0x00, 0x00, 0x14];
+
+    // G-PDU for TEID 0x11223344 with a PDU Session Container for QFI 9 and a
+    // UDP Port extension header (type 0x40) chained after it
+    const CHAINED: [u8; 28] = [
+        0x34, 0xff, 0x00, 0x10, 0x11, 0x22, 0x33, 0x44,
+        0x00, 0x00, 0x00, 0x85,
+        0x01, 0x00, 0x09, 0x40,
+        0x01, 0x08, 0x68, 0x00,
+        0x45, 0x00, 0x00, 0x14,
+        // Past the announced length
+        0xde, 0xad, 0xbe, 0xef,
+    ];
+
+    #[test]
+    fn header_without_optional_fields() {
+        let data = [0x30, 0xff, 0x00, 0x04, 0x11, 0x22, 0x33, 0x44, 0x45, 0x00, 0x00, 0x14];
+        let header = GtpuHeader::new(GTPU_G_PDU, 0x11223344);
+        assert_eq!(header.encode(&PAYLOAD), data);
+        assert_eq!(GtpuHeader::decode(&data), Some((header, &PAYLOAD[..])));
+    }
+
+    #[test]
+    fn header_with_sequence_and_npdu_numbers() {
+        let echo = [0x32, 0x01, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x12, 0x34, 0x00, 0x00];
+        let header = GtpuHeader::path_message(GTPU_ECHO_REQUEST, 0x1234);
+        assert_eq!(header.encode(&[]), echo);
+        assert_eq!(GtpuHeader::decode(&echo), Some((header, &[][..])));
+
+        let data = [0x31, 0xff, 0x00, 0x08, 0x00, 0x00, 0x00, 0x07, 0x00, 0x00, 0x05, 0x00, 0x45, 0x00, 0x00, 0x14];
+        let header = GtpuHeader { npdu_number: Some(5), ..GtpuHeader::new(GTPU_G_PDU, 7) };
+        assert_eq!(header.encode(&PAYLOAD), data);
+        assert_eq!(GtpuHeader::decode(&data), Some((header, &PAYLOAD[..])));
+    }
+
+    #[test]
+    fn header_with_chained_extension_headers() {
+        let header = GtpuHeader {
+            extension_headers: vec![
+                GtpuExtensionHeader { header_type: GTPU_EXT_PDU_SESSION_CONTAINER, content: vec![0x00, 0x09] },
+                GtpuExtensionHeader { header_type: 0x40, content: vec![0x08, 0x68] },
+            ],
+            ..GtpuHeader::new(GTPU_G_PDU, 0x11223344)
+        };
+        // The E flag alone still puts a zero sequence number on the wire,
+        // which decodes as absent
+        assert_eq!(header.encode(&PAYLOAD), CHAINED[..24]);
+        assert_eq!(GtpuHeader::decode(&CHAINED), Some((header, &PAYLOAD[..])));
+    }
+
+    #[test]
+    fn truncated_or_malformed_input_is_rejected() {
+        for len in 0..24 {
+            assert_eq!(GtpuHeader::decode(&CHAINED[..len]), None, "{} octets", len);
+        }
+        let mut zero_length_extension = CHAINED;
+        zero_length_extension[12] = 0;
+        assert_eq!(GtpuHeader::decode(&zero_length_extension), None);
+        let mut version_2 = CHAINED;
+        version_2[0] = 0x54;
+        assert_eq!(GtpuHeader::decode(&version_2), None);
+        let mut gtp_prime = CHAINED;
+        gtp_prime[0] = 0x24;
+        assert_eq!(GtpuHeader::decode(&gtp_prime), None);
+    }
+
+    fn with_container(container: PduSessionContainer) -> GtpuHeader {
+        GtpuHeader { extension_headers: vec![container.encode()], ..GtpuHeader::new(GTPU_G_PDU, 1) }
+    }
+
+    #[test]
+    fn pdu_session_container_layouts() {
+        // PDU type in the high nibble of the first octet, RQI in bit 7 and
+        // the QFI in bits 1-6 of the second, padded to one 4-octet unit
+        let downlink = PduSessionContainer { pdu_type: PduSessionInfoType::Downlink, qfi: 9, rqi: true };
+        assert_eq!(with_container(downlink).encode(&[])[8..], [0x00, 0x00, 0x00, 0x85, 0x01, 0x00, 0x49, 0x00]);
+        let uplink = PduSessionContainer::uplink(63);
+        assert_eq!(with_container(uplink).encode(&[])[8..], [0x00, 0x00, 0x00, 0x85, 0x01, 0x10, 0x3f, 0x00]);
+        for container in [downlink, PduSessionContainer { rqi: false, ..downlink }, uplink] {
+            let data = with_container(container).encode(&PAYLOAD);
+            let (header, payload) = GtpuHeader::decode(&data).unwrap();
+            assert_eq!((header.pdu_session_container(), payload), (Some(container), &PAYLOAD[..]));
+        }
+    }
+
+    #[test]
+    fn pdu_session_container_bits_outside_the_fields_are_ignored() {
+        let decode = |content: Vec<u8>| PduSessionContainer::decode(&GtpuExtensionHeader { header_type: GTPU_EXT_PDU_SESSION_CONTAINER, content });
+        // QFI wider than 6 bits and RQI on uplink are not encoded
+        let encoded = PduSessionContainer { pdu_type: PduSessionInfoType::Uplink, qfi: 0xc5, rqi: true }.encode();
+        assert_eq!(encoded.content, [0x10, 0x05]);
+        // Nor decoded, with the spare bits and the optional fields after them set
+        assert_eq!(decode(vec![0x1f, 0xc5, 0xff]), Some(PduSessionContainer::uplink(5)));
+        assert_eq!(decode(vec![0x0f, 0xff]), Some(PduSessionContainer { pdu_type: PduSessionInfoType::Downlink, qfi: 0x3f, rqi: true }));
+        assert_eq!(decode(vec![0x20, 0x05]), None);
+        assert_eq!(decode(vec![0x10]), None);
+    }
+
+    #[test]
+    fn extension_headers_are_padded_to_4_octet_units() {
+        for (content_len, units) in [(0, 1), (2, 1), (3, 2), (6, 2), (7, 3)] {
+            let extension = GtpuExtensionHeader { header_type: 0x40, content: vec![0xaa; content_len] };
+            let header = GtpuHeader { extension_headers: vec![extension], ..GtpuHeader::new(GTPU_G_PDU, 1) };
+            let data = header.encode(&PAYLOAD);
+            assert_eq!(data.len(), 12 + units * 4 + PAYLOAD.len(), "{} octets", content_len);
+            assert_eq!(data[12] as usize, units);
+            let (decoded, payload) = GtpuHeader::decode(&data).unwrap();
+            // Padding comes back as content
+            let mut padded = vec![0xaa; content_len];
+            padded.resize(units * 4 - 2, 0);
+            assert_eq!((&decoded.extension_headers[0].content, payload), (&padded, &PAYLOAD[..]));
+        }
+    }
+
+    #[test]
+    fn error_indication_with_ipv4_and_ipv6_peers() {
+        let ipv4 = GtpuErrorIndication { teid: 0x11223344, peer_address: "192.0.2.1".parse().unwrap() };
+        let ipv4_ies = [0x10, 0x11, 0x22, 0x33, 0x44, 0x85, 0x00, 0x04, 0xc0, 0x00, 0x02, 0x01];
+        assert_eq!(ipv4.encode(), ipv4_ies);
+        assert_eq!(GtpuErrorIndication::decode(&ipv4_ies), Some(ipv4));
+
+        let ipv6 = GtpuErrorIndication { teid: 7, peer_address: "2001:db8::1".parse().unwrap() };
+        let mut ipv6_ies = vec![0x10, 0x00, 0x00, 0x00, 0x07, 0x85, 0x00, 0x10, 0x20, 0x01, 0x0d, 0xb8];
+        ipv6_ies.extend_from_slice(&[0; 11]);
+        ipv6_ies.push(1);
+        assert_eq!(ipv6.encode(), ipv6_ies);
+        assert_eq!(GtpuErrorIndication::decode(&ipv6_ies), Some(ipv6.clone()));
+
+        // A Recovery IE before them and a Private Extension after them are skipped
+        let mut ies = recovery_ie(3).to_vec();
+        ies.extend_from_slice(&ipv6_ies);
+        ies.extend_from_slice(&[0xff, 0x00, 0x02, 0x12, 0x34]);
+        assert_eq!(GtpuErrorIndication::decode(&ies), Some(ipv6));
+    }
+
+    #[test]
+    fn error_indication_without_both_ies_is_rejected() {
+        let ies = GtpuErrorIndication { teid: 0x11223344, peer_address: "192.0.2.1".parse().unwrap() }.encode();
+        assert_eq!(GtpuErrorIndication::decode(&ies[..5]), None);
+        assert_eq!(GtpuErrorIndication::decode(&ies[5..]), None);
+        for len in 6..ies.len() {
+            assert_eq!(GtpuErrorIndication::decode(&ies[..len]), None, "{} octets", len);
+        }
+        let mut five_octet_address = ies[..8].to_vec();
+        five_octet_address[7] = 5;
+        five_octet_address.extend_from_slice(&[192, 0, 2, 1, 0]);
+        assert_eq!(GtpuErrorIndication::decode(&five_octet_address), None);
+    }
+}
@@ -0,0 +1 @@
+pub mod gtpu;
\ No newline at end of file
@@ -0,0 +1,640 @@
+use std::{collections::{hash_map::Entry, HashMap}, io, net::{IpAddr, SocketAddr, UdpSocket}, sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}}, thread::{self, JoinHandle}, time::{Duration, Instant}};
+
+use bytes::{Bytes, BytesMut};
+use crossbeam::channel::RecvTimeoutError;
+
+use crate::{gtp_helper::gtpu::{recovery_ie, recovery_restart_counter, GtpuErrorIndication, GtpuHeader, PduSessionContainer, Teid, GTPU_ECHO_REQUEST, GTPU_ECHO_RESPONSE, GTPU_END_MARKER, GTPU_ERROR_INDICATION, GTPU_G_PDU, GTPU_PORT}, itti::{Itti, Mailbox}, msg::{GtpDirection, GtpErrorIndication, GtpPathEvent, GtpPathEventKind, IttiMsg, IttiTaskId, IttiTrxTag, UdpGtpBuffer}, timer::{Clock, SystemClock}, tunnel_table::{FTeid, TunnelTable}};
+
+/// Largest UDP payload, so a datagram is never truncated.
+const GTPU_MAX_DATAGRAM: usize = 65535;
+/// Datagrams are received back to back into chunks of this size.
+const GTPU_RECV_CHUNK: usize = 16 * GTPU_MAX_DATAGRAM;
+/// How often the receiver looks at its stop flag while the socket is idle.
+const GTPU_RECV_TIMEOUT: Duration = Duration::from_millis(200);
+/// 3GPP TS 29281 8.2: the restart counter is not used by GTP-U and sent as 0.
+const GTPU_RESTART_COUNTER: u8 = 0;
+/// At most one Error Indication per peer in this interval. TS 29281 7.3.1
+/// leaves the rate to the implementation; without a limit G-PDUs with a
+/// spoofed source would have us send traffic at a third party.
+const GTPU_ERROR_INDICATION_INTERVAL: Duration = Duration::from_secs(1);
+/// Peers the Error Indication limit is kept for.
+const GTPU_ERROR_INDICATION_PEERS: usize = 1024;
+
+/// Echo state of the path to one peer.
+#[derive(Debug, Default)]
+struct GtpPath {
+    sequence_number: u16,
+    // Echo request still waiting for its response
+    outstanding: Option<u16>,
+    missed: u32,
+    restart_counter: Option<u8>,
+    down: bool,
+}
+
+impl GtpPath {
+    /// Starts the next echo, counting the previous one as missed if it got
+    /// no response. Returns the sequence number to send and whether the path
+    /// just went down after `max_missed` misses in a row.
+    fn next_echo(&mut self, max_missed: u32) -> (u16, bool) {
+        let mut went_down = false;
+        if self.outstanding.take().is_some() {
+            self.missed += 1;
+            if self.missed >= max_missed && !self.down {
+                self.down = true;
+                went_down = true;
+            }
+        }
+        self.sequence_number = self.sequence_number.wrapping_add(1);
+        self.outstanding = Some(self.sequence_number);
+        (self.sequence_number, went_down)
+    }
+
+    /// Echo response from the peer. Anything but the response to the
+    /// outstanding request is ignored, since a late one may carry an older
+    /// restart counter.
+    fn on_echo_response(&mut self, sequence_number: Option<u16>, restart_counter: Option<u8>) -> Vec<GtpPathEventKind> {
+        let mut events = vec![];
+        if self.outstanding.is_none() || self.outstanding != sequence_number {
+            return events;
+        }
+        self.outstanding = None;
+        self.missed = 0;
+        if self.down {
+            self.down = false;
+            events.push(GtpPathEventKind::Up);
+        }
+        if let Some(restart_counter) = restart_counter {
+            if self.restart_counter.is_some_and(|previous| previous != restart_counter) {
+                events.push(GtpPathEventKind::Restarted);
+            }
+            self.restart_counter = Some(restart_counter);
+        }
+        events
+    }
+}
+
+type GtpPaths = Arc<Mutex<HashMap<IpAddr, GtpPath>>>;
+
+/// Error Indications sent to one peer.
+struct ErrorIndicationPeer {
+    last_sent: Instant,
+    // Our address as the peer sees it, resolved once
+    local: Option<IpAddr>,
+}
+
+/// Rate limit of the Error Indications, per peer.
+struct ErrorIndications {
+    clock: Arc<dyn Clock>,
+    peers: HashMap<IpAddr, ErrorIndicationPeer>,
+}
+
+impl ErrorIndications {
+    fn new(clock: Arc<dyn Clock>) -> ErrorIndications {
+        ErrorIndications { clock, peers: HashMap::new() }
+    }
+
+    /// Whether an indication may go to `peer` now, counting it if so. Peers
+    /// not sent one for an interval are forgotten once the table is full, and
+    /// new peers refused while it stays full.
+    fn allow(&mut self, peer: IpAddr) -> Option<&mut ErrorIndicationPeer> {
+        let now = self.clock.now();
+        if !self.peers.contains_key(&peer) && self.peers.len() >= GTPU_ERROR_INDICATION_PEERS {
+            self.peers.retain(|_, sent| now.duration_since(sent.last_sent) < GTPU_ERROR_INDICATION_INTERVAL);
+            if self.peers.len() >= GTPU_ERROR_INDICATION_PEERS {
+                return None;
+            }
+        }
+        match self.peers.entry(peer) {
+            Entry::Occupied(entry) => {
+                let sent = entry.into_mut();
+                if now.duration_since(sent.last_sent) < GTPU_ERROR_INDICATION_INTERVAL {
+                    return None;
+                }
+                sent.last_sent = now;
+                Some(sent)
+            },
+            Entry::Vacant(entry) => Some(entry.insert(ErrorIndicationPeer { last_sent: now, local: None })),
+        }
+    }
+}
+
+/// Downlink G-PDU handed to the session manager.
+pub fn gpdu_buffer(teid: Teid, pdu_session_id: u8, peer: SocketAddr, payload: Bytes) -> UdpGtpBuffer {
+    UdpGtpBuffer {
+        teid,
+        pdu_session_id,
+        qfi: None,
+        rqi: false,
+        peer: Some(peer),
+        direction: GtpDirection::Downlink,
+        payload,
+    }
+}
+
+/// Sessions may be on any session manager instance, so path events go to all.
+fn notify_session_mgmt(itti: &Itti, task: IttiTaskId, event: GtpPathEvent) {
+    println!("gtp-u: peer {} {:?}", event.peer, event.kind);
+    for instance in 0..itti.instances(IttiTrxTag::PduSessionMgmt) {
+        let msg = IttiMsg::PduSessionMgmtGtpPathEvent(event.clone());
+        let envelope = itti.envelope(Some(task), Some(IttiTaskId::new(IttiTrxTag::PduSessionMgmt, instance)), msg);
+        if let Err(e) = itti.send_envelope(envelope) {
+            println!("gtp-u: path event not delivered: {}", e);
+        }
+    }
+}
+
+/// Everything the receive path needs, owned by the receiver thread.
+struct GtpUdpRx {
+    itti: Arc<Itti>,
+    task: IttiTaskId,
+    socket: UdpSocket,
+    local: SocketAddr,
+    tunnels: Arc<TunnelTable>,
+    paths: GtpPaths,
+    error_indications: Mutex<ErrorIndications>,
+}
+
+impl GtpUdpRx {
+    fn on_datagram(&self, datagram: Bytes, peer: SocketAddr) {
+        let (header, payload) = match GtpuHeader::decode(&datagram) {
+            Some(decoded) => decoded,
+            None => {
+                println!("gtp-u: malformed packet from {}", peer);
+                return;
+            },
+        };
+        match header.message_type {
+            GTPU_G_PDU => {
+                let container = header.pdu_session_container();
+                let qfi = container.as_ref().map(|container| container.qfi);
+                let bound = self.tunnels.lookup(header.teid, |tunnel| {
+                    (tunnel.pdu_session_id, qfi.is_none_or(|qfi| tunnel.qfis.contains(&qfi)))
+                });
+                let pdu_session_id = match bound {
+                    Some((pdu_session_id, known_qfi)) => {
+                        if !known_qfi {
+                            println!("gtp-u: downlink for pdu session {} on unknown qfi {:?}", pdu_session_id, qfi);
+                        }
+                        pdu_session_id
+                    },
+                    None => {
+                        println!("gtp-u: G-PDU from {} for unknown teid {:#x}", peer, header.teid);
+                        self.send_error_indication(header.teid, peer);
+                        return;
+                    },
+                };
+                let mut buffer = gpdu_buffer(header.teid, pdu_session_id, peer, datagram.slice_ref(payload));
+                if let Some(container) = container {
+                    buffer.qfi = Some(container.qfi);
+                    buffer.rqi = container.rqi;
+                }
+                if let Err(e) = self.itti.send_from(self.task, IttiTrxTag::PduSessionMgmt, IttiMsg::GtpUdpRecvFromRemoteThenToPduSessoin(buffer)) {
+                    println!("gtp-u: G-PDU for pdu session {} dropped: {}", pdu_session_id, e);
+                }
+            },
+            GTPU_ECHO_REQUEST => {
+                // Answered to the port it came from, which may not be 2152
+                let response = GtpuHeader::path_message(GTPU_ECHO_RESPONSE, header.sequence_number.unwrap_or(0));
+                if let Err(e) = self.socket.send_to(&response.encode(&recovery_ie(GTPU_RESTART_COUNTER)), peer) {
+                    println!("gtp-u: echo response to {}: {}", peer, e);
+                }
+            },
+            GTPU_ECHO_RESPONSE => {
+                let events = self.on_echo_response(peer.ip(), header.sequence_number, recovery_restart_counter(payload));
+                for kind in events {
+                    notify_session_mgmt(&self.itti, self.task, GtpPathEvent { peer: peer.ip(), kind });
+                }
+            },
+            GTPU_ERROR_INDICATION => {
+                match GtpuErrorIndication::decode(payload) {
+                    Some(indication) => self.on_error_indication(indication),
+                    None => println!("gtp-u: malformed error indication from {}", peer),
+                }
+            },
+            GTPU_END_MARKER => {
+                if let Some(old) = self.tunnels.flush_draining(header.teid) {
+                    println!("gtp-u: end marker, old path {} teid {:#x} of teid {:#x} flushed", old.addr, old.teid, header.teid);
+                    return;
+                }
+                match self.tunnels.lookup(header.teid, |tunnel| tunnel.pdu_session_id) {
+                    Some(pdu_session_id) => println!("gtp-u: end marker on active teid {:#x} of pdu session {}", header.teid, pdu_session_id),
+                    None => println!("gtp-u: end marker from {} for unknown teid {:#x}", peer, header.teid),
+                }
+            },
+            message_type => {
+                println!("gtp-u: message type {} from {} not handled", message_type, peer);
+            },
+        }
+    }
+
+    /// Tells the sender of a G-PDU that there is no tunnel for its TEID,
+    /// always on port 2152. TEID 0 is never answered (TS 29281 7.3.1).
+    fn send_error_indication(&self, teid: Teid, peer: SocketAddr) {
+        if teid == 0 {
+            return;
+        }
+        let mut error_indications = self.error_indications.lock().unwrap();
+        let sent = match error_indications.allow(peer.ip()) {
+            Some(sent) => sent,
+            None => return,
+        };
+        let peer_address = match self.local.ip().is_unspecified() {
+            true => match sent.local.or_else(|| local_address_towards(peer.ip())) {
+                Some(address) => {
+                    sent.local = Some(address);
+                    address
+                },
+                None => {
+                    println!("gtp-u: no local address towards {}, error indication not sent", peer);
+                    return;
+                },
+            },
+            false => self.local.ip(),
+        };
+        let ies = GtpuErrorIndication { teid, peer_address }.encode();
+        let indication = GtpuHeader::path_message(GTPU_ERROR_INDICATION, 0).encode(&ies);
+        if let Err(e) = self.socket.send_to(&indication, SocketAddr::new(peer.ip(), GTPU_PORT)) {
+            println!("gtp-u: error indication to {}: {}", peer.ip(), e);
+        }
+    }
+
+    /// The indication names the TEID and address of the peer, so it is the
+    /// remote F-TEID of one of our tunnels.
+    fn on_error_indication(&self, indication: GtpuErrorIndication) {
+        let remote = FTeid { teid: indication.teid, addr: indication.peer_address };
+        let pdu_session_id = match self.tunnels.find_by_remote(remote) {
+            Some((_, pdu_session_id)) => pdu_session_id,
+            None => {
+                println!("gtp-u: error indication for unknown teid {:#x} at {}", indication.teid, indication.peer_address);
+                return;
+            },
+        };
+        println!("gtp-u: error indication for pdu session {}, teid {:#x} at {}", pdu_session_id, indication.teid, indication.peer_address);
+        let msg = IttiMsg::PduSessionMgmtGtpErrorIndication(GtpErrorIndication {
+            pdu_session_id,
+            remote_teid: indication.teid,
+            peer: indication.peer_address,
+        });
+        if let Err(e) = self.itti.send_from(self.task, IttiTrxTag::PduSessionMgmt, msg) {
+            println!("gtp-u: error indication not delivered: {}", e);
+        }
+    }
+
+    /// A response to the outstanding request brings a path back up. A changed
+    /// restart counter means the peer lost its tunnels, though peers that
+    /// follow TS 29281 8.2 always send 0.
+    fn on_echo_response(&self, peer: IpAddr, sequence_number: Option<u16>, restart_counter: Option<u8>) -> Vec<GtpPathEventKind> {
+        match self.paths.lock().unwrap().get_mut(&peer) {
+            Some(path) => path.on_echo_response(sequence_number, restart_counter),
+            None => vec![],
+        }
+    }
+}
+
+/// Source address the kernel would pick to reach a peer, for a socket bound
+/// to the wildcard address.
+fn local_address_towards(peer: IpAddr) -> Option<IpAddr> {
+    let any = match peer {
+        IpAddr::V4(_) => SocketAddr::from(([0, 0, 0, 0], 0)),
+        IpAddr::V6(_) => SocketAddr::from(([0u16; 8], 0)),
+    };
+    let probe = UdpSocket::bind(any).ok()?;
+    probe.connect(SocketAddr::new(peer, GTPU_PORT)).ok()?;
+    probe.local_addr().ok().map(|local| local.ip())
+}
+
+/// Reads the socket on its own thread, since a task only waits on its mailbox.
+struct GtpUdpReceiver {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl GtpUdpReceiver {
+    fn spawn(rx: GtpUdpRx) -> io::Result<GtpUdpReceiver> {
+        rx.socket.set_read_timeout(Some(GTPU_RECV_TIMEOUT))?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let handle = thread::Builder::new()
+            .name("gtp-udp-rx".to_string())
+            .spawn(move || {
+                // Each datagram is split off the chunk it was received into,
+                // without a copy. Payloads are consumed in order, so queued
+                // ones keep at most one partly used chunk alive besides
+                // their own octets.
+                let mut datagram = BytesMut::new();
+                while !stopped.load(Ordering::Relaxed) {
+                    if datagram.capacity() < GTPU_MAX_DATAGRAM {
+                        datagram.clear();
+                        datagram.reserve(GTPU_RECV_CHUNK);
+                    }
+                    // Only the octets split off last time are zeroed again
+                    datagram.resize(GTPU_MAX_DATAGRAM, 0);
+                    match rx.socket.recv_from(&mut datagram) {
+                        Ok((len, peer)) => rx.on_datagram(datagram.split_to(len).freeze(), peer),
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {},
+                        Err(e) => println!("gtp-u receive: {}", e),
+                    }
+                }
+            })?;
+        Ok(GtpUdpReceiver { stop, handle: Some(handle) })
+    }
+}
+
+impl Drop for GtpUdpReceiver {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// GTP-U endpoint of the PDU sessions: decapsulates downlink G-PDUs to the
+/// session owning their TEID and encapsulates uplink buffers towards the
+/// remote F-TEID of the session, both looked up in the tunnel table the
+/// session managers keep. Every peer with a tunnel is sent an Echo
+/// Request each `echo_interval`, and reported down after `echo_max_missed`
+/// of them went unanswered.
+pub struct GtpUdp {
+    bind: SocketAddr,
+    tunnels: Arc<TunnelTable>,
+    paths: GtpPaths,
+    echo_interval: Option<Duration>,
+    echo_max_missed: u32,
+}
+
+impl GtpUdp {
+    pub fn new(bind: SocketAddr, tunnels: Arc<TunnelTable>) -> GtpUdp {
+        GtpUdp {
+            bind,
+            tunnels,
+            paths: Arc::new(Mutex::new(HashMap::new())),
+            echo_interval: None,
+            echo_max_missed: 0,
+        }
+    }
+
+    /// Turns on path management; TS 29281 7.2.1 asks for at least 60s
+    /// between echoes on a path.
+    pub fn with_echo(mut self, interval: Duration, max_missed: u32) -> GtpUdp {
+        self.echo_interval = Some(interval);
+        self.echo_max_missed = max_missed.max(1);
+        self
+    }
+
+    fn send_to_remote(&self, socket: &UdpSocket, buffer: &UdpGtpBuffer) {
+        if buffer.direction != GtpDirection::Uplink {
+            println!("gtp-u: downlink buffer for teid {:#x} sent to remote dropped", buffer.teid);
+            return;
+        }
+        let remote = match self.tunnels.lookup(buffer.teid, |tunnel| tunnel.remote) {
+            Some(Some(remote)) => remote,
+            Some(None) => {
+                println!("gtp-u: uplink for teid {:#x} without remote end dropped", buffer.teid);
+                return;
+            },
+            None => {
+                println!("gtp-u: uplink for unknown teid {:#x} dropped", buffer.teid);
+                return;
+            },
+        };
+        let mut header = GtpuHeader::new(GTPU_G_PDU, remote.teid);
+        if let Some(qfi) = buffer.qfi {
+            header.extension_headers.push(PduSessionContainer::uplink(qfi).encode());
+        }
+        let packet = header.encode(&buffer.payload);
+        if let Err(e) = socket.send_to(&packet, remote.socket_addr()) {
+            println!("gtp-u: send to {}: {}", remote.addr, e);
+        }
+    }
+
+    /// Sends the next Echo Request to every peer, counting the one before as
+    /// missed if it is still unanswered. Peers without tunnels are forgotten.
+    fn echo_peers(&self, itti: &Itti, task: IttiTaskId, socket: &UdpSocket) {
+        let peers = self.tunnels.remote_peers();
+        let mut down = vec![];
+        {
+            let mut paths = self.paths.lock().unwrap();
+            paths.retain(|peer, _| peers.contains(peer));
+            for peer in &peers {
+                let (sequence_number, went_down) = paths.entry(*peer).or_default().next_echo(self.echo_max_missed);
+                if went_down {
+                    down.push(*peer);
+                }
+                let request = GtpuHeader::path_message(GTPU_ECHO_REQUEST, sequence_number);
+                if let Err(e) = socket.send_to(&request.encode(&[]), SocketAddr::new(*peer, GTPU_PORT)) {
+                    println!("gtp-u: echo request to {}: {}", peer, e);
+                }
+            }
+        }
+        for peer in down {
+            notify_session_mgmt(itti, task, GtpPathEvent { peer, kind: GtpPathEventKind::Down });
+        }
+    }
+
+    pub fn init_gtp_udp_task(self, itti: Arc<Itti>, mailbox: Mailbox) {
+        let socket = match UdpSocket::bind(self.bind) {
+            Ok(socket) => socket,
+            Err(e) => {
+                println!("gtp-u: cannot bind {}: {}", self.bind, e);
+                return;
+            },
+        };
+        let receiver = match socket.try_clone().and_then(|rx_socket| {
+            GtpUdpReceiver::spawn(GtpUdpRx {
+                itti: itti.clone(),
+                task: mailbox.id,
+                local: rx_socket.local_addr()?,
+                socket: rx_socket,
+                tunnels: self.tunnels.clone(),
+                paths: self.paths.clone(),
+                error_indications: Mutex::new(ErrorIndications::new(Arc::new(SystemClock))),
+            })
+        }) {
+            Ok(receiver) => receiver,
+            Err(e) => {
+                println!("gtp-u: receiver not started: {}", e);
+                return;
+            },
+        };
+        println!("gtp-u: listening on {}", self.bind);
+        let mut next_echo = self.echo_interval.map(|interval| Instant::now() + interval);
+        loop {
+            let received = match next_echo {
+                Some(deadline) => mailbox.recv_timeout(deadline.saturating_duration_since(Instant::now())),
+                None => mailbox.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            if let (Some(deadline), Some(interval)) = (next_echo, self.echo_interval) {
+                if Instant::now() >= deadline {
+                    self.echo_peers(&itti, mailbox.id, &socket);
+                    next_echo = Some(deadline + interval);
+                }
+            }
+            match received {
+                Ok(envelope) => {
+                    match envelope.msg {
+                        IttiMsg::GtpUdpSendToRemote(buffer) => self.send_to_remote(&socket, &buffer),
+                        IttiMsg::GtpUdpStopThread => {
+                            break;
+                        },
+                        msg => {println!("{:#?}", msg);},
+                    }
+                },
+                Err(RecvTimeoutError::Timeout) => {},
+                Err(RecvTimeoutError::Disconnected) => {
+                    break;
+                },
+            }
+        }
+        drop(receiver);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timer::MockClock;
+
+    fn rx(bind: &str, clock: Arc<MockClock>) -> GtpUdpRx {
+        let socket = UdpSocket::bind(bind).unwrap();
+        GtpUdpRx {
+            itti: Arc::new(Itti::new()),
+            task: IttiTaskId::new(IttiTrxTag::GtpUdp, 0),
+            local: socket.local_addr().unwrap(),
+            socket,
+            tunnels: Arc::new(TunnelTable::default()),
+            paths: GtpPaths::default(),
+            error_indications: Mutex::new(ErrorIndications::new(clock)),
+        }
+    }
+
+    fn gpdu(teid: Teid) -> Bytes {
+        Bytes::from(GtpuHeader::new(GTPU_G_PDU, teid).encode(&[0x45, 0x00]))
+    }
+
+    fn error_indication(peer: &UdpSocket) -> Option<GtpuErrorIndication> {
+        let mut buf = [0; 128];
+        let (len, _) = peer.recv_from(&mut buf).ok()?;
+        let (header, ies) = GtpuHeader::decode(&buf[..len])?;
+        assert_eq!(header.message_type, GTPU_ERROR_INDICATION);
+        GtpuErrorIndication::decode(ies)
+    }
+
+    #[test]
+    fn path_goes_down_after_missed_echoes_and_up_on_a_response() {
+        let mut path = GtpPath::default();
+        let (first, went_down) = path.next_echo(3);
+        assert!(!went_down);
+        assert_eq!(path.on_echo_response(Some(first), None), vec![]);
+        assert_eq!(path.missed, 0);
+        // Three requests in a row go unanswered
+        assert!(!path.next_echo(3).1);
+        assert!(!path.next_echo(3).1);
+        assert!(!path.next_echo(3).1);
+        let (sequence_number, went_down) = path.next_echo(3);
+        assert!(went_down);
+        assert!(path.down);
+        // Down is reported once
+        assert!(!path.next_echo(3).1);
+        assert_eq!(path.on_echo_response(Some(sequence_number.wrapping_add(1)), None), vec![GtpPathEventKind::Up]);
+        assert!(!path.down);
+        assert_eq!(path.missed, 0);
+    }
+
+    #[test]
+    fn stale_echo_response_is_ignored() {
+        let mut path = GtpPath::default();
+        let (stale, _) = path.next_echo(2);
+        path.next_echo(2);
+        path.next_echo(2);
+        assert!(path.down);
+        assert_eq!(path.on_echo_response(Some(stale), Some(1)), vec![]);
+        assert!(path.down);
+        assert_eq!(path.missed, 2);
+        assert_eq!(path.restart_counter, None);
+        // Without a sequence number either
+        assert_eq!(path.on_echo_response(None, None), vec![]);
+        assert!(path.outstanding.is_some());
+    }
+
+    #[test]
+    fn changed_restart_counter_is_reported() {
+        let mut path = GtpPath::default();
+        let (sequence_number, _) = path.next_echo(3);
+        // The first counter learnt is no restart
+        assert_eq!(path.on_echo_response(Some(sequence_number), Some(7)), vec![]);
+        let (sequence_number, _) = path.next_echo(3);
+        assert_eq!(path.on_echo_response(Some(sequence_number), Some(7)), vec![]);
+        let (sequence_number, _) = path.next_echo(3);
+        assert_eq!(path.on_echo_response(Some(sequence_number), Some(8)), vec![GtpPathEventKind::Restarted]);
+        // Not from a late response
+        path.next_echo(3);
+        assert_eq!(path.on_echo_response(Some(sequence_number), Some(7)), vec![]);
+        assert_eq!(path.restart_counter, Some(8));
+    }
+
+    #[test]
+    fn echo_response_reaches_the_path_of_its_peer() {
+        let rx = rx("127.0.0.1:0", Arc::new(MockClock::new()));
+        let peer: IpAddr = "192.0.2.1".parse().unwrap();
+        assert_eq!(rx.on_echo_response(peer, Some(1), Some(1)), vec![]);
+        let (sequence_number, _) = rx.paths.lock().unwrap().entry(peer).or_default().next_echo(1);
+        rx.paths.lock().unwrap().get_mut(&peer).unwrap().next_echo(1);
+        assert_eq!(rx.on_echo_response(peer, Some(sequence_number + 1), Some(1)), vec![GtpPathEventKind::Up]);
+    }
+
+    #[test]
+    fn error_indications_are_limited_per_peer() {
+        let clock = Arc::new(MockClock::new());
+        let mut error_indications = ErrorIndications::new(clock.clone());
+        let peer: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+        assert!(error_indications.allow(peer).is_some());
+        assert!(error_indications.allow(peer).is_none());
+        assert!(error_indications.allow(other).is_some());
+        clock.advance(GTPU_ERROR_INDICATION_INTERVAL / 2);
+        assert!(error_indications.allow(peer).is_none());
+        clock.advance(GTPU_ERROR_INDICATION_INTERVAL / 2);
+        assert!(error_indications.allow(peer).is_some());
+    }
+
+    #[test]
+    fn error_indication_peers_are_bounded() {
+        let clock = Arc::new(MockClock::new());
+        let mut error_indications = ErrorIndications::new(clock.clone());
+        for peer in 0..GTPU_ERROR_INDICATION_PEERS as u32 {
+            assert!(error_indications.allow(IpAddr::from(peer.to_be_bytes())).is_some());
+        }
+        let new_peer: IpAddr = "198.51.100.1".parse().unwrap();
+        assert!(error_indications.allow(new_peer).is_none());
+        // Peers not sent one for an interval make room
+        clock.advance(GTPU_ERROR_INDICATION_INTERVAL);
+        assert!(error_indications.allow(new_peer).is_some());
+        assert_eq!(error_indications.peers.len(), 1);
+    }
+
+    #[test]
+    fn unknown_teid_is_answered_with_a_limited_error_indication() {
+        // Indications always go to port 2152 of the sender
+        let peer = UdpSocket::bind(("127.0.0.46", GTPU_PORT)).unwrap();
+        peer.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+        let clock = Arc::new(MockClock::new());
+        let rx = rx("0.0.0.0:0", clock.clone());
+        let from = SocketAddr::new(peer.local_addr().unwrap().ip(), 40000);
+        rx.on_datagram(gpdu(0x1234), from);
+        let indication = error_indication(&peer).unwrap();
+        assert_eq!(indication.teid, 0x1234);
+        // Resolved for the wildcard bind, and kept for the next indications
+        assert_eq!(indication.peer_address, "127.0.0.1".parse::<IpAddr>().unwrap());
+        assert_eq!(rx.error_indications.lock().unwrap().peers[&from.ip()].local, Some(indication.peer_address));
+        rx.on_datagram(gpdu(0x1235), from);
+        assert!(error_indication(&peer).is_none());
+        clock.advance(GTPU_ERROR_INDICATION_INTERVAL);
+        rx.on_datagram(gpdu(0x1236), from);
+        assert_eq!(error_indication(&peer).unwrap().teid, 0x1236);
+        // TEID 0 never gets one
+        clock.advance(GTPU_ERROR_INDICATION_INTERVAL);
+        rx.on_datagram(gpdu(0), from);
+        assert!(error_indication(&peer).is_none());
+    }
+}
@@ -0,0 +1,932 @@
+use std::{collections::HashMap, fmt, sync::{Arc, Condvar, Mutex, RwLock, atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering}}, thread::{self, Thread}, time::{Duration, Instant}};
+
+use crossbeam::{channel::{bounded, select, unbounded, Receiver, RecvError, RecvTimeoutError, Sender, TryRecvError, TrySendError}, queue::SegQueue};
+use serde::Deserialize;
+
+use crate::{msg::{IttiEnvelope, IttiHeader, IttiLane, IttiMsg, IttiTaskId, IttiTrxTag}, trace::TraceRecorder};
+
+#[derive(Debug)]
+pub enum IttiError {
+    UnknownTask(IttiTaskId),
+    TaskDead(IttiTaskId),
+    MailboxFull(IttiTaskId),
+    // Boxed, every send returns this error type
+    NoRoute(Box<IttiMsg>),
+    NoReplyTo(u64),
+    ReplyDropped(u64),
+    Timeout(u64),
+}
+
+impl fmt::Display for IttiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IttiError::UnknownTask(id) => write!(f, "no task registered for {}", id),
+            IttiError::TaskDead(id) => write!(f, "task {} is no longer receiving", id),
+            IttiError::MailboxFull(id) => write!(f, "mailbox of {} is full", id),
+            IttiError::NoRoute(msg) => write!(f, "no task owns {:?}", msg),
+            IttiError::NoReplyTo(id) => write!(f, "request {} has nowhere to reply to", id),
+            IttiError::ReplyDropped(id) => write!(f, "requester of {} is gone", id),
+            IttiError::Timeout(id) => write!(f, "no reply to request {}", id),
+        }
+    }
+}
+
+impl std::error::Error for IttiError {}
+
+/// Receiving side of a task, handed out by `Itti::register`. Only the task
+/// owns it; the registry keeps the sending side.
+///
+/// Every receive takes from the control lane while it has messages, and only
+/// then waits on both lanes. Both lanes are disconnected together, and the
+/// mailbox reports it once neither has anything left.
+pub struct Mailbox {
+    pub id: IttiTaskId,
+    control: Receiver<IttiEnvelope>,
+    data: Receiver<IttiEnvelope>,
+    in_flight: Option<InFlight>,
+    counters: Arc<MailboxCounters>,
+    // Dropped together with the mailbox, which tells `shutdown` the task exited
+    _alive: Sender<()>,
+}
+
+/// Copy of the message a task received last, kept for a supervisor to log
+/// when the task panics while handling it.
+pub type InFlight = Arc<Mutex<Option<IttiEnvelope>>>;
+
+impl Mailbox {
+    /// Starts keeping a copy of every received message.
+    pub fn track_in_flight(&mut self) -> InFlight {
+        self.in_flight.get_or_insert_with(|| Arc::new(Mutex::new(None))).clone()
+    }
+
+    fn received(&self, envelope: IttiEnvelope) -> IttiEnvelope {
+        let latency_us = envelope.latency().as_micros().try_into().unwrap_or(u64::MAX);
+        self.counters.max_latency_us.fetch_max(latency_us, Ordering::Relaxed);
+        if let Some(in_flight) = &self.in_flight {
+            *in_flight.lock().unwrap() = Some(envelope.clone());
+        }
+        envelope
+    }
+
+    pub fn recv(&self) -> Result<IttiEnvelope, RecvError> {
+        if let Ok(envelope) = self.control.try_recv() {
+            return Ok(self.received(envelope));
+        }
+        let received = select! {
+            recv(self.control) -> envelope => envelope.or_else(|_| self.data.recv()),
+            recv(self.data) -> envelope => envelope.or_else(|_| self.control.try_recv().map_err(|_| RecvError)),
+        };
+        received.map(|envelope| self.received(envelope))
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<IttiEnvelope, RecvTimeoutError> {
+        if let Ok(envelope) = self.control.try_recv() {
+            return Ok(self.received(envelope));
+        }
+        let received = select! {
+            recv(self.control) -> envelope => envelope.or_else(|_| self.data.recv_timeout(timeout)),
+            recv(self.data) -> envelope => envelope.or_else(|_| self.control.try_recv().map_err(|_| RecvTimeoutError::Disconnected)),
+            default(timeout) => Err(RecvTimeoutError::Timeout),
+        };
+        received.map(|envelope| self.received(envelope))
+    }
+
+    #[cfg(test)]
+    pub fn try_recv(&self) -> Result<IttiEnvelope, TryRecvError> {
+        self.control
+            .try_recv()
+            .or_else(|_| self.data.try_recv())
+            .map(|envelope| self.received(envelope))
+    }
+}
+
+/// What a send does when the mailbox of a bounded task is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// Wait for room. The dispatcher waits too, which stalls every task
+    #[default]
+    Block,
+    DropNewest,
+    /// Evict the oldest data message. The control lane waits for room
+    /// instead, as its oldest message may be a stop, a release or a timer
+    DropOldest,
+    /// Fail the send with `IttiError::MailboxFull`
+    Reject,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MailboxConfig {
+    /// Per lane, `None` for an unbounded mailbox
+    pub capacity: Option<usize>,
+    #[serde(default)]
+    pub policy: OverflowPolicy,
+}
+
+impl MailboxConfig {
+    pub fn unbounded() -> MailboxConfig {
+        MailboxConfig { capacity: None, policy: OverflowPolicy::Block }
+    }
+
+    pub fn bounded(capacity: usize, policy: OverflowPolicy) -> MailboxConfig {
+        MailboxConfig { capacity: Some(capacity), policy }
+    }
+}
+
+#[derive(Default)]
+struct MailboxCounters {
+    delivered: AtomicU64,
+    dropped: AtomicU64,
+    rejected: AtomicU64,
+    high_water: AtomicUsize,
+    // Longest time a received message waited, from its send
+    max_latency_us: AtomicU64,
+}
+
+/// Snapshot of the queue of one task.
+#[derive(Debug, Clone)]
+pub struct MailboxStats {
+    pub id: IttiTaskId,
+    pub config: MailboxConfig,
+    pub control_depth: usize,
+    pub data_depth: usize,
+    pub high_water: usize,
+    pub delivered: u64,
+    pub dropped: u64,
+    pub rejected: u64,
+    pub max_latency: Duration,
+}
+
+impl fmt::Display for MailboxStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.config.capacity {
+            Some(capacity) => write!(f, "{} ({:?}, capacity {}", self.id, self.config.policy, capacity)?,
+            None => write!(f, "{} (unbounded", self.id)?,
+        }
+        write!(f, ", depth {}/{}, high water {}, delivered {}, dropped {}, rejected {}, max latency {:?})",
+            self.control_depth, self.data_depth, self.high_water, self.delivered, self.dropped, self.rejected, self.max_latency)
+    }
+}
+
+#[derive(Clone)]
+struct LanePort {
+    tx: Sender<IttiEnvelope>,
+    // Lets `DropOldest` pop from the head of the queue
+    rx: Option<Receiver<IttiEnvelope>>,
+}
+
+impl LanePort {
+    fn new(config: MailboxConfig, lane: IttiLane) -> (LanePort, Receiver<IttiEnvelope>) {
+        let (tx, rx) = match config.capacity {
+            Some(capacity) => bounded::<IttiEnvelope>(capacity),
+            None => unbounded::<IttiEnvelope>(),
+        };
+        let port = LanePort {
+            tx,
+            rx: match (config.policy, lane) {
+                (OverflowPolicy::DropOldest, IttiLane::Data) => Some(rx.clone()),
+                _ => None,
+            },
+        };
+        (port, rx)
+    }
+}
+
+/// Sending side of a task, cloned out of the registry for every send.
+#[derive(Clone)]
+struct TaskPort {
+    id: IttiTaskId,
+    config: MailboxConfig,
+    control: LanePort,
+    data: LanePort,
+    exited: Receiver<()>,
+    counters: Arc<MailboxCounters>,
+}
+
+impl TaskPort {
+    fn depth(&self) -> usize {
+        self.control.tx.len() + self.data.tx.len()
+    }
+
+    fn delivered(&self) -> Result<(), IttiError> {
+        self.counters.delivered.fetch_add(1, Ordering::Relaxed);
+        self.counters.high_water.fetch_max(self.depth(), Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn send(&self, envelope: IttiEnvelope) -> Result<(), IttiError> {
+        // A stop message is never lost, or `shutdown` would wait on the task,
+        // and nothing is evicted from the control lane, where one may be queued
+        let policy = match (self.config.policy, envelope.msg.lane()) {
+            (OverflowPolicy::DropNewest | OverflowPolicy::Reject, _) if envelope.msg.is_stop_thread() => OverflowPolicy::Block,
+            (OverflowPolicy::DropOldest, IttiLane::Control) => OverflowPolicy::Block,
+            (policy, _) => policy,
+        };
+        let lane = match envelope.msg.lane() {
+            IttiLane::Control => &self.control,
+            IttiLane::Data => &self.data,
+        };
+        let mut envelope = envelope;
+        loop {
+            let full = match policy {
+                OverflowPolicy::Block => {
+                    return match lane.tx.send(envelope) {
+                        Ok(_) => self.delivered(),
+                        Err(_) => Err(IttiError::TaskDead(self.id)),
+                    };
+                },
+                _ => match lane.tx.try_send(envelope) {
+                    Ok(_) => return self.delivered(),
+                    Err(TrySendError::Disconnected(_)) => return Err(IttiError::TaskDead(self.id)),
+                    Err(TrySendError::Full(full)) => full,
+                },
+            };
+            match policy {
+                OverflowPolicy::DropNewest => {
+                    self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+                    return Ok(());
+                },
+                OverflowPolicy::Reject => {
+                    self.counters.rejected.fetch_add(1, Ordering::Relaxed);
+                    return Err(IttiError::MailboxFull(self.id));
+                },
+                _ => {
+                    // The registry holds a receiver too, so a dead task only
+                    // shows on its `exited` channel
+                    if self.exited.try_recv() == Err(TryRecvError::Disconnected) {
+                        return Err(IttiError::TaskDead(self.id));
+                    }
+                    if let Some(Ok(_)) = lane.rx.as_ref().map(|rx| rx.try_recv()) {
+                        self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+                    }
+                    envelope = full;
+                },
+            }
+        }
+    }
+
+    fn stats(&self) -> MailboxStats {
+        MailboxStats {
+            id: self.id,
+            config: self.config,
+            control_depth: self.control.tx.len(),
+            data_depth: self.data.tx.len(),
+            high_water: self.counters.high_water.load(Ordering::Relaxed),
+            delivered: self.counters.delivered.load(Ordering::Relaxed),
+            dropped: self.counters.dropped.load(Ordering::Relaxed),
+            rejected: self.counters.rejected.load(Ordering::Relaxed),
+            max_latency: Duration::from_micros(self.counters.max_latency_us.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Tasks are stopped producers first, so every message a task emits while
+/// draining still finds its consumer running.
+/// The timer task serves every other task and goes last.
+pub const SHUTDOWN_ORDER: [IttiTrxTag; 7] = [
+    IttiTrxTag::Listener,
+    IttiTrxTag::NetIfMgmt,
+    IttiTrxTag::NasDecoer,
+    IttiTrxTag::Tun,
+    IttiTrxTag::PduSessionMgmt,
+    IttiTrxTag::GtpUdp,
+    IttiTrxTag::Timer,
+];
+
+const TASK_STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+impl IttiTrxTag {
+    pub fn stop_msg(&self) -> IttiMsg {
+        match self {
+            IttiTrxTag::PduSessionMgmt => IttiMsg::PduSessionMgmtStopThread,
+            IttiTrxTag::NasDecoer => IttiMsg::Nas5GsStopThread,
+            IttiTrxTag::Listener => IttiMsg::ListenerStopThread,
+            IttiTrxTag::GtpUdp => IttiMsg::GtpUdpStopThread,
+            IttiTrxTag::Timer => IttiMsg::TimerStopThread,
+            IttiTrxTag::Tun => IttiMsg::TunStopThread,
+            IttiTrxTag::NetIfMgmt => IttiMsg::NetIfMgmtStopThread,
+        }
+    }
+}
+
+/// Registry of running ITTI tasks, shared by every thread, plus the global
+/// queues the dispatcher routes from, one per lane.
+pub struct Itti {
+    tasks: Mutex<HashMap<IttiTaskId, TaskPort>>,
+    // Number of instances of every tag messages are sharded across, which
+    // only grows so that keys never move while a restarted instance is away
+    instances: Mutex<HashMap<IttiTrxTag, u16>>,
+    registered: Condvar,
+    control_queue: SegQueue<IttiEnvelope>,
+    data_queue: SegQueue<IttiEnvelope>,
+    dispatcher: Mutex<Option<Thread>>,
+    next_correlation_id: AtomicU64,
+    trace: RwLock<Option<TraceRecorder>>,
+    stopping: AtomicBool,
+    stopped: AtomicBool,
+    // Result of `shutdown` once it is done, for `wait_stopped`
+    outcome: Mutex<Option<bool>>,
+    done: Condvar,
+}
+
+impl Itti {
+    pub fn new() -> Itti {
+        Itti {
+            tasks: Mutex::new(HashMap::new()),
+            instances: Mutex::new(HashMap::new()),
+            registered: Condvar::new(),
+            control_queue: SegQueue::new(),
+            data_queue: SegQueue::new(),
+            dispatcher: Mutex::new(None),
+            next_correlation_id: AtomicU64::new(1),
+            trace: RwLock::new(None),
+            stopping: AtomicBool::new(false),
+            stopped: AtomicBool::new(false),
+            outcome: Mutex::new(None),
+            done: Condvar::new(),
+        }
+    }
+
+    /// Creates an unbounded mailbox for a task. Registering an ID again
+    /// replaces the previous mailbox, whose owner then stops receiving messages.
+    #[cfg(test)]
+    pub fn register(&self, id: impl Into<IttiTaskId>) -> Mailbox {
+        self.register_with(id, MailboxConfig::unbounded())
+    }
+
+    pub fn register_with(&self, id: impl Into<IttiTaskId>, config: MailboxConfig) -> Mailbox {
+        let id = id.into();
+        let (control, control_rx) = LanePort::new(config, IttiLane::Control);
+        let (data, data_rx) = LanePort::new(config, IttiLane::Data);
+        let (alive, exited) = unbounded::<()>();
+        let counters = Arc::new(MailboxCounters::default());
+        let port = TaskPort {
+            id,
+            config,
+            control,
+            data,
+            exited,
+            counters: counters.clone(),
+        };
+        let mut instances = self.instances.lock().unwrap();
+        let count = instances.entry(id.tag).or_insert(0);
+        *count = (*count).max(id.instance + 1);
+        drop(instances);
+        self.tasks.lock().unwrap().insert(id, port);
+        self.registered.notify_all();
+        Mailbox {
+            id,
+            control: control_rx,
+            data: data_rx,
+            in_flight: None,
+            counters,
+            _alive: alive,
+        }
+    }
+
+    pub fn unregister(&self, id: impl Into<IttiTaskId>) {
+        self.tasks.lock().unwrap().remove(&id.into());
+    }
+
+    /// Blocks until a task has registered the ID.
+    pub fn wait_for(&self, id: impl Into<IttiTaskId>) {
+        let id = id.into();
+        let tasks = self.tasks.lock().unwrap();
+        let _tasks = self
+            .registered
+            .wait_while(tasks, |tasks| !tasks.contains_key(&id))
+            .unwrap();
+    }
+
+    fn port(&self, id: IttiTaskId) -> Result<TaskPort, IttiError> {
+        self.tasks
+            .lock()
+            .unwrap()
+            .get(&id)
+            .cloned()
+            .ok_or(IttiError::UnknownTask(id))
+    }
+
+    pub fn instances(&self, tag: IttiTrxTag) -> u16 {
+        self.instances.lock().unwrap().get(&tag).copied().unwrap_or(0)
+    }
+
+    /// Instances of task `tag` that handle the message: the one owning its
+    /// shard key, instance 0 when it has none, or every instance for a
+    /// message each of them answers for its own shard.
+    pub fn route(&self, tag: IttiTrxTag, msg: &IttiMsg) -> Vec<IttiTaskId> {
+        let instances = self.instances(tag);
+        if msg.to_all_instances() {
+            return (0..instances.max(1)).map(|instance| IttiTaskId::new(tag, instance)).collect();
+        }
+        let instance = match msg.shard_key() {
+            Some(key) => key.instance(instances),
+            None => 0,
+        };
+        vec![IttiTaskId::new(tag, instance)]
+    }
+
+    /// Queue depth and overflow counters of every registered task, by task.
+    pub fn stats(&self) -> Vec<MailboxStats> {
+        let mut stats: Vec<MailboxStats> = self.tasks.lock().unwrap().values().map(|port| port.stats()).collect();
+        stats.sort_by_key(|stats| (stats.id.tag as u8, stats.id.instance));
+        stats
+    }
+
+    /// Records every message delivered from now on.
+    pub fn set_trace(&self, recorder: TraceRecorder) {
+        *self.trace.write().unwrap() = Some(recorder);
+    }
+
+    fn record(&self, envelope: &IttiEnvelope) {
+        if let Some(recorder) = self.trace.read().unwrap().as_ref() {
+            recorder.record(envelope);
+        }
+    }
+
+    /// Wraps a message in a new envelope with a fresh correlation ID.
+    pub fn envelope(&self, src: Option<IttiTaskId>, dst: Option<IttiTaskId>, msg: IttiMsg) -> IttiEnvelope {
+        IttiEnvelope {
+            header: IttiHeader {
+                src,
+                dst,
+                correlation_id: self.next_correlation_id.fetch_add(1, Ordering::Relaxed),
+                reply_to: None,
+                enqueued_at: Instant::now(),
+            },
+            msg,
+        }
+    }
+
+    fn deliver_to(&self, id: IttiTaskId, mut envelope: IttiEnvelope) -> Result<(), IttiError> {
+        envelope.header.dst = Some(id);
+        self.record(&envelope);
+        // Clone the port so the registry is not locked while sending
+        self.port(id)?.send(envelope)
+    }
+
+    /// Sends the envelope to each task, the same correlation ID included, and
+    /// returns how many were sent. Only extra tasks get a copy.
+    fn deliver(&self, dsts: Vec<IttiTaskId>, envelope: IttiEnvelope) -> Result<usize, IttiError> {
+        let (last, others) = match dsts.split_last() {
+            Some(split) => split,
+            None => return Ok(0),
+        };
+        for &id in others {
+            self.deliver_to(id, envelope.clone())?;
+        }
+        self.deliver_to(*last, envelope)?;
+        Ok(dsts.len())
+    }
+
+    /// Delivers an envelope to its `dst`, or to the owners of its message when
+    /// it has none.
+    pub fn send_envelope(&self, envelope: IttiEnvelope) -> Result<(), IttiError> {
+        match (envelope.header.dst, envelope.msg.destination()) {
+            (Some(id), _) => self.deliver(vec![id], envelope).map(|_| ()),
+            (None, Some(tag)) => self.send_routed(tag, envelope),
+            (None, None) => Err(IttiError::NoRoute(Box::new(envelope.msg))),
+        }
+    }
+
+    /// Delivers an envelope to the instances of task `tag` that handle its
+    /// message, whatever its `dst`.
+    pub fn send_routed(&self, tag: IttiTrxTag, envelope: IttiEnvelope) -> Result<(), IttiError> {
+        self.deliver(self.route(tag, &envelope.msg), envelope).map(|_| ())
+    }
+
+    /// Sends a message from outside the task set to the instances of `tag`
+    /// that own it.
+    #[cfg(test)]
+    pub fn send(&self, tag: IttiTrxTag, msg: IttiMsg) -> Result<(), IttiError> {
+        self.send_routed(tag, self.envelope(None, None, msg))
+    }
+
+    /// Sends a message from outside the task set to one given instance.
+    #[cfg(test)]
+    pub fn send_to(&self, dst: IttiTaskId, msg: IttiMsg) -> Result<(), IttiError> {
+        self.send_envelope(self.envelope(None, Some(dst), msg))
+    }
+
+    /// Sends a message on behalf of task `src`, which receives any reply.
+    /// A message for every instance gets one reply from each.
+    pub fn send_from(&self, src: IttiTaskId, dst: IttiTrxTag, msg: IttiMsg) -> Result<u64, IttiError> {
+        let envelope = self.envelope(Some(src), None, msg);
+        let correlation_id = envelope.header.correlation_id;
+        self.send_routed(dst, envelope)?;
+        Ok(correlation_id)
+    }
+
+    /// Answers a request, on its `reply_to` channel if it has one and to its
+    /// source task otherwise. The reply keeps the request's correlation ID.
+    pub fn reply(&self, request: &IttiHeader, src: IttiTaskId, msg: IttiMsg) -> Result<(), IttiError> {
+        let correlation_id = request.correlation_id;
+        let reply = IttiEnvelope {
+            header: IttiHeader {
+                src: Some(src),
+                dst: request.src,
+                correlation_id,
+                reply_to: None,
+                enqueued_at: Instant::now(),
+            },
+            msg,
+        };
+        match (&request.reply_to, request.src) {
+            (Some(reply_to), _) => {
+                self.record(&reply);
+                reply_to.send(reply).map_err(|_| IttiError::ReplyDropped(correlation_id))
+            },
+            (None, Some(_)) => self.send_envelope(reply),
+            (None, None) => Err(IttiError::NoReplyTo(correlation_id)),
+        }
+    }
+
+    /// Sends a request from outside the task set and blocks for the reply of
+    /// every instance it went to, for at most `timeout` in total.
+    pub fn call(&self, dst: IttiTrxTag, msg: IttiMsg, timeout: Duration) -> Result<Vec<IttiEnvelope>, IttiError> {
+        let deadline = Instant::now() + timeout;
+        let (reply_to, replies) = unbounded::<IttiEnvelope>();
+        let mut envelope = self.envelope(None, None, msg);
+        let correlation_id = envelope.header.correlation_id;
+        envelope.header.reply_to = Some(reply_to);
+        let sent = self.deliver(self.route(dst, &envelope.msg), envelope)?;
+        (0..sent)
+            .map(|_| replies.recv_deadline(deadline).map_err(|_| IttiError::Timeout(correlation_id)))
+            .collect()
+    }
+
+    /// Queues a message for the dispatcher, which routes it by its destination.
+    pub fn post_envelope(&self, envelope: IttiEnvelope) {
+        match envelope.msg.lane() {
+            IttiLane::Control => self.control_queue.push(envelope),
+            IttiLane::Data => self.data_queue.push(envelope),
+        }
+        if let Some(dispatcher) = self.dispatcher.lock().unwrap().as_ref() {
+            dispatcher.unpark();
+        }
+    }
+
+    /// Runs the dispatcher on the calling thread, control lane first, parking
+    /// while both global queues are empty. `post` unparks it, and an unpark that races with the
+    /// emptiness check is kept as the thread's token, so no message is missed.
+    pub fn run_dispatcher(&self) {
+        *self.dispatcher.lock().unwrap() = Some(thread::current());
+        loop {
+            while let Some(envelope) = self.control_queue.pop().or_else(|| self.data_queue.pop()) {
+                if let Err(e) = self.send_envelope(envelope) {
+                    println!("itti dispatcher: {}", e);
+                }
+            }
+            if self.stopped.load(Ordering::Acquire) {
+                break;
+            }
+            thread::park();
+        }
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::Acquire)
+    }
+
+    /// Stops every registered task in `SHUTDOWN_ORDER`, all instances of a tag
+    /// at once, then the dispatcher.
+    /// Each stop message is posted behind the control messages already queued,
+    /// so a task handles its in-flight signalling before it exits; user-plane
+    /// buffers still queued are dropped with the mailbox. Blocks until done
+    /// and returns false if a task did not exit in time; it must therefore not
+    /// be called from a task thread. Only the first call does anything, later
+    /// ones wait for it and return its result.
+    pub fn shutdown(&self) -> bool {
+        if self.stopping.swap(true, Ordering::AcqRel) {
+            return self.wait_stopped();
+        }
+        let mut clean = true;
+        for tag in SHUTDOWN_ORDER {
+            let mut ports: Vec<TaskPort> = self
+                .tasks
+                .lock()
+                .unwrap()
+                .values()
+                .filter(|port| port.id.tag == tag)
+                .cloned()
+                .collect();
+            ports.sort_by_key(|port| port.id.instance);
+            for port in &ports {
+                println!("itti shutdown: stopping {}", port.stats());
+                // Addressed to the instance, the stop message carries no shard key
+                self.post_envelope(self.envelope(None, Some(port.id), tag.stop_msg()));
+            }
+            for port in &ports {
+                if port.exited.recv_timeout(TASK_STOP_TIMEOUT) == Err(RecvTimeoutError::Timeout) {
+                    println!("itti shutdown: {} did not stop within {:?}", port.id, TASK_STOP_TIMEOUT);
+                    clean = false;
+                }
+                self.unregister(port.id);
+            }
+        }
+        self.stopped.store(true, Ordering::Release);
+        if let Some(dispatcher) = self.dispatcher.lock().unwrap().as_ref() {
+            dispatcher.unpark();
+        }
+        *self.outcome.lock().unwrap() = Some(clean);
+        self.done.notify_all();
+        clean
+    }
+
+    /// Blocks until a `shutdown`, from any thread, is done and returns its
+    /// result: false if a task did not exit in time.
+    pub fn wait_stopped(&self) -> bool {
+        let mut outcome = self.outcome.lock().unwrap();
+        loop {
+            match *outcome {
+                Some(clean) => return clean,
+                None => outcome = self.done.wait(outcome).unwrap(),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::msg::{GtpDirection, GtpTunnelCfg, PduSessionQuery, PduSessionQueryKind, UdpGtpBuffer};
+
+    const TASK: IttiTaskId = IttiTaskId { tag: IttiTrxTag::Timer, instance: 0 };
+
+    fn control(secs: u64) -> IttiMsg {
+        IttiMsg::TimerAdvance(Duration::from_secs(secs))
+    }
+
+    fn data(teid: u32) -> IttiMsg {
+        IttiMsg::TunSendDownlink(UdpGtpBuffer {
+            teid,
+            pdu_session_id: 1,
+            qfi: None,
+            rqi: false,
+            peer: None,
+            direction: GtpDirection::Downlink,
+            payload: Bytes::new(),
+        })
+    }
+
+    fn stats(itti: &Itti) -> MailboxStats {
+        itti.stats().into_iter().find(|stats| stats.id == TASK).unwrap()
+    }
+
+    fn received(mailbox: &Mailbox) -> Vec<String> {
+        let mut received = vec![];
+        while let Ok(envelope) = mailbox.recv_timeout(Duration::from_millis(50)) {
+            received.push(match envelope.msg {
+                IttiMsg::TimerAdvance(duration) => format!("control {}", duration.as_secs()),
+                IttiMsg::TunSendDownlink(buffer) => format!("data {}", buffer.teid),
+                msg if msg.is_stop_thread() => "stop".to_string(),
+                msg => format!("{:?}", msg),
+            });
+        }
+        received
+    }
+
+    #[test]
+    fn block_waits_for_room() {
+        let itti = Arc::new(Itti::new());
+        let mailbox = itti.register_with(TASK, MailboxConfig::bounded(2, OverflowPolicy::Block));
+        itti.send_to(TASK, data(1)).unwrap();
+        itti.send_to(TASK, data(2)).unwrap();
+        let sender = {
+            let itti = itti.clone();
+            thread::spawn(move || itti.send_to(TASK, data(3)))
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!((stats(&itti).delivered, stats(&itti).high_water), (2, 2));
+        assert_eq!(received(&mailbox), ["data 1", "data 2", "data 3"]);
+        sender.join().unwrap().unwrap();
+        let stats = stats(&itti);
+        assert_eq!((stats.delivered, stats.dropped, stats.rejected), (3, 0, 0));
+    }
+
+    #[test]
+    fn drop_newest_counts_dropped() {
+        let itti = Itti::new();
+        let mailbox = itti.register_with(TASK, MailboxConfig::bounded(2, OverflowPolicy::DropNewest));
+        for teid in 1..=4 {
+            itti.send_to(TASK, data(teid)).unwrap();
+        }
+        let stats = stats(&itti);
+        assert_eq!((stats.data_depth, stats.high_water, stats.delivered, stats.dropped, stats.rejected), (2, 2, 2, 2, 0));
+        assert_eq!(received(&mailbox), ["data 1", "data 2"]);
+    }
+
+    #[test]
+    fn drop_oldest_counts_dropped() {
+        let itti = Itti::new();
+        let mailbox = itti.register_with(TASK, MailboxConfig::bounded(2, OverflowPolicy::DropOldest));
+        for teid in 1..=4 {
+            itti.send_to(TASK, data(teid)).unwrap();
+        }
+        let stats = stats(&itti);
+        assert_eq!((stats.data_depth, stats.high_water, stats.delivered, stats.dropped, stats.rejected), (2, 2, 4, 2, 0));
+        assert_eq!(received(&mailbox), ["data 3", "data 4"]);
+    }
+
+    #[test]
+    fn reject_counts_rejected() {
+        let itti = Itti::new();
+        let mailbox = itti.register_with(TASK, MailboxConfig::bounded(2, OverflowPolicy::Reject));
+        itti.send_to(TASK, data(1)).unwrap();
+        itti.send_to(TASK, data(2)).unwrap();
+        assert!(matches!(itti.send_to(TASK, data(3)), Err(IttiError::MailboxFull(TASK))));
+        let stats = stats(&itti);
+        assert_eq!((stats.data_depth, stats.high_water, stats.delivered, stats.dropped, stats.rejected), (2, 2, 2, 0, 1));
+        assert_eq!(received(&mailbox), ["data 1", "data 2"]);
+    }
+
+    #[test]
+    fn stop_is_delivered_to_full_mailbox() {
+        for policy in [OverflowPolicy::DropNewest, OverflowPolicy::DropOldest, OverflowPolicy::Reject] {
+            let itti = Arc::new(Itti::new());
+            let mailbox = itti.register_with(TASK, MailboxConfig::bounded(2, policy));
+            itti.send_to(TASK, control(1)).unwrap();
+            itti.send_to(TASK, control(2)).unwrap();
+            let sender = {
+                let itti = itti.clone();
+                thread::spawn(move || itti.send_to(TASK, IttiMsg::TimerStopThread))
+            };
+            thread::sleep(Duration::from_millis(50));
+            assert_eq!(received(&mailbox), ["control 1", "control 2", "stop"], "{:?}", policy);
+            sender.join().unwrap().unwrap();
+            assert_eq!((stats(&itti).dropped, stats(&itti).rejected), (0, 0), "{:?}", policy);
+        }
+    }
+
+    #[test]
+    fn drop_oldest_never_evicts_control_lane() {
+        let itti = Arc::new(Itti::new());
+        let mailbox = itti.register_with(TASK, MailboxConfig::bounded(1, OverflowPolicy::DropOldest));
+        itti.send_to(TASK, IttiMsg::TimerStopThread).unwrap();
+        let sender = {
+            let itti = itti.clone();
+            thread::spawn(move || itti.send_to(TASK, control(1)))
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(received(&mailbox), ["stop", "control 1"]);
+        sender.join().unwrap().unwrap();
+        assert_eq!(stats(&itti).dropped, 0);
+    }
+
+    fn run_dispatcher(itti: &Arc<Itti>) -> thread::JoinHandle<()> {
+        let itti = itti.clone();
+        thread::spawn(move || itti.run_dispatcher())
+    }
+
+    #[test]
+    fn shutdown_result_reaches_waiters() {
+        let itti = Arc::new(Itti::new());
+        let mailbox = itti.register(IttiTrxTag::Timer);
+        let task = thread::spawn(move || while let Ok(envelope) = mailbox.recv() {
+            if envelope.msg.is_stop_thread() {
+                break;
+            }
+        });
+        let dispatcher = run_dispatcher(&itti);
+        let waiter = {
+            let itti = itti.clone();
+            thread::spawn(move || itti.wait_stopped())
+        };
+        assert!(itti.shutdown());
+        assert!(waiter.join().unwrap());
+        assert!(itti.shutdown());
+        task.join().unwrap();
+        dispatcher.join().unwrap();
+    }
+
+    #[test]
+    fn shutdown_reports_task_not_stopping() {
+        let itti = Arc::new(Itti::new());
+        // Never received from, so the task never exits
+        let _mailbox = itti.register(IttiTrxTag::Timer);
+        let dispatcher = run_dispatcher(&itti);
+        assert!(!itti.shutdown());
+        assert!(!itti.wait_stopped());
+        dispatcher.join().unwrap();
+    }
+
+    fn tunnel_setup(pdu_session_id: u8, remote_teid: u32) -> IttiMsg {
+        IttiMsg::PduSessionMgmtTunnelSetup(GtpTunnelCfg { pdu_session_id, remote_teid, remote_addr: [10, 0, 0, 1].into() })
+    }
+
+    fn session_managers(itti: &Itti, instances: u16) -> Vec<Mailbox> {
+        (0..instances).map(|instance| itti.register(IttiTaskId::new(IttiTrxTag::PduSessionMgmt, instance))).collect()
+    }
+
+    #[test]
+    fn keyed_messages_keep_their_instance_and_order() {
+        let itti = Itti::new();
+        let mailboxes = session_managers(&itti, 3);
+        for round in 0..4 {
+            for pdu_session_id in 1..=6 {
+                itti.send(IttiTrxTag::PduSessionMgmt, tunnel_setup(pdu_session_id, round)).unwrap();
+            }
+        }
+        for (instance, mailbox) in mailboxes.iter().enumerate() {
+            let mut received = vec![];
+            while let Ok(envelope) = mailbox.try_recv() {
+                match envelope.msg {
+                    IttiMsg::PduSessionMgmtTunnelSetup(cfg) => received.push((cfg.pdu_session_id, cfg.remote_teid)),
+                    msg => panic!("unexpected {:?}", msg),
+                }
+            }
+            let expected: Vec<(u8, u32)> = (0..4)
+                .flat_map(|round| (1..=6).filter(|id| *id as usize % 3 == instance).map(move |id| (id, round)))
+                .collect();
+            assert_eq!(received, expected, "instance {}", instance);
+        }
+        // Keys do not move while an instance restarts
+        itti.unregister(IttiTaskId::new(IttiTrxTag::PduSessionMgmt, 1));
+        assert_eq!(itti.route(IttiTrxTag::PduSessionMgmt, &tunnel_setup(4, 0)), [IttiTaskId::new(IttiTrxTag::PduSessionMgmt, 1)]);
+        assert!(matches!(itti.send(IttiTrxTag::PduSessionMgmt, tunnel_setup(4, 0)), Err(IttiError::UnknownTask(_))));
+        assert_eq!(itti.route(IttiTrxTag::PduSessionMgmt, &tunnel_setup(5, 0)), [IttiTaskId::new(IttiTrxTag::PduSessionMgmt, 2)]);
+    }
+
+    #[test]
+    fn queries_over_all_sessions_go_to_every_instance() {
+        let itti = Itti::new();
+        let mailboxes = session_managers(&itti, 3);
+        let query = |kind| IttiMsg::PduSessionMgmtQuery(PduSessionQuery { kind });
+        let correlation_id = itti.send_from(IttiTrxTag::NasDecoer.into(), IttiTrxTag::PduSessionMgmt, query(PduSessionQueryKind::ListAll)).unwrap();
+        itti.send(IttiTrxTag::PduSessionMgmt, query(PduSessionQueryKind::ById(5))).unwrap();
+        for (instance, mailbox) in mailboxes.iter().enumerate() {
+            let envelope = mailbox.try_recv().unwrap();
+            assert_eq!(envelope.header.correlation_id, correlation_id);
+            assert_eq!(envelope.header.dst, Some(mailbox.id));
+            assert!(matches!(envelope.msg, IttiMsg::PduSessionMgmtQuery(PduSessionQuery { kind: PduSessionQueryKind::ListAll })));
+            match instance {
+                2 => assert!(matches!(mailbox.try_recv().unwrap().msg, IttiMsg::PduSessionMgmtQuery(PduSessionQuery { kind: PduSessionQueryKind::ById(5) }))),
+                _ => assert!(mailbox.try_recv().is_err()),
+            }
+        }
+    }
+
+    const REQUESTER: IttiTaskId = IttiTaskId { tag: IttiTrxTag::NasDecoer, instance: 0 };
+
+    #[test]
+    fn reply_goes_to_the_source_task() {
+        let itti = Itti::new();
+        let requester = itti.register(REQUESTER);
+        let mailbox = itti.register(TASK);
+        let correlation_id = itti.send_from(REQUESTER, IttiTrxTag::Timer, control(1)).unwrap();
+        let request = mailbox.try_recv().unwrap();
+        assert_eq!((request.header.src, request.header.correlation_id), (Some(REQUESTER), correlation_id));
+        itti.reply(&request.header, TASK, control(2)).unwrap();
+        let reply = requester.try_recv().unwrap();
+        assert_eq!((reply.header.src, reply.header.dst, reply.header.correlation_id), (Some(TASK), Some(REQUESTER), correlation_id));
+        assert!(matches!(reply.msg, IttiMsg::TimerAdvance(duration) if duration.as_secs() == 2));
+    }
+
+    #[test]
+    fn reply_goes_to_the_caller() {
+        let itti = Arc::new(Itti::new());
+        let requester = itti.register(REQUESTER);
+        let mailbox = itti.register(TASK);
+        let responder = {
+            let itti = itti.clone();
+            thread::spawn(move || {
+                let request = mailbox.recv().unwrap();
+                itti.reply(&request.header, TASK, control(2)).unwrap();
+            })
+        };
+        let replies = itti.call(IttiTrxTag::Timer, control(1), Duration::from_secs(5)).unwrap();
+        responder.join().unwrap();
+        assert_eq!(replies.len(), 1);
+        assert_eq!((replies[0].header.src, replies[0].header.dst), (Some(TASK), None));
+        assert!(matches!(replies[0].msg, IttiMsg::TimerAdvance(duration) if duration.as_secs() == 2));
+        // The reply went on the channel, not to any task
+        assert!(requester.try_recv().is_err());
+    }
+
+    #[test]
+    fn call_times_out_and_request_without_source_has_no_reply() {
+        let itti = Itti::new();
+        let mailbox = itti.register(TASK);
+        match itti.call(IttiTrxTag::Timer, control(1), Duration::from_millis(20)) {
+            Err(IttiError::Timeout(correlation_id)) => assert_eq!(mailbox.try_recv().unwrap().header.correlation_id, correlation_id),
+            result => panic!("unexpected {:?}", result),
+        }
+        itti.send(IttiTrxTag::Timer, control(1)).unwrap();
+        let request = mailbox.try_recv().unwrap();
+        assert!(matches!(itti.reply(&request.header, TASK, control(2)), Err(IttiError::NoReplyTo(_))));
+    }
+
+    #[test]
+    fn receive_records_queue_latency() {
+        let itti = Itti::new();
+        let mailbox = itti.register(TASK);
+        itti.send(IttiTrxTag::Timer, control(1)).unwrap();
+        assert_eq!(stats(&itti).max_latency, Duration::ZERO);
+        thread::sleep(Duration::from_millis(20));
+        mailbox.try_recv().unwrap();
+        assert!(stats(&itti).max_latency >= Duration::from_millis(20));
+    }
+}
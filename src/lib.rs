@@ -0,0 +1,4 @@
+//! What other processes share with the ITTI process, e.g. the controller
+//! sending it interface management commands.
+
+pub mod netif_cmd;
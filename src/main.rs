@@ -1,218 +1,253 @@
-
 mod msg;
 mod pdu_session;
 mod nas_decoder;
 mod pdu_helper;
-use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
-use crossbeam::channel::{unbounded, Receiver ,Sender};
-use crossbeam::queue::SegQueue;
+mod timer;
+mod session_store;
+mod itti;
+mod signal;
+mod trace;
+mod supervisor;
+mod config;
+mod gtp_helper;
+mod gtp_udp;
+mod qos_classifier;
+mod tunnel_table;
+mod tun_device;
+mod tun_data_path;
+mod rtnetlink;
+mod netif_mgmt;
+use std::{sync::Arc, time::Duration};
 use crossbeam::scope;
-use msg::{IttiMsg, IttiTrxTag, PlainNAS5GSMessage, NasDecoerSdu};
-use nas_decoder::{nas_5gs_decoder_to_json, nas_5gs_decoder_to_text};
+use config::IttiConfig;
+use gtp_udp::GtpUdp;
+use itti::Itti;
+use msg::{IttiTaskId, IttiTrxTag};
+use nas_decoder::init_nas_decoder_task;
 use pdu_session::PduSessionMgmt;
+use session_store::SessionStore;
+use supervisor::supervise;
+use timer::{MockClock, TimerService};
+use trace::{ReplaySpeed, TraceRecorder, TraceReplay};
+use tunnel_table::TunnelTable;
+use tun_data_path::TunDataPath;
+use netif_mgmt::NetIfMgmt;
 
+struct Args {
+    config: Option<String>,
+    trace: Option<String>,
+    replay: Option<String>,
+    replay_speed: ReplaySpeed,
+    replay_to: Option<IttiTrxTag>,
+}
 
-fn main() {
-    let global_task_queue = Arc::new(SegQueue::<IttiMsg>::new());
-    let global_itti_trx_tag_list = Arc::new(RwLock::new(HashMap::<IttiTrxTag,(Sender<IttiMsg>,Receiver<IttiMsg>)>::new()));
-    let global_itti_trx_tag_list_pdu = global_itti_trx_tag_list.clone();
-    let global_itti_trx_tag_list_nas_decoder = global_itti_trx_tag_list.clone();
-    let global_task_queue_handler = global_task_queue.clone();
-    let global_itti_trx_tag_list_handler = global_itti_trx_tag_list.clone();
-    scope(|scope| {
-
-            scope.spawn(move |_|{
-                //Thread for nas decoder
-                let nas_decoder_trx: (Sender<IttiMsg>, Receiver<IttiMsg>) = unbounded::<IttiMsg>();
-                // global_itti_trx_tag_list_pdu.insert(IttiTrxTag::NasDecoer, nas_decoder_trx.clone()); // 插入
-                {
-                    loop {
-                        let bb = global_itti_trx_tag_list_pdu.try_write();
+const USAGE: &str = "usage: rust_itti [--config FILE] [--trace FILE] [--replay FILE [--replay-speed FACTOR|max] [--replay-to TAG]]";
 
-                        match bb {
-                            Ok(mut b) => {
-                                println!("a");
-                                b.insert(IttiTrxTag::NasDecoer, nas_decoder_trx.clone()); 
-                                // global_itti_trx_tag_list_pdu.
-                                break;
-                            },
-                            Err(_) => {
-                                continue;
-                            },
-                        }
-                    }
+fn parse_args() -> Result<Args, String> {
+    let mut args = Args {
+        config: None,
+        trace: None,
+        replay: None,
+        replay_speed: ReplaySpeed::Original,
+        replay_to: None,
+    };
+    let mut argv = std::env::args().skip(1);
+    while let Some(arg) = argv.next() {
+        let mut value = || argv.next().ok_or(format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--config" => args.config = Some(value()?),
+            "--trace" => args.trace = Some(value()?),
+            "--replay" => args.replay = Some(value()?),
+            "--replay-speed" => {
+                args.replay_speed = match value()?.as_str() {
+                    "max" => ReplaySpeed::AsFastAsPossible,
+                    factor => ReplaySpeed::Accelerated(factor.parse().map_err(|_| format!("bad replay speed {}", factor))?),
                 }
-                loop {
-                    match  nas_decoder_trx.1.recv() {
-                        Ok(msg) => {
-                            match msg {
-                                IttiMsg::Nas5GsDecodePduAndSend2PduMgmt(data_to_decode) => {
-                                    if let Ok(plain_nas5_gsmessage) = nas_5gs_decoder_to_json(data_to_decode.sdu) {
-                                        println!("{:#?}", plain_nas5_gsmessage);
-                                        let bb = global_itti_trx_tag_list_pdu.try_read().unwrap();
-                                        if bb.contains_key(&IttiTrxTag::PduSessionMgmt) {
-                                            match  bb.get(&IttiTrxTag::PduSessionMgmt){
-                                                Some(pdu_trx) =>{
-                                                    let _ = pdu_trx.0.send(IttiMsg::PduSessionMgmtCreatePduSession(PlainNAS5GSMessage { data: plain_nas5_gsmessage.clone() }));
-                                                },
-                                                None => {
-                
-                                                },
-                                            } {
-            
-                                            } 
-                                        }
+            },
+            "--replay-to" => {
+                let tag = value()?;
+                args.replay_to = Some(tag.parse().map_err(|_| format!("unknown task {}", tag))?);
+            },
+            _ => return Err(format!("unknown argument {}", arg)),
+        }
+    }
+    Ok(args)
+}
 
+fn main() {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(e) => {
+            println!("{}\n{}", e, USAGE);
+            std::process::exit(2);
+        },
+    };
+    let config = match &args.config {
+        Some(path) => match IttiConfig::load(path) {
+            Ok(config) => config,
+            Err(e) => {
+                println!("cannot read config {}: {}", path, e);
+                std::process::exit(2);
+            },
+        },
+        None => IttiConfig::default(),
+    };
+    config::set_log_level(config.log_level);
+    let tasks = config.tasks;
+    let replay = match &args.replay {
+        Some(path) => match TraceReplay::open(path) {
+            Ok(mut replay) => {
+                replay.speed = args.replay_speed;
+                replay.only_dst = args.replay_to;
+                Some(replay)
+            },
+            Err(e) => {
+                println!("cannot read trace {}: {}", path, e);
+                std::process::exit(2);
+            },
+        },
+        None => None,
+    };
+    let itti = Arc::new(Itti::new());
+    if let Some(path) = &args.trace {
+        match TraceRecorder::create(path) {
+            Ok(recorder) => itti.set_trace(recorder),
+            Err(e) => println!("cannot record trace {}: {}", path, e),
+        }
+    }
+    if let Err(e) = signal::handle_signals(itti.clone()) {
+        println!("signals not handled: {}", e);
+    }
+    let itti_handler = itti.clone();
+    // Outlives task restarts, so tunnels are not lost with a task
+    let tunnels = Arc::new(TunnelTable::default());
+    let replaying = replay.is_some();
+    let result = scope(|scope| {
+            let mut started = vec![];
 
-                                    }
-                                },
-                                IttiMsg::Nas5GsStopThread => {
-                                    break;
-                                },
-                                _ => {},
+            if let Some(gtp_udp) = tasks.gtp_udp.clone() {
+                let itti_gtp_udp = itti.clone();
+                let tunnels = tunnels.clone();
+                started.push(IttiTrxTag::GtpUdp.into());
+                scope.spawn(move |_|{
+                    //Thread GTP-U
+                    supervise(itti_gtp_udp, IttiTrxTag::GtpUdp.into(), gtp_udp.mailbox, gtp_udp.restart,
+                        |itti, mailbox| {
+                            let mut task = GtpUdp::new(gtp_udp.bind, tunnels.clone());
+                            if gtp_udp.echo_interval_secs > 0 {
+                                task = task.with_echo(Duration::from_secs(gtp_udp.echo_interval_secs), gtp_udp.echo_max_missed);
                             }
-                        },
-                        Err(_) => {
-                            
-                        },
-                    }
-                    
+                            task.init_gtp_udp_task(itti, mailbox)
+                        });
+                });
+            }
+
+            if let Some(tun) = tasks.tun.clone() {
+                let itti_tun = itti.clone();
+                started.push(IttiTrxTag::Tun.into());
+                scope.spawn(move |_|{
+                    //Thread TUN data path
+                    supervise(itti_tun, IttiTrxTag::Tun.into(), tun.mailbox, tun.restart,
+                        |itti, mailbox| TunDataPath::new(tun.clone()).init_tun_task(itti, mailbox));
+                });
+                // Up before the session managers, which hand it their restored sessions
+                itti.wait_for(IttiTrxTag::Tun);
+            }
+
+            if let Some(netif_mgmt) = tasks.netif_mgmt.clone() {
+                let itti_netif_mgmt = itti.clone();
+                started.push(IttiTrxTag::NetIfMgmt.into());
+                scope.spawn(move |_|{
+                    //Thread network interface management
+                    supervise(itti_netif_mgmt, IttiTrxTag::NetIfMgmt.into(), netif_mgmt.mailbox, netif_mgmt.restart,
+                        |itti, mailbox| NetIfMgmt::new(netif_mgmt.dry_run).init_netif_mgmt_task(itti, mailbox));
+                });
+            }
+
+            if let Some(nas_decoder) = &tasks.nas_decoder {
+                for instance in 0..nas_decoder.instances.max(1) {
+                    let nas_decoder = nas_decoder.clone();
+                    let itti_nas_decoder = itti.clone();
+                    let id = IttiTaskId::new(IttiTrxTag::NasDecoer, instance);
+                    started.push(id);
+                    scope.spawn(move |_|{
+                        //Thread for nas decoder
+                        supervise(itti_nas_decoder, id, nas_decoder.mailbox, nas_decoder.restart,
+                            |itti, mailbox| init_nas_decoder_task(itti, mailbox, nas_decoder.backend));
+                    });
                 }
-            });
-            
-            scope.spawn(move |_|{
-                //Thread pduSessionMgmt
-                let pdu_session_mgmt = PduSessionMgmt::default();
-                let pdu_trx = unbounded::<IttiMsg>();
-                
-                {
-                loop {
-                    let b = global_itti_trx_tag_list_nas_decoder.try_write();
-                    match b {
-                        Ok(mut b) => {
-                            b.insert(IttiTrxTag::PduSessionMgmt, pdu_trx.clone());
-                            println!("b");
-                            break;
-                        },
-                        Err(_) => {
-                            continue;
-                        },
-                    }
+            }
+
+            if let Some(pdu_session_mgmt_config) = &tasks.pdu_session_mgmt {
+                for instance in 0..pdu_session_mgmt_config.instances.max(1) {
+                    let pdu_session_mgmt_config = pdu_session_mgmt_config.clone();
+                    let itti_pdu = itti.clone();
+                    let tunnels = tunnels.clone();
+                    let id = IttiTaskId::new(IttiTrxTag::PduSessionMgmt, instance);
+                    started.push(id);
+                    scope.spawn(move |_|{
+                        //Thread pduSessionMgmt
+                        supervise(itti_pdu, id, pdu_session_mgmt_config.mailbox, pdu_session_mgmt_config.restart,
+                            |itti, mailbox| {
+                                let mut pdu_session_mgmt = PduSessionMgmt::default().with_tunnel_table(tunnels.clone());
+                                // A replay starts from a fresh manager to stay deterministic,
+                                // a restart picks up the sessions persisted before the panic
+                                match &pdu_session_mgmt_config.session_store {
+                                    Some(path) if !replaying => pdu_session_mgmt.restore(SessionStore::for_instance(path, instance)),
+                                    _ => {},
+                                }
+                                pdu_session_mgmt.init_pdu_session_mgmt_task(itti, mailbox);
+                            });
+                    });
                 }
             }
-                pdu_session_mgmt.init_pdu_session_mgmt_task(pdu_trx.clone());
-            });
+
+            if let Some(timer) = tasks.timer.clone() {
+                let itti_timer = itti.clone();
+                started.push(IttiTrxTag::Timer.into());
+                scope.spawn(move |_|{
+                    //Thread timer
+                    supervise(itti_timer, IttiTrxTag::Timer.into(), timer.mailbox, timer.restart,
+                        |itti, mailbox| {
+                            let service = match timer.virtual_time {
+                                true => TimerService::virtual_time(Arc::new(MockClock::new())),
+                                false => TimerService::default(),
+                            };
+                            service.init_timer_task(itti, mailbox)
+                        });
+                });
+            }
+
+            if let Some(listener) = &tasks.listener {
+                println!("Listener task on {} is not available yet, not started", listener.bind);
+            }
 
             scope.spawn(move |_|{
                 //Thread Itti
-                match  global_task_queue_handler.pop() {
-                    Some(msg) => {
-                        match msg{
-                            IttiMsg::PduSessionMgmtCreatePduSession(plain_nas5_gsmessage) |
-                            IttiMsg::PduSessionMgmtModifiyPduSession(plain_nas5_gsmessage)|
-                            IttiMsg::PduSessionMgmtDestoryPduSession(plain_nas5_gsmessage)
-                                  => {
-                                        loop{
-                                            let global_itti_trx_tag_list_handler = global_itti_trx_tag_list_handler.try_read();
-                                            match global_itti_trx_tag_list_handler {
-                                                Ok(g) => {
-                                                    if g.contains_key(&IttiTrxTag::PduSessionMgmt) {
-                                                        let pdu_trx =  g.get(&IttiTrxTag::PduSessionMgmt);
-                                                        match pdu_trx {
-                                                            Some(pdu_trx) => {
-                                                                let _ = pdu_trx.0.send(IttiMsg::PduSessionMgmtCreatePduSession(plain_nas5_gsmessage.clone()));
-                                                                println!("PduSessionMgmt");
-                                                            },
-                                                            None => {
-                                                            },
-                                                        }
-                                                    }
-                                                    break;
-                                                },
-                                                Err(_) => {
-                                                    continue;
-                                                },
-                                            }
-                                            
-                                        }
-                                        
-                                    },
-                            IttiMsg::Nas5GsDecodePduAndSend2PduMgmt(nas_decoer_sdu) =>{
-                                        loop{
-                                            let global_itti_trx_tag_list_handler = global_itti_trx_tag_list_handler.try_read();
-                                            match global_itti_trx_tag_list_handler {
-                                                Ok(g) => {
-                                                    if g.contains_key(&IttiTrxTag::NasDecoer) {
-                                                        let pdu_trx =  g.get(&IttiTrxTag::NasDecoer);
-                                                        match pdu_trx {
-                                                            Some(pdu_trx) => {
-                                                                let _ = pdu_trx.0.send(IttiMsg::Nas5GsDecodePduAndSend2PduMgmt(nas_decoer_sdu.clone()));
-                                                                println!("nasDecoerSdu");
-                                                            },
-                                                            None => {
-                                                            },
-                                                        }
-                                                    }
-                                                    break;
-                                                },
-                                                Err(_) => {
-                                                    continue;
-                                                },
-                                            }
-                                            
-                                        }
-                            }
-                            _ => {println!("{:#?}", msg);},
-                        }
-                    },
-                    None => {
-                        
-                    },
-                }
-            });
-            
-            
-            // global_task_queue.push(1);
-            // global_task_queue.push(3);
-            // global_task_queue.push(2);
-            let nas_test_msg = IttiMsg::Nas5GsDecodePduAndSend2PduMgmt(NasDecoerSdu { sdu: vec![0x7e,0x00,0x68,0x01,0x00,0x65,0x2e,0x01,0x01,0xc2,0x11,0x00,0x09,0x01,0x00,0x06,0x31,0x3f,0x01,0x01,0xff,0x01,0x06,0x06,0x13,0x88,0x04,0x7a,0x12,0x59,0x32,0x29,0x05,0x01,0xac,0x1a,0x64,0x65,0x22,0x01,0x01,0x79,0x00,0x06,0x01,0x20,0x41,0x01,0x01,0x09,0x7b,0x00,0x18,0x80,0x80,0x21,0x0a,0x03,0x00,0x00,0x0a,0x81,0x06,0x08,0x08,0x08,0x08,0x00,0x0d,0x04,0x08,0x08,0x08,0x08,0x00,0x11,0x00,0x25,0x1c,0x09,0x69,0x69,0x6e,0x74,0x65,0x72,0x6e,0x65,0x74,0x06,0x6d,0x6e,0x63,0x30,0x30,0x31,0x06,0x6d,0x63,0x63,0x30,0x30,0x31,0x04,0x67,0x70,0x72,0x73,0x12,0x01] });
-            // global_task_queue.push(nas_test_msg);
-            let b = nas_5gs_decoder_to_text(vec![0x7e,0x00,0x68,0x01,0x00,0x65,0x2e,0x01,0x01,0xc2,0x11,0x00,0x09,0x01,0x00,0x06,0x31,0x3f,0x01,0x01,0xff,0x01,0x06,0x06,0x13,0x88,0x04,0x7a,0x12,0x59,0x32,0x29,0x05,0x01,0xac,0x1a,0x64,0x65,0x22,0x01,0x01,0x79,0x00,0x06,0x01,0x20,0x41,0x01,0x01,0x09,0x7b,0x00,0x18,0x80,0x80,0x21,0x0a,0x03,0x00,0x00,0x0a,0x81,0x06,0x08,0x08,0x08,0x08,0x00,0x0d,0x04,0x08,0x08,0x08,0x08,0x00,0x11,0x00,0x25,0x1c,0x09,0x69,0x69,0x6e,0x74,0x65,0x72,0x6e,0x65,0x74,0x06,0x6d,0x6e,0x63,0x30,0x30,0x31,0x06,0x6d,0x63,0x63,0x30,0x30,0x31,0x04,0x67,0x70,0x72,0x73,0x12,0x01]);
-            println!("{}", b.unwrap());
-            scope.spawn(move |_|{
-                // while true {
-
-                    // match global_task_queue.pop() {
-                    //     Some(int) => {
-                    //         if int %2 != 0 {
-                    //             // println!("odd {:#?}",int);
-                    //         }
-                    //         else {
-                    //             global_task_queue.push(int);
-                    //         }
-                    //     },
-                    //     None => todo!(),
-                    // }
-                // }
+                itti_handler.run_dispatcher();
             });
 
-            loop {
-                // if global_task_queue.pop().is_none() {
-                //     thread::yield_now();
-                // } else {
-                //     break;
-                // }
+            for id in started {
+                itti.wait_for(id);
             }
-    })
-    .unwrap();
- 
-
-    
-    // At most one of these two receive operations will be executed.
-    // select! {
-    //     recv(r1) -> msg => assert_eq!(msg, Ok(10)),
-    //     recv(r2) -> msg => assert_eq!(msg, Ok(20)),
-    //     default(Duration::from_secs(1)) => println!("timed out"),
-    // }
-}
\ No newline at end of file
+            if let Some(replay) = &replay {
+                let sent = replay.run(&itti);
+                println!("replayed {} of {} trace records", sent, replay.records.len());
+                itti.shutdown();
+            }
+            // Runs until a signal shuts the tasks down. A task that did not
+            // stop would never let the scope join, so exit without it
+            if !itti.wait_stopped() {
+                println!("a task did not stop in time");
+                std::process::exit(1);
+            }
+    });
+    // Every task has returned once the scope is joined
+    match result {
+        Ok(_) => std::process::exit(0),
+        Err(_) => {
+            println!("a task panicked");
+            std::process::exit(1);
+        },
+    }
+}
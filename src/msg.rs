@@ -1,20 +1,94 @@
 
+use std::{fmt, net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr}, str::FromStr, time::{Duration, Instant}};
+
+use bytes::Bytes;
+use crossbeam::channel::Sender;
+use serde::{Deserialize, Serialize};
 use serde_json::{self, Value};
-#[derive(PartialEq, Eq, Hash)]
+
+use crate::{pdu_helper::pdu_helper::n1_sm_payload, session_store::{PduSessionContext, QosFlowCtx}};
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum IttiTrxTag {
     PduSessionMgmt,
     NasDecoer,
     Listener,
-    GtpUdp
+    GtpUdp,
+    Timer,
+    Tun,
+    NetIfMgmt
 }
 
-#[derive(Debug,Clone)]
+impl FromStr for IttiTrxTag {
+    type Err = serde_json::Error;
+
+    /// Parses the variant name, e.g. `PduSessionMgmt`.
+    fn from_str(s: &str) -> Result<IttiTrxTag, serde_json::Error> {
+        serde_json::from_value(Value::String(s.to_string()))
+    }
+}
+
+/// One running instance of a task. Tasks that run a single instance use
+/// instance 0, which is what a bare tag converts to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct IttiTaskId {
+    pub tag: IttiTrxTag,
+    pub instance: u16
+}
+
+impl IttiTaskId {
+    pub fn new(tag: IttiTrxTag, instance: u16) -> IttiTaskId {
+        IttiTaskId { tag, instance }
+    }
+}
+
+impl From<IttiTrxTag> for IttiTaskId {
+    fn from(tag: IttiTrxTag) -> IttiTaskId {
+        IttiTaskId::new(tag, 0)
+    }
+}
+
+impl fmt::Display for IttiTaskId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}#{}", self.tag, self.instance)
+    }
+}
+
+/// What a message is sharded by across the instances of its task. Messages
+/// with the same key always reach the same instance, in order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShardKey {
+    Ue(u64),
+    PduSession(u8),
+}
+
+impl ShardKey {
+    /// Instance out of `instances` that owns the key. Plain modulo, so the
+    /// mapping is the same on every run and a replayed trace shards alike.
+    pub fn instance(&self, instances: u16) -> u16 {
+        let key = match *self {
+            ShardKey::Ue(ue_id) => ue_id,
+            ShardKey::PduSession(pdu_session_id) => pdu_session_id as u64,
+        };
+        (key % instances.max(1) as u64) as u16
+    }
+}
+
+#[derive(Debug,Clone,Serialize,Deserialize)]
 pub enum IttiMsg  {
 
     //PduSessionMgmt Msg
     PduSessionMgmtCreatePduSession(PlainNAS5GSMessage),
     PduSessionMgmtModifiyPduSession(PlainNAS5GSMessage),
     PduSessionMgmtDestoryPduSession(PlainNAS5GSMessage),
+    PduSessionMgmtGsmProcedureStart(GsmProcedureReq),
+    PduSessionMgmtGsmTransmit(GsmProcedurePdu),
+    PduSessionMgmtGsmProcedureTimeout(GsmProcedureTimeout),
+    PduSessionMgmtQuery(PduSessionQuery),
+    PduSessionMgmtQueryResponse(PduSessionQueryResponse),
+    PduSessionMgmtTunnelSetup(GtpTunnelCfg),
+    PduSessionMgmtSendUplink(UdpGtpBuffer),
+    PduSessionMgmtGtpPathEvent(GtpPathEvent),
+    PduSessionMgmtGtpErrorIndication(GtpErrorIndication),
     PduSessionMgmtStopThread,
 
     //NAS-5GS decoder Msg
@@ -32,20 +106,354 @@ pub enum IttiMsg  {
     GtpUdpRecvFromRemoteThenToPduSessoin(UdpGtpBuffer),
     GtpUdpStopThread,
 
+    //TUN data path Msg
+    TunSessionUp(TunSessionCfg),
+    TunSessionDown(u8),
+    TunSendDownlink(UdpGtpBuffer),
+    TunStopThread,
+
+    //Network interface management Msg
+    NetIfMgmtCommand(NetIfMgmtReq),
+    NetIfMgmtResult(NetIfMgmtResult),
+    NetIfMgmtStopThread,
+
+    //Timer Msg
+    TimerStart(TimerStartReq),
+    TimerCancel(TimerCancelReq),
+    TimerExpired(TimerExpiry),
+    TimerAdvance(Duration),
+    TimerStopThread,
+
+}
+
+/// Addressing of a message. `src` is `None` for producers outside the task
+/// set, such as `main` or a CLI, and `dst` is `None` for replies sent on a
+/// `reply_to` channel.
+#[derive(Debug,Clone)]
+pub struct IttiHeader {
+    pub src:Option<IttiTaskId>,
+    pub dst:Option<IttiTaskId>,
+    pub correlation_id:u64,
+    pub reply_to:Option<Sender<IttiEnvelope>>,
+    pub enqueued_at:Instant
 }
+
 #[derive(Debug,Clone)]
+pub struct IttiEnvelope {
+    pub header:IttiHeader,
+    pub msg:IttiMsg
+}
+
+impl IttiEnvelope {
+    /// Time spent between sending and now.
+    pub fn latency(&self) -> Duration {
+        self.header.enqueued_at.elapsed()
+    }
+}
+
+/// Mailbox lane of a message. A task always drains its control lane first,
+/// so signalling never waits behind queued user-plane buffers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IttiLane {
+    Control,
+    Data
+}
+
+impl IttiMsg {
+    /// Messages without a key go to instance 0 of their task, unless they
+    /// go to every instance.
+    pub fn shard_key(&self) -> Option<ShardKey> {
+        match self {
+            IttiMsg::Nas5GsDecodePduAndSend2PduMgmt(nas_decoer_sdu) => match nas_decoer_sdu.ue_id {
+                Some(ue_id) => Some(ShardKey::Ue(ue_id)),
+                None => pdu_session_id(&nas_decoer_sdu.sdu).map(ShardKey::PduSession),
+            },
+            IttiMsg::PduSessionMgmtCreatePduSession(plain_nas5_gsmessage)
+            | IttiMsg::PduSessionMgmtModifiyPduSession(plain_nas5_gsmessage)
+            | IttiMsg::PduSessionMgmtDestoryPduSession(plain_nas5_gsmessage) => {
+                pdu_session_id(&plain_nas5_gsmessage.sdu).map(ShardKey::PduSession)
+            },
+            IttiMsg::PduSessionMgmtGsmProcedureStart(req) => Some(ShardKey::PduSession(req.pdu_session_id)),
+            IttiMsg::PduSessionMgmtTunnelSetup(cfg) => Some(ShardKey::PduSession(cfg.pdu_session_id)),
+            IttiMsg::PduSessionMgmtGtpErrorIndication(indication) => Some(ShardKey::PduSession(indication.pdu_session_id)),
+            IttiMsg::GtpUdpRecvFromRemoteThenToPduSessoin(udp_gtp_buffer)
+            | IttiMsg::PduSessionMgmtSendUplink(udp_gtp_buffer) => Some(ShardKey::PduSession(udp_gtp_buffer.pdu_session_id)),
+            IttiMsg::PduSessionMgmtQuery(query) => match query.kind {
+                PduSessionQueryKind::ById(pdu_session_id)
+                | PduSessionQueryKind::QosFlows(pdu_session_id) => Some(ShardKey::PduSession(pdu_session_id)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Queries over every session, which each session manager answers for
+    /// the sessions of its own shard.
+    pub fn to_all_instances(&self) -> bool {
+        matches!(self, IttiMsg::PduSessionMgmtQuery(PduSessionQuery {
+            kind: PduSessionQueryKind::ListAll | PduSessionQueryKind::ByUeIp(_),
+        }))
+    }
+
+    pub fn is_stop_thread(&self) -> bool {
+        matches!(self,
+            IttiMsg::PduSessionMgmtStopThread
+            | IttiMsg::Nas5GsStopThread
+            | IttiMsg::ListenerStopThread
+            | IttiMsg::GtpUdpStopThread
+            | IttiMsg::TunStopThread
+            | IttiMsg::NetIfMgmtStopThread
+            | IttiMsg::TimerStopThread)
+    }
+
+    pub fn lane(&self) -> IttiLane {
+        match self {
+            IttiMsg::GtpUdpSendToRemote(_)
+            | IttiMsg::GtpUdpRecvFromRemoteThenToPduSessoin(_)
+            | IttiMsg::PduSessionMgmtSendUplink(_)
+            | IttiMsg::TunSendDownlink(_) => IttiLane::Data,
+            _ => IttiLane::Control,
+        }
+    }
+
+    /// Routing table of the dispatcher: the task that owns each message.
+    /// Replies are addressed from the header of their request instead.
+    pub fn destination(&self) -> Option<IttiTrxTag> {
+        match self {
+            IttiMsg::PduSessionMgmtCreatePduSession(_)
+            | IttiMsg::PduSessionMgmtModifiyPduSession(_)
+            | IttiMsg::PduSessionMgmtDestoryPduSession(_)
+            | IttiMsg::PduSessionMgmtGsmProcedureStart(_)
+            | IttiMsg::PduSessionMgmtQuery(_)
+            | IttiMsg::PduSessionMgmtTunnelSetup(_)
+            | IttiMsg::PduSessionMgmtSendUplink(_)
+            | IttiMsg::PduSessionMgmtGtpPathEvent(_)
+            | IttiMsg::PduSessionMgmtGtpErrorIndication(_)
+            | IttiMsg::PduSessionMgmtStopThread
+            | IttiMsg::GtpUdpRecvFromRemoteThenToPduSessoin(_) => Some(IttiTrxTag::PduSessionMgmt),
+
+            IttiMsg::Nas5GsDecodePduAndSend2PduMgmt(_)
+            | IttiMsg::Nas5GsStopThread => Some(IttiTrxTag::NasDecoer),
+
+            IttiMsg::ListenerInitAndRun
+            | IttiMsg::ListenerDestory
+            | IttiMsg::ListenerStopThread => Some(IttiTrxTag::Listener),
+
+            IttiMsg::GtpUdpCfgSetup
+            | IttiMsg::GtpUdpSendToRemote(_)
+            | IttiMsg::GtpUdpStopThread => Some(IttiTrxTag::GtpUdp),
+
+            IttiMsg::TunSessionUp(_)
+            | IttiMsg::TunSessionDown(_)
+            | IttiMsg::TunSendDownlink(_)
+            | IttiMsg::TunStopThread => Some(IttiTrxTag::Tun),
+
+            IttiMsg::NetIfMgmtCommand(_)
+            | IttiMsg::NetIfMgmtStopThread => Some(IttiTrxTag::NetIfMgmt),
+
+            IttiMsg::TimerStart(_)
+            | IttiMsg::TimerCancel(_)
+            | IttiMsg::TimerAdvance(_)
+            | IttiMsg::TimerStopThread => Some(IttiTrxTag::Timer),
+
+            IttiMsg::PduSessionMgmtGsmTransmit(_)
+            | IttiMsg::NetIfMgmtResult(_)
+            | IttiMsg::PduSessionMgmtGsmProcedureTimeout(_)
+            | IttiMsg::PduSessionMgmtQueryResponse(_)
+            | IttiMsg::TimerExpired(_) => None,
+        }
+    }
+}
+#[derive(Debug,Clone,Serialize,Deserialize)]
 
 pub struct NasDecoerSdu{
-    pub sdu:Vec<u8>
+    pub sdu:Vec<u8>,
+    /// Keeps the messages of one UE on one decoder instance when known
+    #[serde(default)]
+    pub ue_id:Option<u64>
 }
-#[derive(Debug,Clone)]
+
+/// PDU session identity of a 5GSM message, plain or in a NAS transport.
+fn pdu_session_id(sdu: &[u8]) -> Option<u8> {
+    n1_sm_payload(sdu)?.get(1).copied()
+}
+#[derive(Debug,Clone,Serialize,Deserialize)]
 
 pub struct PlainNAS5GSMessage {
-    pub data:Value
+    pub data:Value,
+    pub sdu:Vec<u8>
 }
 
-#[derive(Debug,Clone)]
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Serialize,Deserialize)]
+pub enum GsmProcedure {
+    Establishment,
+    Modification,
+    Release
+}
+
+#[derive(Debug,Clone,Serialize,Deserialize)]
+
+pub struct GsmProcedureReq {
+    pub procedure:GsmProcedure,
+    pub pdu_session_id:u8,
+    pub pti:u8,
+    pub nas_pdu:Vec<u8>
+}
+#[derive(Debug,Clone,Serialize,Deserialize)]
+
+pub struct GsmProcedurePdu {
+    pub procedure:GsmProcedure,
+    pub pdu_session_id:u8,
+    pub pti:u8,
+    pub nas_pdu:Vec<u8>,
+    pub attempt:u8
+}
+#[derive(Debug,Clone,Serialize,Deserialize)]
+
+pub struct GsmProcedureTimeout {
+    pub procedure:GsmProcedure,
+    pub pdu_session_id:u8,
+    pub pti:u8
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Serialize,Deserialize)]
+pub enum GtpDirection {
+    Uplink,
+    Downlink
+}
+
+/// G-PDU payload of a PDU session. The payload is copied once out of the
+/// receive buffer, and passing it between tasks after that never copies it.
+/// `qfi` and `rqi` are those of the PDU Session Container on downlink; on
+/// uplink the session sets the QFI from its QoS rules.
+#[derive(Debug,Clone,Serialize,Deserialize)]
 
 pub struct UdpGtpBuffer {
-    pub data:Value
+    pub teid:u32,
+    pub pdu_session_id:u8,
+    pub qfi:Option<u8>,
+    #[serde(default)]
+    pub rqi:bool,
+    pub peer:Option<SocketAddr>,
+    pub direction:GtpDirection,
+    pub payload:Bytes
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Serialize,Deserialize)]
+pub enum GtpPathEventKind {
+    Down,
+    Up,
+    Restarted
+}
+
+/// Liveness change of a GTP-U peer, sent to every session manager.
+#[derive(Debug,Clone,Serialize,Deserialize)]
+pub struct GtpPathEvent {
+    pub peer:IpAddr,
+    pub kind:GtpPathEventKind
+}
+
+/// The peer of a session's tunnel has no context for the TEID we send to.
+#[derive(Debug,Clone,Serialize,Deserialize)]
+pub struct GtpErrorIndication {
+    pub pdu_session_id:u8,
+    pub remote_teid:u32,
+    pub peer:IpAddr
+}
+
+/// Addresses, DNS servers and MTU the data path of a PDU session is set up with.
+#[derive(Debug,Clone,Serialize,Deserialize)]
+pub struct TunSessionCfg {
+    pub pdu_session_id:u8,
+    pub ipv4:Option<Ipv4Addr>,
+    /// Interface identifier only, the prefix comes from router advertisements
+    pub ipv6:Option<Ipv6Addr>,
+    pub dns:Vec<IpAddr>,
+    pub mtu:Option<u16>
+}
+
+/// Interface management commands of the controller, as encoded by
+/// `netif_cmd::encode_commands`.
+#[derive(Debug,Clone,Serialize,Deserialize)]
+pub struct NetIfMgmtReq {
+    pub data:Vec<u8>
+}
+
+/// Operations a command was carried out with, as `ip` commands, and the
+/// error that stopped it if any.
+#[derive(Debug,Clone,Serialize,Deserialize)]
+pub struct NetIfMgmtResult {
+    pub ops:Vec<String>,
+    pub error:Option<String>
+}
+
+/// Remote F-TEID of the N3 tunnel of a PDU session; the local TEID is the
+/// one the session manager allocated at establishment.
+#[derive(Debug,Clone,Serialize,Deserialize)]
+pub struct GtpTunnelCfg {
+    pub pdu_session_id:u8,
+    pub remote_teid:u32,
+    pub remote_addr:IpAddr
+}
+#[derive(Debug,Clone,Serialize,Deserialize)]
+pub enum PduSessionQueryKind {
+    ListAll,
+    ById(u8),
+    ByUeIp(IpAddr),
+    QosFlows(u8)
+}
+#[derive(Debug,Clone,Serialize,Deserialize)]
+
+pub struct PduSessionQuery {
+    pub kind:PduSessionQueryKind
+}
+
+#[derive(Debug,Clone,Serialize,Deserialize)]
+pub enum PduSessionQueryResponse {
+    Sessions(Vec<PduSessionContext>),
+    Session(Option<PduSessionContext>),
+    QosFlows(Option<Vec<QosFlowCtx>>)
+}
+
+impl PduSessionQueryResponse {
+    /// Combines the answers of two session manager instances to one query.
+    pub fn merge(self, other: PduSessionQueryResponse) -> PduSessionQueryResponse {
+        match (self, other) {
+            (PduSessionQueryResponse::Sessions(mut sessions), PduSessionQueryResponse::Sessions(others)) => {
+                sessions.extend(others);
+                sessions.sort_by_key(|ctx| ctx.pdu_session_id);
+                PduSessionQueryResponse::Sessions(sessions)
+            },
+            (PduSessionQueryResponse::Session(session), PduSessionQueryResponse::Session(other)) => {
+                PduSessionQueryResponse::Session(session.or(other))
+            },
+            (PduSessionQueryResponse::QosFlows(flows), PduSessionQueryResponse::QosFlows(others)) => {
+                PduSessionQueryResponse::QosFlows(flows.or(others))
+            },
+            (response, _) => response,
+        }
+    }
+}
+
+/// Arms timer `id` of task `target_tag`, replacing a running timer with the
+/// same `id` and target.
+#[derive(Debug,Clone,Serialize,Deserialize)]
+pub struct TimerStartReq {
+    pub id:u64,
+    pub duration:Duration,
+    pub periodic:bool,
+    pub target_tag:IttiTaskId
+}
+#[derive(Debug,Clone,Serialize,Deserialize)]
+
+pub struct TimerCancelReq {
+    pub id:u64,
+    pub target_tag:IttiTaskId
+}
+#[derive(Debug,Clone,Serialize,Deserialize)]
+
+pub struct TimerExpiry {
+    pub id:u64,
+    /// How many times the timer has fired, 1 for a one-shot timer
+    pub expirations:u64
 }
@@ -1,6 +1,62 @@
-use std::{fs::File, time::{SystemTime, UNIX_EPOCH}, io::{self, BufRead}};
+use std::{sync::Arc, time::{SystemTime, UNIX_EPOCH}, io::{self, BufRead}};
 use pcap_file::{pcap, TsResolution, Endianness};
-use serde_json;
+use serde_json::{self, Value};
+
+use crate::{config::{self, NasDecoderBackend}, itti::{Itti, Mailbox}, msg::{IttiMsg, IttiTrxTag, PlainNAS5GSMessage}, pdu_helper::pdu_helper::{PduSessionPlainMsg, SessionMessageType}};
+
+/// Session manager message for a decoded 5GSM message, chosen from its type.
+fn pdu_session_mgmt_msg(messagetype: &SessionMessageType, plain_nas5_gsmessage: PlainNAS5GSMessage) -> Option<IttiMsg> {
+    match messagetype {
+        SessionMessageType::EstablishmentAccept
+        | SessionMessageType::EstablishmentReject => Some(IttiMsg::PduSessionMgmtCreatePduSession(plain_nas5_gsmessage)),
+        SessionMessageType::ModificationCommand
+        | SessionMessageType::ModificationReject => Some(IttiMsg::PduSessionMgmtModifiyPduSession(plain_nas5_gsmessage)),
+        SessionMessageType::ReleaseCommand
+        | SessionMessageType::ReleaseReject => Some(IttiMsg::PduSessionMgmtDestoryPduSession(plain_nas5_gsmessage)),
+        _ => None,
+    }
+}
+
+pub fn init_nas_decoder_task(itti: Arc<Itti>, mailbox: Mailbox, backend: NasDecoderBackend) {
+    while let Ok(envelope) = mailbox.recv() {
+        match envelope.msg {
+            IttiMsg::Nas5GsDecodePduAndSend2PduMgmt(data_to_decode) => {
+                let header = match PduSessionPlainMsg::decode(&data_to_decode.sdu) {
+                    Some(header) => header,
+                    None => {
+                        println!("nas decoder: no 5GSM message in {:02x?}", data_to_decode.sdu);
+                        continue;
+                    },
+                };
+                let decoded = match backend {
+                    NasDecoderBackend::Tshark => nas_5gs_decoder_to_json(data_to_decode.sdu.clone()),
+                    NasDecoderBackend::Native => Ok(Value::Null),
+                };
+                if let Ok(plain_nas5_gsmessage) = decoded {
+                    if config::debug_enabled() {
+                        println!("{:#?}", plain_nas5_gsmessage);
+                    }
+                    let plain_nas5_gsmessage = PlainNAS5GSMessage { data: plain_nas5_gsmessage, sdu: data_to_decode.sdu };
+                    match pdu_session_mgmt_msg(&header.messagetype, plain_nas5_gsmessage) {
+                        Some(msg) => {
+                            if let Err(e) = itti.send_from(mailbox.id, IttiTrxTag::PduSessionMgmt, msg) {
+                                println!("nas decoder: {}", e);
+                            }
+                        },
+                        None => {
+                            println!("nas decoder: {:?} is not handled by the session manager", header.messagetype);
+                        },
+                    }
+                }
+            },
+            IttiMsg::Nas5GsStopThread => {
+                break;
+            },
+            _ => {},
+        }
+    }
+}
+
 pub fn nas_5gs_decoder_to_json(nas_hex: Vec<u8>) -> Result<serde_json::Value, serde_json::Error> {
     let prefix: Vec<u8>  = vec![0x00,0x0c,0x00,0x07,0x6e,0x61,0x73,0x2d,0x35,0x67,0x73,0x00,0x00,0x00,0x00];
     let mut result = prefix.clone();
@@ -15,44 +71,15 @@ pub fn nas_5gs_decoder_to_json(nas_hex: Vec<u8>) -> Result<serde_json::Value, se
         ts_resolution: TsResolution::MicroSecond,
         endianness: Endianness::native(),
     };
-    let file = File::create("a.pcap").unwrap();
-    let mut writer = pcap_file::pcap::PcapWriter::with_header(file,header ).unwrap();
-    let now = SystemTime::now()
-    .duration_since(UNIX_EPOCH)
-    .expect("Time went backwards");
-    writer
-                        .write_packet(
-                            &pcap::PcapPacket { timestamp: now, orig_len: result.len() as u32, data: std::borrow::Cow::Borrowed(&result) }
-                        )
-                        .unwrap();
+    // The capture goes through a pipe rather than a file, so that several
+    // decoder instances can run tshark at the same time
     let mut tshark_process = std::process::Command::new("tshark")
-        .args(["-V", "-T", "json", "-r", "a.pcap"])
+        .args(["-V", "-T", "json", "-r", "-"])
+        .stdin(std::process::Stdio::piped())
         .stdout(std::process::Stdio::piped())
         .spawn().unwrap();
-    let stdout = tshark_process.stdout.as_mut().unwrap();
-    let output = io::BufReader::new(stdout).lines();
-    let output_str = output.collect::<Result<Vec<_>, _>>().unwrap()
-    .join("\n");
-    serde_json::from_str(&output_str)
-    // Ok(res)
-}
-
-pub fn nas_5gs_decoder_to_text(nas_hex: Vec<u8>) -> Result<String, ()> {
-    let prefix: Vec<u8>  = vec![0x00,0x0c,0x00,0x07,0x6e,0x61,0x73,0x2d,0x35,0x67,0x73,0x00,0x00,0x00,0x00];
-    let mut result = prefix.clone();
-    result.extend(nas_hex);
-    let header = pcap_file::pcap::PcapHeader {
-        version_major: 2,
-        version_minor: 4,
-        ts_correction: 0,
-        ts_accuracy: 0,
-        snaplen: 65535,
-        datalink: pcap_file::DataLink::WIRESHARK_UPPER_PDU,
-        ts_resolution: TsResolution::MicroSecond,
-        endianness: Endianness::native(),
-    };
-    let file = File::create("a.pcap").unwrap();
-    let mut writer = pcap_file::pcap::PcapWriter::with_header(file,header ).unwrap();
+    let stdin = tshark_process.stdin.take().unwrap();
+    let mut writer = pcap_file::pcap::PcapWriter::with_header(stdin,header ).unwrap();
     let now = SystemTime::now()
     .duration_since(UNIX_EPOCH)
     .expect("Time went backwards");
@@ -61,15 +88,13 @@ pub fn nas_5gs_decoder_to_text(nas_hex: Vec<u8>) -> Result<String, ()> {
                             &pcap::PcapPacket { timestamp: now, orig_len: result.len() as u32, data: std::borrow::Cow::Borrowed(&result) }
                         )
                         .unwrap();
-    let mut tshark_process = std::process::Command::new("tshark")
-        .args(["-V", "-r", "a.pcap"])
-        .stdout(std::process::Stdio::piped())
-        .spawn().unwrap();
+    // Closes the pipe so tshark sees the end of the capture
+    drop(writer);
     let stdout = tshark_process.stdout.as_mut().unwrap();
     let output = io::BufReader::new(stdout).lines();
     let output_str = output.collect::<Result<Vec<_>, _>>().unwrap()
     .join("\n");
-    // serde_json::from_str(&output_str)
-    Ok(output_str.to_string())
+    let _ = tshark_process.wait();
+    serde_json::from_str(&output_str)
     // Ok(res)
-}
\ No newline at end of file
+}
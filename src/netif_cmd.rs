@@ -0,0 +1,414 @@
+//! Interface management commands of the controller, as carried in
+//! `NetIfMgmtCommand`. Only depends on std, so the controller builds the
+//! same types from the `rust_itti` library.
+//!
+//! A message is a sequence of TLVs of one octet tag, one octet length and
+//! the value. It starts with a VERSION TLV, a message without one being
+//! version 1, followed by one or more commands carried out in order:
+//! CREATE, UPDATE and DELETE hold fields of the same TLV layout, DESTROY
+//! holds nothing.
+
+use std::{fmt, net::{IpAddr, Ipv4Addr, Ipv6Addr}};
+
+/// Newest version understood, and the one every message is encoded with.
+pub const NETIF_CMD_VERSION: u8 = 1;
+
+pub const NETIF_CMD_CREATE: u8 = 0x00;
+pub const NETIF_CMD_UPDATE: u8 = 0x01;
+pub const NETIF_CMD_DELETE: u8 = 0x11;
+pub const NETIF_CMD_TAG_VERSION: u8 = 0xfe;
+pub const NETIF_CMD_DESTROY: u8 = 0xff;
+
+pub const NETIF_FIELD_NAME: u8 = 0x01;
+/// Address followed by the prefix length, IPv4 or IPv6 by its length
+pub const NETIF_FIELD_ADDRESS: u8 = 0x02;
+/// Same layout as an address
+pub const NETIF_FIELD_ROUTE: u8 = 0x03;
+pub const NETIF_FIELD_MTU: u8 = 0x04;
+pub const NETIF_FIELD_DNS: u8 = 0x05;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NetIfCmdError {
+    /// A TLV runs past the end of the data, at that offset
+    Truncated(usize),
+    UnsupportedVersion(u8),
+    /// VERSION after the first TLV
+    MisplacedVersion,
+    UnknownCommand(u8),
+    UnknownField { command: u8, field: u8 },
+    BadField { command: u8, field: u8 },
+    MissingName(u8),
+    /// Value of VERSION or DESTROY of the wrong length
+    BadLength(u8),
+    /// Value of that tag over the 255 octets a TLV holds
+    TooLong(u8),
+    NoCommand,
+}
+
+impl fmt::Display for NetIfCmdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NetIfCmdError::Truncated(offset) => write!(f, "TLV at offset {} is truncated", offset),
+            NetIfCmdError::UnsupportedVersion(version) => write!(f, "version {} not supported, {} is the newest", version, NETIF_CMD_VERSION),
+            NetIfCmdError::MisplacedVersion => write!(f, "version is not the first TLV"),
+            NetIfCmdError::UnknownCommand(tag) => write!(f, "unknown command {:#04x}", tag),
+            NetIfCmdError::UnknownField { command, field } => write!(f, "unknown field {:#04x} in command {:#04x}", field, command),
+            NetIfCmdError::BadField { command, field } => write!(f, "malformed field {:#04x} in command {:#04x}", field, command),
+            NetIfCmdError::MissingName(command) => write!(f, "command {:#04x} names no interface", command),
+            NetIfCmdError::BadLength(tag) => write!(f, "value of {:#04x} has the wrong length", tag),
+            NetIfCmdError::TooLong(tag) => write!(f, "value of {:#04x} does not fit a TLV", tag),
+            NetIfCmdError::NoCommand => write!(f, "no command"),
+        }
+    }
+}
+
+impl std::error::Error for NetIfCmdError {}
+
+/// Interface a command applies to and what to set on it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NetIfSettings {
+    pub name: String,
+    pub addresses: Vec<(IpAddr, u8)>,
+    pub routes: Vec<(IpAddr, u8)>,
+    pub mtu: Option<u16>,
+    pub dns: Vec<IpAddr>,
+}
+
+impl NetIfSettings {
+    pub fn new(name: &str) -> NetIfSettings {
+        NetIfSettings { name: name.to_string(), ..NetIfSettings::default() }
+    }
+
+    pub fn with_address(mut self, addr: IpAddr, prefix_len: u8) -> NetIfSettings {
+        self.addresses.push((addr, prefix_len));
+        self
+    }
+
+    pub fn with_route(mut self, dst: IpAddr, prefix_len: u8) -> NetIfSettings {
+        self.routes.push((dst, prefix_len));
+        self
+    }
+
+    pub fn with_mtu(mut self, mtu: u16) -> NetIfSettings {
+        self.mtu = Some(mtu);
+        self
+    }
+
+    pub fn with_dns(mut self, server: IpAddr) -> NetIfSettings {
+        self.dns.push(server);
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NetworkIfCommand {
+    /// Sets up the interface, adding to what it has
+    Create(NetIfSettings),
+    /// Same as CREATE, its addresses replacing those set by earlier
+    /// commands if it has any, and its routes likewise
+    Update(NetIfSettings),
+    Delete { name: String },
+    /// Removes every address and route set by earlier commands
+    Destroy,
+}
+
+impl NetworkIfCommand {
+    pub fn tag(&self) -> u8 {
+        match self {
+            NetworkIfCommand::Create(_) => NETIF_CMD_CREATE,
+            NetworkIfCommand::Update(_) => NETIF_CMD_UPDATE,
+            NetworkIfCommand::Delete { .. } => NETIF_CMD_DELETE,
+            NetworkIfCommand::Destroy => NETIF_CMD_DESTROY,
+        }
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) -> Result<(), NetIfCmdError> {
+        let tag = self.tag();
+        let mut value = vec![];
+        match self {
+            NetworkIfCommand::Create(settings) | NetworkIfCommand::Update(settings) => {
+                encode_name(tag, &settings.name, &mut value)?;
+                if let Some(mtu) = settings.mtu {
+                    encode_tlv(NETIF_FIELD_MTU, &mtu.to_be_bytes(), &mut value)?;
+                }
+                for (addr, prefix_len) in &settings.addresses {
+                    encode_tlv(NETIF_FIELD_ADDRESS, &encode_prefix(addr, *prefix_len), &mut value)?;
+                }
+                for (dst, prefix_len) in &settings.routes {
+                    encode_tlv(NETIF_FIELD_ROUTE, &encode_prefix(dst, *prefix_len), &mut value)?;
+                }
+                for server in &settings.dns {
+                    encode_tlv(NETIF_FIELD_DNS, &encode_addr(server), &mut value)?;
+                }
+            },
+            NetworkIfCommand::Delete { name } => encode_name(tag, name, &mut value)?,
+            NetworkIfCommand::Destroy => {},
+        }
+        encode_tlv(tag, &value, buf)
+    }
+
+    fn decode(tag: u8, value: &[u8]) -> Result<NetworkIfCommand, NetIfCmdError> {
+        match tag {
+            NETIF_CMD_CREATE => Ok(NetworkIfCommand::Create(decode_settings(tag, value)?)),
+            NETIF_CMD_UPDATE => Ok(NetworkIfCommand::Update(decode_settings(tag, value)?)),
+            NETIF_CMD_DELETE => {
+                let mut name = None;
+                for tlv in Tlvs::new(value) {
+                    let (_, field, content) = tlv?;
+                    match field {
+                        NETIF_FIELD_NAME => name = Some(decode_name(tag, content)?),
+                        field => return Err(NetIfCmdError::UnknownField { command: tag, field }),
+                    }
+                }
+                Ok(NetworkIfCommand::Delete { name: name.ok_or(NetIfCmdError::MissingName(tag))? })
+            },
+            NETIF_CMD_DESTROY if value.is_empty() => Ok(NetworkIfCommand::Destroy),
+            NETIF_CMD_DESTROY => Err(NetIfCmdError::BadLength(tag)),
+            tag => Err(NetIfCmdError::UnknownCommand(tag)),
+        }
+    }
+}
+
+/// Encodes the commands, behind the current VERSION.
+pub fn encode_commands(commands: &[NetworkIfCommand]) -> Result<Vec<u8>, NetIfCmdError> {
+    if commands.is_empty() {
+        return Err(NetIfCmdError::NoCommand);
+    }
+    let mut buf = vec![];
+    encode_tlv(NETIF_CMD_TAG_VERSION, &[NETIF_CMD_VERSION], &mut buf)?;
+    for command in commands {
+        command.encode(&mut buf)?;
+    }
+    Ok(buf)
+}
+
+/// Decodes every command of a message. One malformed command fails the
+/// whole message, so none of it is carried out.
+pub fn decode_commands(data: &[u8]) -> Result<Vec<NetworkIfCommand>, NetIfCmdError> {
+    let mut commands = vec![];
+    for tlv in Tlvs::new(data) {
+        let (offset, tag, value) = tlv?;
+        match tag {
+            NETIF_CMD_TAG_VERSION if offset == 0 => match value {
+                [version] if (1..=NETIF_CMD_VERSION).contains(version) => {},
+                [version] => return Err(NetIfCmdError::UnsupportedVersion(*version)),
+                _ => return Err(NetIfCmdError::BadLength(tag)),
+            },
+            NETIF_CMD_TAG_VERSION => return Err(NetIfCmdError::MisplacedVersion),
+            tag => commands.push(NetworkIfCommand::decode(tag, value)?),
+        }
+    }
+    if commands.is_empty() {
+        return Err(NetIfCmdError::NoCommand);
+    }
+    Ok(commands)
+}
+
+/// TLVs of `data` with their offset, up to the first one running past the
+/// end.
+struct Tlvs<'a> {
+    data: &'a [u8],
+    index: usize,
+}
+
+impl<'a> Tlvs<'a> {
+    fn new(data: &'a [u8]) -> Tlvs<'a> {
+        Tlvs { data, index: 0 }
+    }
+}
+
+impl<'a> Iterator for Tlvs<'a> {
+    type Item = Result<(usize, u8, &'a [u8]), NetIfCmdError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.index >= self.data.len() {
+            return None;
+        }
+        let offset = self.index;
+        let value = self.data.get(offset + 1)
+            .and_then(|length| self.data.get(offset + 2..offset + 2 + *length as usize));
+        match value {
+            Some(value) => {
+                self.index = offset + 2 + value.len();
+                Some(Ok((offset, self.data[offset], value)))
+            },
+            None => {
+                self.index = self.data.len();
+                Some(Err(NetIfCmdError::Truncated(offset)))
+            },
+        }
+    }
+}
+
+fn encode_tlv(tag: u8, value: &[u8], buf: &mut Vec<u8>) -> Result<(), NetIfCmdError> {
+    let length = u8::try_from(value.len()).map_err(|_| NetIfCmdError::TooLong(tag))?;
+    buf.push(tag);
+    buf.push(length);
+    buf.extend_from_slice(value);
+    Ok(())
+}
+
+fn encode_name(command: u8, name: &str, value: &mut Vec<u8>) -> Result<(), NetIfCmdError> {
+    if name.is_empty() {
+        return Err(NetIfCmdError::MissingName(command));
+    }
+    encode_tlv(NETIF_FIELD_NAME, name.as_bytes(), value)
+}
+
+fn encode_addr(addr: &IpAddr) -> Vec<u8> {
+    match addr {
+        IpAddr::V4(ipv4) => ipv4.octets().to_vec(),
+        IpAddr::V6(ipv6) => ipv6.octets().to_vec(),
+    }
+}
+
+fn encode_prefix(addr: &IpAddr, prefix_len: u8) -> Vec<u8> {
+    let mut value = encode_addr(addr);
+    value.push(prefix_len);
+    value
+}
+
+fn decode_name(command: u8, content: &[u8]) -> Result<String, NetIfCmdError> {
+    match String::from_utf8(content.to_vec()) {
+        Ok(name) if !name.is_empty() => Ok(name),
+        _ => Err(NetIfCmdError::BadField { command, field: NETIF_FIELD_NAME }),
+    }
+}
+
+fn decode_addr(content: &[u8]) -> Option<IpAddr> {
+    if let Ok(ipv4) = <[u8; 4]>::try_from(content) {
+        return Some(IpAddr::V4(Ipv4Addr::from(ipv4)));
+    }
+    let ipv6 = <[u8; 16]>::try_from(content).ok()?;
+    Some(IpAddr::V6(Ipv6Addr::from(ipv6)))
+}
+
+fn decode_prefix(content: &[u8]) -> Option<(IpAddr, u8)> {
+    let (prefix_len, addr) = content.split_last()?;
+    let addr = decode_addr(addr)?;
+    let max = if addr.is_ipv4() { 32 } else { 128 };
+    if *prefix_len > max {
+        return None;
+    }
+    Some((addr, *prefix_len))
+}
+
+fn decode_settings(command: u8, value: &[u8]) -> Result<NetIfSettings, NetIfCmdError> {
+    let mut settings = NetIfSettings::default();
+    for tlv in Tlvs::new(value) {
+        let (_, field, content) = tlv?;
+        let bad = NetIfCmdError::BadField { command, field };
+        match field {
+            NETIF_FIELD_NAME => settings.name = decode_name(command, content)?,
+            NETIF_FIELD_ADDRESS => settings.addresses.push(decode_prefix(content).ok_or(bad)?),
+            NETIF_FIELD_ROUTE => settings.routes.push(decode_prefix(content).ok_or(bad)?),
+            NETIF_FIELD_MTU => {
+                let mtu: [u8; 2] = content.try_into().map_err(|_| bad)?;
+                settings.mtu = Some(u16::from_be_bytes(mtu));
+            },
+            NETIF_FIELD_DNS => settings.dns.push(decode_addr(content).ok_or(bad)?),
+            field => return Err(NetIfCmdError::UnknownField { command, field }),
+        }
+    }
+    if settings.name.is_empty() {
+        return Err(NetIfCmdError::MissingName(command));
+    }
+    Ok(settings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VERSION: [u8; 3] = [NETIF_CMD_TAG_VERSION, 1, NETIF_CMD_VERSION];
+    const DELETE_PDU2: [u8; 8] = [NETIF_CMD_DELETE, 6, NETIF_FIELD_NAME, 4, b'p', b'd', b'u', b'2'];
+
+    fn message(commands: &[&[u8]]) -> Vec<u8> {
+        commands.concat()
+    }
+
+    #[test]
+    fn create_encodes_every_field() {
+        let create = NetworkIfCommand::Create(NetIfSettings::new("a")
+            .with_mtu(1400)
+            .with_address(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 24)
+            .with_route(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0)
+            .with_dns(IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8))));
+        let mut data = VERSION.to_vec();
+        data.extend_from_slice(&[NETIF_CMD_CREATE, 39]);
+        data.extend_from_slice(&[NETIF_FIELD_NAME, 1, b'a']);
+        data.extend_from_slice(&[NETIF_FIELD_MTU, 2, 0x05, 0x78]);
+        data.extend_from_slice(&[NETIF_FIELD_ADDRESS, 5, 10, 0, 0, 1, 24]);
+        data.extend_from_slice(&[NETIF_FIELD_ROUTE, 17]);
+        data.extend_from_slice(&[0; 17]);
+        data.extend_from_slice(&[NETIF_FIELD_DNS, 4, 8, 8, 8, 8]);
+        assert_eq!(encode_commands(std::slice::from_ref(&create)), Ok(data.clone()));
+        assert_eq!(decode_commands(&data), Ok(vec![create]));
+    }
+
+    #[test]
+    fn commands_round_trip_in_order() {
+        let commands = vec![
+            NetworkIfCommand::Create(NetIfSettings::new("pdu1")
+                .with_address(IpAddr::V4(Ipv4Addr::new(172, 26, 100, 101)), 32)
+                .with_address(IpAddr::V6(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1)), 64)
+                .with_route(IpAddr::V4(Ipv4Addr::new(10, 45, 0, 0)), 16)
+                .with_dns(IpAddr::V6(Ipv6Addr::new(0x2001, 0x4860, 0x4860, 0, 0, 0, 0, 0x8888)))),
+            NetworkIfCommand::Update(NetIfSettings::new("pdu1").with_mtu(1456)),
+            NetworkIfCommand::Delete { name: "pdu2".to_string() },
+            NetworkIfCommand::Destroy,
+        ];
+        let data = encode_commands(&commands).unwrap();
+        assert_eq!(decode_commands(&data), Ok(commands));
+        assert_eq!(encode_commands(&[NetworkIfCommand::Delete { name: "pdu2".to_string() }]), Ok(message(&[&VERSION, &DELETE_PDU2])));
+    }
+
+    #[test]
+    fn message_without_version_is_version_1() {
+        let delete = NetworkIfCommand::Delete { name: "pdu2".to_string() };
+        assert_eq!(decode_commands(&DELETE_PDU2), Ok(vec![delete.clone()]));
+        assert_eq!(decode_commands(&message(&[&DELETE_PDU2, &[NETIF_CMD_DESTROY, 0]])), Ok(vec![delete, NetworkIfCommand::Destroy]));
+    }
+
+    #[test]
+    fn malformed_messages_are_rejected() {
+        let cases: &[(Vec<u8>, NetIfCmdError)] = &[
+            (message(&[&VERSION, &DELETE_PDU2[..7]]), NetIfCmdError::Truncated(3)),
+            (vec![NETIF_CMD_DESTROY], NetIfCmdError::Truncated(0)),
+            // Offsets inside a command are those of its value
+            (vec![NETIF_CMD_CREATE, 3, NETIF_FIELD_NAME, 2, b'a'], NetIfCmdError::Truncated(0)),
+            (message(&[&[NETIF_CMD_TAG_VERSION, 1, 2], &DELETE_PDU2]), NetIfCmdError::UnsupportedVersion(2)),
+            (message(&[&[NETIF_CMD_TAG_VERSION, 1, 0], &DELETE_PDU2]), NetIfCmdError::UnsupportedVersion(0)),
+            (message(&[&[NETIF_CMD_TAG_VERSION, 0], &DELETE_PDU2]), NetIfCmdError::BadLength(NETIF_CMD_TAG_VERSION)),
+            (vec![NETIF_CMD_DESTROY, 1, 0], NetIfCmdError::BadLength(NETIF_CMD_DESTROY)),
+            (message(&[&DELETE_PDU2, &VERSION]), NetIfCmdError::MisplacedVersion),
+            (vec![0x42, 0], NetIfCmdError::UnknownCommand(0x42)),
+            (vec![NETIF_CMD_DELETE, 2, NETIF_FIELD_MTU, 0], NetIfCmdError::UnknownField { command: NETIF_CMD_DELETE, field: NETIF_FIELD_MTU }),
+            (vec![NETIF_CMD_CREATE, 5, NETIF_FIELD_NAME, 1, b'a', 0x09, 0], NetIfCmdError::UnknownField { command: NETIF_CMD_CREATE, field: 0x09 }),
+            (vec![NETIF_CMD_CREATE, 6, NETIF_FIELD_NAME, 1, b'a', NETIF_FIELD_MTU, 1, 5], NetIfCmdError::BadField { command: NETIF_CMD_CREATE, field: NETIF_FIELD_MTU }),
+            (vec![NETIF_CMD_UPDATE, 10, NETIF_FIELD_NAME, 1, b'a', NETIF_FIELD_ROUTE, 5, 10, 0, 0, 0, 33], NetIfCmdError::BadField { command: NETIF_CMD_UPDATE, field: NETIF_FIELD_ROUTE }),
+            (vec![NETIF_CMD_CREATE, 9, NETIF_FIELD_NAME, 1, b'a', NETIF_FIELD_ADDRESS, 4, 10, 0, 0, 1], NetIfCmdError::BadField { command: NETIF_CMD_CREATE, field: NETIF_FIELD_ADDRESS }),
+            (vec![NETIF_CMD_CREATE, 8, NETIF_FIELD_NAME, 1, b'a', NETIF_FIELD_DNS, 3, 8, 8, 8], NetIfCmdError::BadField { command: NETIF_CMD_CREATE, field: NETIF_FIELD_DNS }),
+            (vec![NETIF_CMD_DELETE, 3, NETIF_FIELD_NAME, 1, 0xff], NetIfCmdError::BadField { command: NETIF_CMD_DELETE, field: NETIF_FIELD_NAME }),
+            (vec![NETIF_CMD_DELETE, 2, NETIF_FIELD_NAME, 0], NetIfCmdError::BadField { command: NETIF_CMD_DELETE, field: NETIF_FIELD_NAME }),
+            (vec![NETIF_CMD_CREATE, 4, NETIF_FIELD_MTU, 2, 0x05, 0x78], NetIfCmdError::MissingName(NETIF_CMD_CREATE)),
+            (vec![NETIF_CMD_DELETE, 0], NetIfCmdError::MissingName(NETIF_CMD_DELETE)),
+            (VERSION.to_vec(), NetIfCmdError::NoCommand),
+            (vec![], NetIfCmdError::NoCommand),
+        ];
+        for (data, error) in cases {
+            assert_eq!(decode_commands(data).as_ref(), Err(error), "{:02x?}", data);
+        }
+    }
+
+    #[test]
+    fn commands_that_do_not_fit_are_not_encoded() {
+        let long_name = NetworkIfCommand::Delete { name: "a".repeat(256) };
+        assert_eq!(encode_commands(&[long_name]), Err(NetIfCmdError::TooLong(NETIF_FIELD_NAME)));
+        // 14 IPv6 addresses of 19 octets each fill more than one command
+        let many = (0..14).fold(NetIfSettings::new("pdu1"), |settings, i| settings.with_address(IpAddr::V6(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, i)), 128));
+        assert_eq!(encode_commands(&[NetworkIfCommand::Update(many)]), Err(NetIfCmdError::TooLong(NETIF_CMD_UPDATE)));
+        assert_eq!(encode_commands(&[NetworkIfCommand::Create(NetIfSettings::default())]), Err(NetIfCmdError::MissingName(NETIF_CMD_CREATE)));
+        assert_eq!(encode_commands(&[]), Err(NetIfCmdError::NoCommand));
+    }
+}
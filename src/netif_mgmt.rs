@@ -0,0 +1,364 @@
+use std::{collections::{BTreeMap, BTreeSet}, fmt, io, net::IpAddr, process::Command, sync::Arc};
+
+use rust_itti::netif_cmd::{decode_commands, NetIfSettings, NetworkIfCommand};
+
+use crate::{itti::{Itti, Mailbox}, msg::{IttiMsg, NetIfMgmtResult}, rtnetlink::{link_index, RtNetlink, RT_TABLE_MAIN}};
+
+/// One step of a command, shown as the `ip` command doing the same.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NetIfOp {
+    SetLink { dev: String, mtu: Option<u16> },
+    AddAddress { dev: String, addr: IpAddr, prefix_len: u8, replace: bool },
+    AddRoute { dev: String, dst: IpAddr, prefix_len: u8, replace: bool },
+    DelAddress { dev: String, addr: IpAddr, prefix_len: u8 },
+    DelRoute { dev: String, dst: IpAddr, prefix_len: u8 },
+    SetDns { dev: String, servers: Vec<IpAddr> },
+    DeleteLink { dev: String },
+}
+
+impl fmt::Display for NetIfOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let verb = |replace: bool| if replace { "replace" } else { "add" };
+        match self {
+            NetIfOp::SetLink { dev, mtu: Some(mtu) } => write!(f, "ip link set dev {} mtu {} up", dev, mtu),
+            NetIfOp::SetLink { dev, mtu: None } => write!(f, "ip link set dev {} up", dev),
+            NetIfOp::AddAddress { dev, addr, prefix_len, replace } => write!(f, "ip address {} {}/{} dev {}", verb(*replace), addr, prefix_len, dev),
+            NetIfOp::AddRoute { dev, dst, prefix_len, replace } => write!(f, "ip route {} {}/{} dev {}", verb(*replace), dst, prefix_len, dev),
+            NetIfOp::DelAddress { dev, addr, prefix_len } => write!(f, "ip address del {}/{} dev {}", addr, prefix_len, dev),
+            NetIfOp::DelRoute { dev, dst, prefix_len } => write!(f, "ip route del {}/{} dev {}", dst, prefix_len, dev),
+            // Not a link setting, resolvers are told per interface
+            NetIfOp::SetDns { dev, servers } => {
+                write!(f, "resolvectl dns {}", dev)?;
+                for server in servers {
+                    write!(f, " {}", server)?;
+                }
+                Ok(())
+            },
+            NetIfOp::DeleteLink { dev } => write!(f, "ip link del dev {}", dev),
+        }
+    }
+}
+
+fn existing_link_index(dev: &str) -> io::Result<Option<u32>> {
+    match link_index(dev) {
+        Ok(index) => Ok(Some(index)),
+        Err(e) if e.raw_os_error() == Some(libc::ENODEV) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Addresses and routes this task added to one interface.
+#[derive(Debug, Clone, Default)]
+struct NetIfState {
+    addresses: BTreeSet<(IpAddr, u8)>,
+    routes: BTreeSet<(IpAddr, u8)>,
+}
+
+/// Keeps track of what an operation added or removed, once it is done.
+fn track(managed: &mut BTreeMap<String, NetIfState>, op: &NetIfOp) {
+    match op {
+        NetIfOp::AddAddress { dev, addr, prefix_len, .. } => {
+            managed.entry(dev.clone()).or_default().addresses.insert((*addr, *prefix_len));
+        },
+        NetIfOp::AddRoute { dev, dst, prefix_len, .. } => {
+            managed.entry(dev.clone()).or_default().routes.insert((*dst, *prefix_len));
+        },
+        NetIfOp::DelAddress { dev, addr, prefix_len } => {
+            if let Some(state) = managed.get_mut(dev) {
+                state.addresses.remove(&(*addr, *prefix_len));
+            }
+        },
+        NetIfOp::DelRoute { dev, dst, prefix_len } => {
+            if let Some(state) = managed.get_mut(dev) {
+                state.routes.remove(&(*dst, *prefix_len));
+            }
+        },
+        NetIfOp::DeleteLink { dev } => {
+            managed.remove(dev);
+        },
+        NetIfOp::SetLink { .. } | NetIfOp::SetDns { .. } => {},
+    }
+    managed.retain(|_, state| !state.addresses.is_empty() || !state.routes.is_empty());
+}
+
+/// Applies the interface management commands of the controller to existing
+/// interfaces: CREATE and UPDATE set addresses, routes, MTU and DNS on one,
+/// DELETE removes one interface and DESTROY the addresses and routes added
+/// to any so far, leaving the interfaces and whatever else they have. An
+/// UPDATE with addresses removes those added before and not in it, and the
+/// same for routes. In dry-run mode nothing is touched and the planned
+/// operations are only printed and returned.
+pub struct NetIfMgmt {
+    dry_run: bool,
+    netlink: Option<RtNetlink>,
+    managed: BTreeMap<String, NetIfState>,
+}
+
+impl NetIfMgmt {
+    pub fn new(dry_run: bool) -> NetIfMgmt {
+        NetIfMgmt {
+            dry_run,
+            netlink: None,
+            managed: BTreeMap::new(),
+        }
+    }
+
+    fn plan_settings(settings: &NetIfSettings, replace: bool) -> Vec<NetIfOp> {
+        let dev = &settings.name;
+        let mut ops = vec![NetIfOp::SetLink { dev: dev.clone(), mtu: settings.mtu }];
+        for (addr, prefix_len) in &settings.addresses {
+            ops.push(NetIfOp::AddAddress { dev: dev.clone(), addr: *addr, prefix_len: *prefix_len, replace });
+        }
+        for (dst, prefix_len) in &settings.routes {
+            ops.push(NetIfOp::AddRoute { dev: dev.clone(), dst: *dst, prefix_len: *prefix_len, replace });
+        }
+        if !settings.dns.is_empty() {
+            ops.push(NetIfOp::SetDns { dev: dev.clone(), servers: settings.dns.clone() });
+        }
+        ops
+    }
+
+    /// Addresses and routes added before that an UPDATE no longer has.
+    fn plan_stale(state: &NetIfState, settings: &NetIfSettings) -> Vec<NetIfOp> {
+        let dev = &settings.name;
+        let mut ops = vec![];
+        if !settings.addresses.is_empty() {
+            ops.extend(state.addresses.iter()
+                .filter(|address| !settings.addresses.contains(address))
+                .map(|(addr, prefix_len)| NetIfOp::DelAddress { dev: dev.clone(), addr: *addr, prefix_len: *prefix_len }));
+        }
+        if !settings.routes.is_empty() {
+            ops.extend(state.routes.iter()
+                .filter(|route| !settings.routes.contains(route))
+                .map(|(dst, prefix_len)| NetIfOp::DelRoute { dev: dev.clone(), dst: *dst, prefix_len: *prefix_len }));
+        }
+        ops
+    }
+
+    /// Operations carrying out the commands of a message, in order.
+    pub fn plan(&self, data: &[u8]) -> Result<Vec<NetIfOp>, String> {
+        let commands = decode_commands(data).map_err(|e| e.to_string())?;
+        // What the commands before in the same message will have added too
+        let mut managed = self.managed.clone();
+        let mut ops = vec![];
+        for command in commands {
+            let planned = match command {
+                NetworkIfCommand::Create(settings) => NetIfMgmt::plan_settings(&settings, false),
+                NetworkIfCommand::Update(settings) => {
+                    let mut planned = match managed.get(&settings.name) {
+                        Some(state) => NetIfMgmt::plan_stale(state, &settings),
+                        None => vec![],
+                    };
+                    planned.extend(NetIfMgmt::plan_settings(&settings, true));
+                    planned
+                },
+                NetworkIfCommand::Delete { name } => vec![NetIfOp::DeleteLink { dev: name }],
+                NetworkIfCommand::Destroy => managed
+                    .iter()
+                    .flat_map(|(dev, state)| {
+                        let routes = state.routes.iter().map(|(dst, prefix_len)| NetIfOp::DelRoute { dev: dev.clone(), dst: *dst, prefix_len: *prefix_len });
+                        let addresses = state.addresses.iter().map(|(addr, prefix_len)| NetIfOp::DelAddress { dev: dev.clone(), addr: *addr, prefix_len: *prefix_len });
+                        routes.chain(addresses).collect::<Vec<_>>()
+                    })
+                    .collect(),
+            };
+            for op in &planned {
+                track(&mut managed, op);
+            }
+            ops.extend(planned);
+        }
+        Ok(ops)
+    }
+
+    fn netlink(&mut self) -> io::Result<&mut RtNetlink> {
+        if self.netlink.is_none() {
+            self.netlink = Some(RtNetlink::open()?);
+        }
+        Ok(self.netlink.as_mut().unwrap())
+    }
+
+    fn apply(&mut self, op: &NetIfOp) -> io::Result<()> {
+        match op {
+            NetIfOp::SetLink { dev, mtu } => {
+                let index = link_index(dev)?;
+                self.netlink()?.set_link(index, *mtu)?;
+            },
+            NetIfOp::AddAddress { dev, addr, prefix_len, replace } => {
+                let index = link_index(dev)?;
+                self.netlink()?.add_address(index, *addr, *prefix_len, *replace)?;
+            },
+            NetIfOp::AddRoute { dev, dst, prefix_len, replace } => {
+                let index = link_index(dev)?;
+                self.netlink()?.add_route(index, *dst, *prefix_len, RT_TABLE_MAIN, *replace)?;
+            },
+            // Addresses and routes of an interface deleted since went with it
+            NetIfOp::DelAddress { dev, addr, prefix_len } => {
+                if let Some(index) = existing_link_index(dev)? {
+                    self.netlink()?.del_address(index, *addr, *prefix_len)?;
+                }
+            },
+            NetIfOp::DelRoute { dev, dst, prefix_len } => {
+                if let Some(index) = existing_link_index(dev)? {
+                    self.netlink()?.del_route(index, *dst, *prefix_len, RT_TABLE_MAIN)?;
+                }
+            },
+            NetIfOp::SetDns { dev, servers } => {
+                let output = Command::new("resolvectl")
+                    .arg("dns")
+                    .arg(dev)
+                    .args(servers.iter().map(|server| server.to_string()))
+                    .output()?;
+                if !output.status.success() {
+                    return Err(io::Error::other(String::from_utf8_lossy(&output.stderr).trim().to_string()));
+                }
+            },
+            NetIfOp::DeleteLink { dev } => {
+                let index = link_index(dev)?;
+                self.netlink()?.del_link(index)?;
+            },
+        }
+        Ok(())
+    }
+
+    /// Runs the operations until one fails; those done are not undone.
+    pub fn execute(&mut self, data: &[u8]) -> NetIfMgmtResult {
+        let ops = match self.plan(data) {
+            Ok(ops) => ops,
+            Err(e) => {
+                println!("netif: {}", e);
+                return NetIfMgmtResult { ops: vec![], error: Some(e) };
+            },
+        };
+        let mut error = None;
+        for op in &ops {
+            if self.dry_run {
+                println!("netif: dry run: {}", op);
+            } else if let Err(e) = self.apply(op) {
+                println!("netif: {}: {}", op, e);
+                error = Some(format!("{}: {}", op, e));
+                break;
+            } else {
+                println!("netif: {}", op);
+            }
+            track(&mut self.managed, op);
+        }
+        NetIfMgmtResult { ops: ops.iter().map(|op| op.to_string()).collect(), error }
+    }
+
+    pub fn init_netif_mgmt_task(mut self, itti: Arc<Itti>, mailbox: Mailbox) {
+        while let Ok(envelope) = mailbox.recv() {
+            match envelope.msg {
+                IttiMsg::NetIfMgmtCommand(req) => {
+                    let result = self.execute(&req.data);
+                    // Commands replayed or posted have nobody to answer
+                    let _ = itti.reply(&envelope.header, mailbox.id, IttiMsg::NetIfMgmtResult(result));
+                },
+                IttiMsg::NetIfMgmtStopThread => {
+                    break;
+                },
+                msg => {println!("{:#?}", msg);},
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use rust_itti::netif_cmd::encode_commands;
+
+    use super::*;
+
+    fn pdu1() -> NetIfSettings {
+        NetIfSettings::new("pdu1")
+            .with_mtu(1456)
+            .with_address(IpAddr::V4(Ipv4Addr::new(172, 26, 100, 101)), 32)
+            .with_route(IpAddr::V4(Ipv4Addr::new(10, 45, 0, 0)), 16)
+            .with_dns(IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8)))
+    }
+
+    fn pdu2() -> NetIfSettings {
+        NetIfSettings::new("pdu2").with_address(IpAddr::V6(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2)), 64)
+    }
+
+    fn plan(mgmt: &NetIfMgmt, commands: &[NetworkIfCommand]) -> Vec<String> {
+        mgmt.plan(&encode_commands(commands).unwrap()).unwrap().iter().map(|op| op.to_string()).collect()
+    }
+
+    /// Runs the commands in dry-run mode, which keeps track the same way.
+    fn executed(commands: &[NetworkIfCommand]) -> NetIfMgmt {
+        let mut mgmt = NetIfMgmt::new(true);
+        assert_eq!(mgmt.execute(&encode_commands(commands).unwrap()).error, None);
+        mgmt
+    }
+
+    #[test]
+    fn create_sets_link_addresses_routes_and_dns() {
+        assert_eq!(plan(&NetIfMgmt::new(true), &[NetworkIfCommand::Create(pdu1())]), [
+            "ip link set dev pdu1 mtu 1456 up",
+            "ip address add 172.26.100.101/32 dev pdu1",
+            "ip route add 10.45.0.0/16 dev pdu1",
+            "resolvectl dns pdu1 8.8.8.8",
+        ]);
+    }
+
+    #[test]
+    fn update_replaces_what_was_added_before() {
+        let mgmt = executed(&[NetworkIfCommand::Create(pdu1())]);
+        let update = NetIfSettings::new("pdu1")
+            .with_address(IpAddr::V4(Ipv4Addr::new(172, 26, 100, 102)), 32)
+            .with_route(IpAddr::V4(Ipv4Addr::new(10, 45, 0, 0)), 16);
+        assert_eq!(plan(&mgmt, &[NetworkIfCommand::Update(update.clone())]), [
+            "ip address del 172.26.100.101/32 dev pdu1",
+            "ip link set dev pdu1 up",
+            "ip address replace 172.26.100.102/32 dev pdu1",
+            "ip route replace 10.45.0.0/16 dev pdu1",
+        ]);
+        // Also when added earlier in the same message
+        assert_eq!(plan(&NetIfMgmt::new(true), &[NetworkIfCommand::Create(pdu1()), NetworkIfCommand::Update(update)])[4],
+            "ip address del 172.26.100.101/32 dev pdu1");
+        // Without addresses or routes it leaves them alone
+        assert_eq!(plan(&mgmt, &[NetworkIfCommand::Update(NetIfSettings::new("pdu1").with_mtu(1400))]), ["ip link set dev pdu1 mtu 1400 up"]);
+        assert_eq!(plan(&mgmt, &[NetworkIfCommand::Update(pdu2())]), [
+            "ip link set dev pdu2 up",
+            "ip address replace fd00::2/64 dev pdu2",
+        ]);
+    }
+
+    #[test]
+    fn delete_removes_the_interface() {
+        let mgmt = executed(&[NetworkIfCommand::Create(pdu1()), NetworkIfCommand::Create(pdu2())]);
+        assert_eq!(plan(&mgmt, &[NetworkIfCommand::Delete { name: "pdu2".to_string() }]), ["ip link del dev pdu2"]);
+        assert_eq!(plan(&NetIfMgmt::new(true), &[NetworkIfCommand::Delete { name: "eth0".to_string() }]), ["ip link del dev eth0"]);
+    }
+
+    #[test]
+    fn destroy_only_removes_what_was_added() {
+        let mut mgmt = executed(&[
+            NetworkIfCommand::Create(pdu1()),
+            NetworkIfCommand::Create(pdu2()),
+            NetworkIfCommand::Delete { name: "pdu2".to_string() },
+            NetworkIfCommand::Update(NetIfSettings::new("pdu1").with_route(IpAddr::V4(Ipv4Addr::new(10, 46, 0, 0)), 16)),
+        ]);
+        let destroy = [NetworkIfCommand::Destroy];
+        assert_eq!(plan(&mgmt, &destroy), [
+            "ip route del 10.46.0.0/16 dev pdu1",
+            "ip address del 172.26.100.101/32 dev pdu1",
+        ]);
+        // Interfaces set up after it in the same message are left alone
+        assert_eq!(plan(&mgmt, &[NetworkIfCommand::Destroy, NetworkIfCommand::Create(pdu2())])[..2], plan(&mgmt, &destroy));
+        assert_eq!(mgmt.execute(&encode_commands(&destroy).unwrap()).ops.len(), 2);
+        assert!(plan(&mgmt, &destroy).is_empty());
+        assert!(plan(&NetIfMgmt::new(true), &destroy).is_empty());
+    }
+
+    #[test]
+    fn undecodable_message_plans_nothing() {
+        let mut mgmt = NetIfMgmt::new(true);
+        // Command 0x42 does not exist
+        assert!(mgmt.plan(&[0x42, 0]).is_err());
+        let result = mgmt.execute(&[]);
+        assert_eq!((result.ops.len(), result.error.is_some()), (0, true));
+    }
+}
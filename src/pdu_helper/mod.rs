@@ -1,3 +1,4 @@
+#[allow(clippy::module_inception)]
 pub mod pdu_helper;
 pub mod qos_rules;
 pub mod pdu_accept;
\ No newline at end of file
@@ -1,4 +1,4 @@
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::{net::{IpAddr, Ipv4Addr, Ipv6Addr}, time::Duration};
 
 
 // 首先是一些协议的常量定义,如消息类型、信息元素标识等。
@@ -12,55 +12,42 @@ use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 // 解析函数根据协议的格式,逐步解析字节数据,填充到数据结构中。
 // 这样就可以从字节流中解析出结构化的协议消息。
 // 参数容器
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 struct ParamContainer {
     _container_id: u16,
     _container_len: u8,
     _container_content: Vec<u8>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct ExtProtoCfgOpts {
     _length: u16,
     //   config_proto: u8,
     _pco_units: Vec<ParamContainer>,
 }
 
-impl Default for ParamContainer {
-    fn default() -> Self {
-        Self {
-            _container_id: 0,
-            _container_len: 0,
-            _container_content: Vec::new(),
-        }
-    }
-}
-
-impl Default for ExtProtoCfgOpts {
-    fn default() -> Self {
-        Self {
-            _length: 0,
-            _pco_units: Vec::new(),
-        }
-    }
-}
-
 impl ParamContainer {
-    pub fn to_ipv6_addr(&mut self) -> Option<Ipv6Addr> {
+    pub fn to_ipv6_addr(&self) -> Option<Ipv6Addr> {
         if self._container_len == 16 {
             // 8 * 16 = 128 bit ipv6
             let array: [u8; 16] = self._container_content.as_slice().try_into().unwrap();
-            Some(Ipv6Addr::from(array));
+            return Some(Ipv6Addr::from(array));
         }
         None
     }
+
+    pub fn to_ipv4_addr(&self) -> Option<Ipv4Addr> {
+        let array: [u8; 4] = self._container_content.as_slice().try_into().ok()?;
+        Some(Ipv4Addr::from(array))
+    }
 }
 
 impl ExtProtoCfgOpts {
+    #[allow(dead_code)]
     pub fn get_pcscf_v6_addr(&mut self) -> Option<Ipv6Addr> {
         let pco_units = self._pco_units.clone();
 
-        for mut param_container in pco_units {
+        for param_container in pco_units {
             if param_container._container_id == 0x0001 {
                 let array: [u8; 16] = param_container
                     ._container_content
@@ -74,19 +61,38 @@ impl ExtProtoCfgOpts {
                 // return None;
             }
         }
-        return None;
+        None
     }
 
     pub fn get_dns_v6_addr(&mut self) -> Option<Ipv6Addr> {
         let pco_units = self._pco_units.clone();
 
-        for mut param_container in pco_units {
+        for param_container in pco_units {
             if param_container._container_id == 0x0003 {
                 return param_container.to_ipv6_addr();
             }
         }
         None
     }
+
+    /**
+     * 3GPP TS 24008 10.5.6.3: DNS Server IPv4 Address, container 000DH.
+     */
+    pub fn get_dns_v4_addr(&self) -> Option<Ipv4Addr> {
+        self._pco_units
+            .iter()
+            .find(|param_container| param_container._container_id == 0x000d)
+            .and_then(|param_container| param_container.to_ipv4_addr())
+    }
+
+    /**
+     * 3GPP TS 24008 10.5.6.3: IPv4 Link MTU, container 0010H.
+     */
+    pub fn get_ipv4_link_mtu(&self) -> Option<u16> {
+        let param_container = self._pco_units.iter().find(|param_container| param_container._container_id == 0x0010)?;
+        let array: [u8; 2] = param_container._container_content.as_slice().try_into().ok()?;
+        Some(u16::from_be_bytes(array))
+    }
 }
 
 // 解析函数
@@ -94,16 +100,16 @@ pub fn parse_extended_pco(data: &[u8]) -> Option<ExtProtoCfgOpts> {
     let mut params = vec![];
 
     let mut _i = 3; // 前4字节是类型和长度
-    let length = u16::from_be_bytes([data[1], data[2]]);
+    let length = u16::from_be_bytes([*data.get(1)?, *data.get(2)?]);
     // print!("{:#?}\n",length);
     let mut i = 4;
     // 解析附加参数列表
     while i < data.len() {
-        let container_id = u16::from_be_bytes([data[i], data[i + 1]]);
+        let container_id = u16::from_be_bytes([data[i], *data.get(i + 1)?]);
         // print!("{:#?}\n",container_id);
 
-        let container_len = data[i + 2];
-        let container_content = &data[i + 3..i + 3 + container_len as usize];
+        let container_len = *data.get(i + 2)?;
+        let container_content = data.get(i + 3..i + 3 + container_len as usize)?;
 
         let container = ParamContainer {
             _container_id: container_id,
@@ -150,9 +156,7 @@ use std::alloc::alloc;
 use std::alloc::Layout;
 use std::slice;
 
-use crate::pdu_helper::qos_rules::{QOSRulesIE, RuleOperationCode, PacketFilterListDeletePFList, PacketFilterContent, PacketFilterComponentType, PacketFilterComponentValue, IPv4FilterAddress, IPv6FilterAddress, ProtocolIdentifierNextHeader, Port, PortRange, SecurityParameterIndex, TypeOfServiceTrafficClass, FlowLabel, MACAddress, VlanCtagVid, VlanStagVid, VlanCtagPcpdei, VlanStagPcpdei, Ethertype, DestinationMACAddressRange, SourceMACAddressRange, PacketFilterListEnum, PacketFilterListUpdatePFList};
-
-use super::pdu_helper::{ExtendedProtocolDiscriminator, PDUSessionIdentity, ProcedureTransactionIdentity, SessionMessageType, PduSessionPlainMsg};
+use super::pdu_helper::{ExtendedProtocolDiscriminator, PDUSessionIdentity, ProcedureTransactionIdentity, SessionMessageType};
 use super::qos_rules::QOSRules;
 
 
@@ -187,12 +191,13 @@ pub struct PduSessionEstablishmentAcceptMsg  {
     pub pdusessiontype: PDUSessionType,
     pub sscmode: SSCMode,
     pub qosrules: QOSRules,
-    // sessionambr: SessionAMBR,
+    pub sessionambr: SessionAMBR,
     // presence: u16,
     // _5gsmcause: _5GSMCause,
     pub pduaddress: PDUAddress,
     // gprstimer: GPRSTimer,
-    // snssai: SNSSAI,
+    pub rqtimer: Option<u8>,
+    pub snssai: SNSSAI,
     // alwaysonpdusessionindication: AlwaysonPDUSessionIndication,
     // mappedepsbearercontexts: MappedEPSBearerContexts,
     // eapmessage: EAPMessage,
@@ -226,13 +231,13 @@ impl PDUSessionType {
 
 #[repr(C)]
 #[derive(Debug)]
-
+#[allow(non_snake_case)]
 pub struct SSCMode {
     pub sscModeValue: u8,
     pub spare: u8,
 }
 
-#[repr(C)]
+// #[repr(C)]
 // pub struct PacketFilterContents {
 //     pub component_type: u8,
 //     pub component_value: OctetString,
@@ -269,15 +274,49 @@ pub struct SSCMode {
 // }
 
 
-// #[repr(C)]
-// #[derive(Debug)]
+#[repr(C)]
+#[derive(Debug, Default)]
 
-// pub struct SessionAMBR {
-//     pub uint_for_session_ambr_for_downlink: u8,
-//     pub session_ambr_for_downlink: u16,
-//     pub uint_for_session_ambr_for_uplink: u8,
-//     pub session_ambr_for_uplink: u16,
-// }
+pub struct SessionAMBR {
+    pub uint_for_session_ambr_for_downlink: u8,
+    pub session_ambr_for_downlink: u16,
+    pub uint_for_session_ambr_for_uplink: u8,
+    pub session_ambr_for_uplink: u16,
+}
+
+impl SessionAMBR {
+    /**
+     * 3GPP TS 24501 9.11.4.14
+     */
+    pub fn decode(data: &[u8]) -> SessionAMBR {
+        if data.len() < 6 {
+            return SessionAMBR::default();
+        }
+        SessionAMBR {
+            uint_for_session_ambr_for_downlink: data[0],
+            session_ambr_for_downlink: u16::from_be_bytes([data[1], data[2]]),
+            uint_for_session_ambr_for_uplink: data[3],
+            session_ambr_for_uplink: u16::from_be_bytes([data[4], data[5]]),
+        }
+    }
+
+    pub fn downlink_kbps(&self) -> u64 {
+        ambr_to_kbps(self.uint_for_session_ambr_for_downlink, self.session_ambr_for_downlink)
+    }
+
+    pub fn uplink_kbps(&self) -> u64 {
+        ambr_to_kbps(self.uint_for_session_ambr_for_uplink, self.session_ambr_for_uplink)
+    }
+}
+
+// unit 1..=5 are 1/4/16/64/256 Kbps, each further group of five is 1000 times the previous one
+fn ambr_to_kbps(unit: u8, value: u16) -> u64 {
+    if unit == 0 || unit > 25 {
+        return 0;
+    }
+    let step = (unit - 1) as u32;
+    value as u64 * 4u64.pow(step % 5) * 1000u64.pow(step / 5)
+}
 
 // pub type _5GSMCause = u8;
 #[repr(C)]
@@ -336,34 +375,33 @@ impl OctetString {
 
         if length > 0 {
             let layout = Layout::array::<u8>(length).unwrap();
-            self.value = unsafe { alloc(layout) as *mut u8 };
+            self.value = unsafe { alloc(layout) };
             unsafe {
                 std::ptr::copy_nonoverlapping(data.as_ptr().add(start_index), self.value, length);
             }
         }
     }
 
-    pub fn to_string(&mut self) -> &str {
+    #[allow(dead_code)]
+    pub fn to_string(&self) -> &str {
         let string: &str;
         unsafe {
             let slice = slice::from_raw_parts(self.value, self.length.try_into().unwrap());
             string = std::str::from_utf8(slice).unwrap();
         };
-        return string;
+        string
     }
 
-    pub fn dnn_to_string(&mut self) -> String {
-        let string = decode_dnn(self.value);
-        return string;
+    pub fn dnn_to_string(&self) -> String {
+        decode_dnn(self.value)
     }
 
-    pub fn to_bytes_u8(&mut self) -> &[u8] {
-        let mut _string: &str;
+    pub fn to_bytes_u8(&self) -> &[u8] {
         let slice: &[u8];
         unsafe {
             slice = slice::from_raw_parts(self.value, self.length.try_into().unwrap());
         };
-        return slice;
+        slice
     }
 }
 
@@ -385,16 +423,51 @@ impl OctetString {
 //     SST_AND_SD_AND_MAPPEDHPLMNSST_AND_MAPPEDHPLMNSD_LENGTH = 0b00001000,
 // }
 
-// #[repr(C)]
-// #[derive(Debug)]
+#[repr(C)]
+#[derive(Debug, Default)]
+#[allow(clippy::upper_case_acronyms)]
+pub struct SNSSAI {
+    pub len: u8,
+    pub sst: u8,
+    pub sd: [u8; 3],
+    pub mappedhplmnsst: u8,
+    pub mappedhplmnsd: [u8; 3],
+}
 
-// pub struct SNSSAI {
-//     pub len: length_of_snssai_contents,
-//     pub sst: u8,
-//     pub sd: [u8; 3],
-//     pub mappedhplmnsst: u8,
-//     pub mappedhplmnsd: [u8; 3],
-// }
+impl SNSSAI {
+    /**
+     * 3GPP TS 24501 9.11.2.8, `data` is the value part without IEI and length
+     */
+    pub fn decode(data: &[u8]) -> SNSSAI {
+        let mut snssai = SNSSAI {
+            len: data.len() as u8,
+            ..SNSSAI::default()
+        };
+        match data.len() {
+            1 => snssai.sst = data[0],
+            2 => {
+                snssai.sst = data[0];
+                snssai.mappedhplmnsst = data[1];
+            }
+            4 | 5 | 8 => {
+                snssai.sst = data[0];
+                snssai.sd.copy_from_slice(&data[1..4]);
+                if data.len() >= 5 {
+                    snssai.mappedhplmnsst = data[4];
+                }
+                if data.len() == 8 {
+                    snssai.mappedhplmnsd.copy_from_slice(&data[5..8]);
+                }
+            }
+            _ => {}
+        }
+        snssai
+    }
+
+    pub fn has_sd(&self) -> bool {
+        self.len >= 4
+    }
+}
 
 // #[repr(C)]
 // #[derive(Debug)]
@@ -432,6 +505,7 @@ impl OctetString {
 
 // #[repr(C)]
 #[derive(Debug)]
+#[allow(dead_code)]
 pub struct QOSFlowDescriptionsContents {
     pub qfi: u8,
     pub operationcode: u8,
@@ -440,12 +514,15 @@ pub struct QOSFlowDescriptionsContents {
     pub parameterslist: Vec<Parameter>,
 }
 #[derive(Debug)]
+#[allow(dead_code)]
 pub struct Parameter {
     pub parameter_id: u8,
     pub length_param_content: u8,
-    // pub contents: Vec<ParametersList>,
+    pub contents: Vec<u8>,
 }
 
+pub const QOS_FLOW_PARAMETER_5QI: u8 = 0x01;
+
 // #[repr(C)]
 #[derive(Debug)]
 pub struct QOSFlowDescriptions {
@@ -459,6 +536,54 @@ impl QOSFlowDescriptions {
             qosflowdescriptionscontents: vec![],
         }
     }
+
+    /**
+     * 3GPP TS 24501 9.11.4.12, `data` is the value part without IEI and length
+     */
+    pub fn decode(data: &[u8]) -> QOSFlowDescriptions {
+        let mut res = QOSFlowDescriptions::default();
+        let mut index = 0;
+        while index + 3 <= data.len() {
+            let qfi = data[index] & 0b00111111;
+            let operationcode = (data[index + 1] & 0b11100000) >> 5;
+            let e = (data[index + 2] & 0b01000000) >> 6;
+            let numberofparameters = data[index + 2] & 0b00111111;
+            index += 3;
+            let mut parameterslist = vec![];
+            for _ in 0..numberofparameters {
+                if index + 2 > data.len() {
+                    break;
+                }
+                let parameter_id = data[index];
+                let length_param_content = data[index + 1];
+                let end = (index + 2 + length_param_content as usize).min(data.len());
+                parameterslist.push(Parameter {
+                    parameter_id,
+                    length_param_content,
+                    contents: data[index + 2..end].to_vec(),
+                });
+                index = end;
+            }
+            res.qosflowdescriptionscontents.push(QOSFlowDescriptionsContents {
+                qfi,
+                operationcode,
+                numberofparameters,
+                e,
+                parameterslist,
+            });
+        }
+        res.qosflowdescriptionsnumber = res.qosflowdescriptionscontents.len() as u16;
+        res
+    }
+}
+
+impl QOSFlowDescriptionsContents {
+    pub fn get_5qi(&self) -> Option<u8> {
+        self.parameterslist
+            .iter()
+            .find(|parameter| parameter.parameter_id == QOS_FLOW_PARAMETER_5QI)
+            .and_then(|parameter| parameter.contents.first().cloned())
+    }
 }
 
 // #[repr(C)]
@@ -497,6 +622,7 @@ impl QOSFlowDescriptions {
 //     pub contents: OctetString,
 // }
 
+#[allow(clippy::upper_case_acronyms)]
 pub type DNN = OctetString;
 
 impl PduSessionEstablishmentAcceptMsg {
@@ -509,12 +635,13 @@ impl PduSessionEstablishmentAcceptMsg {
             pdusessiontype: PDUSessionType::default(),
             // sscmode: SSCMode::default(),
             // qosrules: QOSRules::default(),
-            // sessionambr: SessionAMBR::default(),
+            sessionambr: SessionAMBR::default(),
             // presence: 0,
             // _5gsmcause: _5GSMCause::default(),
             pduaddress: PDUAddress::default(),
             // gprstimer: GPRSTimer::default(),
-            // snssai: SNSSAI::default(),
+            rqtimer: None,
+            snssai: SNSSAI::default(),
             // alwaysonpdusessionindication: AlwaysonPDUSessionIndication::default(),
             // mappedepsbearercontexts: MappedEPSBearerContexts::default(),
             // eapmessage: EAPMessage::default(),
@@ -537,9 +664,9 @@ impl PduSessionEstablishmentAcceptMsg {
 
     pub fn get_dnn_name(&mut self) -> String {
         if self.dnn.length > 0 {
-            return self.dnn.dnn_to_string();
+            self.dnn.dnn_to_string()
         } else {
-            return "".to_string();
+            "".to_string()
         }
     }
 
@@ -568,7 +695,7 @@ impl PduSessionEstablishmentAcceptMsg {
         }
     }
 
-    pub fn _get_ipv6(&mut self) -> Result<IpAddr, &str> {
+    pub fn get_ipv6(&mut self) -> Result<IpAddr, &str> {
         let arr = self.pduaddress.pdu_address_information.to_bytes_u8();
         let mut _ipv6_str: String;
         let mut _ipv4_str: String;
@@ -595,25 +722,49 @@ impl PduSessionEstablishmentAcceptMsg {
         }
     }
 
+    #[allow(dead_code)]
     pub fn get_pcscf_v6_address(&mut self) -> Ipv6Addr {
-        let v6_addr = self
-            .extendedprotocolconfigurationoptions
+        self.extendedprotocolconfigurationoptions
             .get_pcscf_v6_addr()
-            .unwrap_or(Ipv6Addr::LOCALHOST);
-        v6_addr
+            .unwrap_or(Ipv6Addr::LOCALHOST)
     }
     pub fn get_dns_v6_address(&mut self) -> Option<Ipv6Addr> {
-        return self
-            .extendedprotocolconfigurationoptions
-            .get_pcscf_v6_addr();
+        self.extendedprotocolconfigurationoptions
+            .get_dns_v6_addr()
+    }
+
+    pub fn get_dns_v4_address(&self) -> Option<Ipv4Addr> {
+        self.extendedprotocolconfigurationoptions.get_dns_v4_addr()
+    }
+
+    pub fn get_mtu(&self) -> Option<u16> {
+        self.extendedprotocolconfigurationoptions.get_ipv4_link_mtu()
+    }
+
+    /**
+     * 3GPP TS 24008 10.5.7.3: GPRS timer, unit in bits 8 to 6 and value in
+     * bits 5 to 1. `None` when the RQ timer is absent or deactivated.
+     */
+    pub fn get_rq_timer(&self) -> Option<Duration> {
+        let octet = self.rqtimer?;
+        let value = (octet & 0b00011111) as u64;
+        match octet >> 5 {
+            0b000 => Some(Duration::from_secs(value * 2)),
+            0b010 => Some(Duration::from_secs(value * 360)),
+            0b111 => None,
+            // 001 and the other values are minutes
+            _ => Some(Duration::from_secs(value * 60)),
+        }
     }
 }
 
 const PDU_SESSION_ESTABLISHMENT_ACCEPT_5_GSM_CAUSE_IEI: u8 = 0x59;
 const PDU_SESSION_ESTABLISHMENT_ACCEPT_RQ_TIMER_IEI: u8 = 0x56;
+#[allow(dead_code)]
 const PDU_SESSION_ESTABLISHMENT_ACCEPT_ALWAYS_ON_IEI: u8 = 0x08;
 const PDU_SESSION_ESTABLISHMENT_ACCEPT_CP_ONLY_IEI: u8 = 0xc0;
 
+#[allow(dead_code)]
 const PDU_SESSION_ESTABLISHMENT_ACCEPT_GPRS_TIMER_IEI: u8 = 0x56;
 
 const PDU_SESSION_ESTABLISHMENT_ACCEPT_PDU_ADDRESS_IEI: u8 = 0x29;
@@ -626,50 +777,27 @@ const PDU_SESSION_ESTABLISHMENT_ACCEPT_EPCO_IEI: u8 = 0x7B;
 const PDU_SESSION_ESTABLISHMENT_ACCEPT_ATSSS_IEI: u8 = 0x77;
 const PDU_SESSION_ESTABLISHMENT_ACCEPT_DNN_IEI: u8 = 0x25;
 
+#[allow(dead_code)]
 const PDU_SESSION_ESTABLISHMENT_ACCEPT__5GSM_CAUSE_PRESENCE: u16 = 1 << 0;
+#[allow(dead_code)]
 const PDU_SESSION_ESTABLISHMENT_ACCEPT_PDU_ADDRESS_PRESENCE: u16 = 1 << 1;
+#[allow(dead_code)]
 const PDU_SESSION_ESTABLISHMENT_ACCEPT_GPRS_TIMER_PRESENCE: u16 = 1 << 2;
+#[allow(dead_code)]
 const PDU_SESSION_ESTABLISHMENT_ACCEPT_SNSSAI_PRESENCE: u16 = 1 << 3;
+#[allow(dead_code)]
 const PDU_SESSION_ESTABLISHMENT_ACCEPT_ALWAYSON_PDU_SESSION_INDICATION_PRESENCE: u16 = 1 << 4;
+#[allow(dead_code)]
 const PDU_SESSION_ESTABLISHMENT_ACCEPT_MAPPED_EPS_BEARER_CONTEXTS_PRESENCE: u16 = 1 << 5;
+#[allow(dead_code)]
 const PDU_SESSION_ESTABLISHMENT_ACCEPT_EAP_MESSAGE_PRESENCE: u16 = 1 << 6;
+#[allow(dead_code)]
 const PDU_SESSION_ESTABLISHMENT_ACCEPT_QOS_FLOW_DESCRIPTIONS_PRESENCE: u16 = 1 << 7;
+#[allow(dead_code)]
 const PDU_SESSION_ESTABLISHMENT_ACCEPT_EPCO_PRESENCE: u16 = 1 << 8;
+#[allow(dead_code)]
 const PDU_SESSION_ESTABLISHMENT_ACCEPT_DNN_PRESENCE: u16 = 1 << 9;
 
-pub const _NR_NETWORK_IF_MGMT_CREATE: u8 = 0x00;
-pub const _NR_NETWORK_IF_MGMT_UPDATE: u8 = 0x01;
-pub const _NR_NETWORK_IF_MGMT_DELETE: u8 = 0x11;
-pub const _NR_NETWORK_IF_MGMT_DESTORY: u8 = 0xff;
-
-pub fn tlv_decode_nr_network_if_mgm(data: &[u8]) -> Option<(u8, Vec<u8>)> {
-    let mut index = 0;
-    while index < data.len() {
-        let current_tag = data[index];
-        let length = data[index + 1] as usize;
-
-        if current_tag == _NR_NETWORK_IF_MGMT_CREATE {
-            let value = data[index + 2..index + 2 + length].to_vec();
-            return Some((current_tag, value));
-        }
-
-        if current_tag == _NR_NETWORK_IF_MGMT_UPDATE {
-            let value = data[index + 2..index + 2 + length].to_vec();
-            return Some((current_tag, value));
-        }
-
-        if current_tag == _NR_NETWORK_IF_MGMT_DESTORY {
-            let value = Vec::new();
-            return Some((current_tag, value));
-        }
-
-        index += 2 + length;
-    }
-
-    None
-}
-
-
 impl PduSessionEstablishmentAcceptMsg {
     /**
      * 3GPP TS 24501 8.3.2.1
@@ -680,29 +808,30 @@ impl PduSessionEstablishmentAcceptMsg {
         let mut index: usize = 0;
         let mut res: PduSessionEstablishmentAcceptMsg = PduSessionEstablishmentAcceptMsg::new();
         println!("{:?}", data);
+        let header = data.get(..5)?;
         //decode extended_protocol_discriminator
-        res.extendedprotocoldiscriminator = data[index];
+        res.extendedprotocoldiscriminator = header[index];
         index += 1;
         //decode_pdu_session_identity/scc
-        res.pdusessionidentity = data[index];
+        res.pdusessionidentity = header[index];
         index += 1;
         //decode_procedure_transaction_identity
-        res.proceduretransactionidentity = data[index];
+        res.proceduretransactionidentity = header[index];
         index += 1;
         //decode_message_type
         index += 1;
         //seleted pdu session type and seleted ssc mode are in one octet!
-        res.pdusessiontype.pdu_session_type_value = PduAddressType::from_u8(data[index] & 0b00000111);
+        res.pdusessiontype.pdu_session_type_value = PduAddressType::from_u8(header[index] & 0b00000111);
         index += 1;
         //decode_qos_rules
-        let value: u16 = (data[index] as u16) << 8 | data[index + 1] as u16;
-        res.qosrules = QOSRules::decode(data[index..].to_vec());
-        let length = value as usize;
+        let length = u16::from_be_bytes([*data.get(index)?, *data.get(index + 1)?]) as usize;
+        res.qosrules = QOSRules::decode(data.get(index..index + 2 + length)?.to_vec());
         index += 2;
         index += length;
         //decode_session_ambr
-        let length1 = data[index] as usize;
+        let length1 = *data.get(index)? as usize;
         index += 1;
+        res.sessionambr = SessionAMBR::decode(data.get(index..index + length1)?);
         index += length1;
     
         //begin TLV
@@ -724,11 +853,11 @@ impl PduSessionEstablishmentAcceptMsg {
             };
     
             if is_match == 1 {
-                let value: u16 = (data[index + 1] as u16) << 8 | data[index + 2] as u16;
+                let value = u16::from_be_bytes([*data.get(index + 1)?, *data.get(index + 2)?]);
                 length = value as usize;
                 println!("TLV-E {} index {} len {}", current_tag, index, length);
             } else if is_match == 0 {
-                length = data[index + 1] as usize;
+                length = *data.get(index + 1)? as usize;
                 println!("TLV {} index {}", current_tag, index);
             } else if is_match == 2 {
                 length = 2;
@@ -739,24 +868,32 @@ impl PduSessionEstablishmentAcceptMsg {
             }
     
             if current_tag == PDU_SESSION_ESTABLISHMENT_ACCEPT_DNN_IEI {
-                let value = data[index + 2..index + 2 + length].to_vec();
-                let _dnn_index = res.dnn.set_value(&value, 0, length);
-                // let string = std::str::from_utf8(&value).unwrap(); // 解析切片为字符串
-                res.dnn.to_string();
+                let value = data.get(index + 2..index + 2 + length)?.to_vec();
+                res.dnn.set_value(&value, 0, length);
             }
     
+            if current_tag == PDU_SESSION_ESTABLISHMENT_ACCEPT_RQ_TIMER_IEI {
+                res.rqtimer = Some(*data.get(index + 1)?);
+            }
+
+            if current_tag == PDU_SESSION_ESTABLISHMENT_ACCEPT_SNSSAI_IEI {
+                res.snssai = SNSSAI::decode(data.get(index + 2..index + 2 + length)?);
+            }
+
+            if current_tag == PDU_SESSION_ESTABLISHMENT_ACCEPT_QOS_FLOW_DESCRIPTIONS_IEI {
+                res.qosflowdescriptions = QOSFlowDescriptions::decode(data.get(index + 3..index + 3 + length)?);
+            }
+
             if current_tag == PDU_SESSION_ESTABLISHMENT_ACCEPT_EPCO_IEI {
-                let value = data[index..index + 3 + length].to_vec();
-                // let string = std::str::from_utf8(&value).unwrap(); // 解析切片为字符串
-                // print!("{:#?}\n",value);
-                res.extendedprotocolconfigurationoptions = parse_extended_pco(&value).unwrap();
+                let value = data.get(index..index + 3 + length)?;
+                res.extendedprotocolconfigurationoptions = parse_extended_pco(value)?;
             }
     
             if current_tag == PDU_SESSION_ESTABLISHMENT_ACCEPT_PDU_ADDRESS_IEI {
                 let ip_len;
     
-                let value = data[index + 2..index + 2 + length].to_vec();
-                res.pduaddress.pdu_session_type_value = PduAddressType::from_u8(value[0]);
+                let value = data.get(index + 2..index + 2 + length)?;
+                res.pduaddress.pdu_session_type_value = PduAddressType::from_u8(*value.first()?);
                 if res.pduaddress.pdu_session_type_value == PduAddressType::IPV4 {
                     ip_len = 4;
                 } else if res.pduaddress.pdu_session_type_value == PduAddressType::IPV6 {
@@ -766,38 +903,100 @@ impl PduSessionEstablishmentAcceptMsg {
                 } else {
                     ip_len = 4;
                 }
+                if value.len() < 1 + ip_len {
+                    return None;
+                }
                 res.pduaddress
                     .pdu_address_information
-                    .set_value(&value, 1, ip_len);
+                    .set_value(value, 1, ip_len);
             }
     
             if is_match == 1 {
                 index += 3 + length;
             } else if is_match == 0 {
                 index += 2 + length;
-            } else if is_match == 2 {
-                index += length;
             } else {
                 index += length;
             }
         }
-        return Some(res);
+        Some(res)
     }
     
 }
 
-// fn main() {
-//     let mut pduSessionEstablishmentAcceptMsg = tlv_decode_pdu_session_establishment_accept(vec![
-//         0x2e, 0x01, 0x01, 0xc2, 0x13, 0x00, 0x09, 0x01, 0x00, 0x06, 0x31, 0x3f, 0x01, 0x01, 0xff,
-//         0x01, 0x06, 0x06, 0x13, 0x88, 0x04, 0x7a, 0x12, 0x29, 0x0d, 0x03, 0x20, 0x01, 0x04, 0x68,
-//         0x30, 0x00, 0x00, 0x01, 0xc0, 0xa8, 0x04, 0x02, 0x22, 0x01, 0x01, 0x79, 0x00, 0x06, 0x01,
-//         0x20, 0x41, 0x01, 0x01, 0x05, 0x7b, 0x00, 0x32, 0x80, 0x80, 0x21, 0x0a, 0x03, 0x00, 0x00,
-//         0x0a, 0x81, 0x06, 0x08, 0x08, 0x08, 0x08, 0x00, 0x0d, 0x04, 0x08, 0x08, 0x08, 0x08, 0x00,
-//         0x0c, 0x04, 0xc0, 0xa8, 0x04, 0x01, 0x00, 0x01, 0x10, 0x20, 0x01, 0x04, 0x68, 0x30, 0x00,
-//         0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x11, 0x00, 0x25, 0x17,
-//         0x03, 0x69, 0x6d, 0x73, 0x06, 0x6d, 0x6e, 0x63, 0x30, 0x30, 0x31, 0x06, 0x6d, 0x63, 0x63,
-//         0x30, 0x30, 0x31, 0x04, 0x67, 0x70, 0x72, 0x73,
-//     ]);
-
-//     println!("{:#?}", pduSessionEstablishmentAcceptMsg);
-// }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // QoS rules, session AMBR, PDU address, S-NSSAI, QoS flow descriptions,
+    // extended PCO and DNN
+    const ACCEPT: [u8; 128] = [
+        0x2e, 0x01, 0x01, 0xc2, 0x13, 0x00, 0x09, 0x01, 0x00, 0x06, 0x31, 0x3f, 0x01, 0x01, 0xff,
+        0x01, 0x06, 0x06, 0x13, 0x88, 0x04, 0x7a, 0x12, 0x29, 0x0d, 0x03, 0x20, 0x01, 0x04, 0x68,
+        0x30, 0x00, 0x00, 0x01, 0xc0, 0xa8, 0x04, 0x02, 0x22, 0x01, 0x01, 0x79, 0x00, 0x06, 0x01,
+        0x20, 0x41, 0x01, 0x01, 0x05, 0x7b, 0x00, 0x32, 0x80, 0x80, 0x21, 0x0a, 0x03, 0x00, 0x00,
+        0x0a, 0x81, 0x06, 0x08, 0x08, 0x08, 0x08, 0x00, 0x0d, 0x04, 0x08, 0x08, 0x08, 0x08, 0x00,
+        0x0c, 0x04, 0xc0, 0xa8, 0x04, 0x01, 0x00, 0x01, 0x10, 0x20, 0x01, 0x04, 0x68, 0x30, 0x00,
+        0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x11, 0x00, 0x25, 0x17,
+        0x03, 0x69, 0x6d, 0x73, 0x06, 0x6d, 0x6e, 0x63, 0x30, 0x30, 0x31, 0x06, 0x6d, 0x63, 0x63,
+        0x30, 0x30, 0x31, 0x04, 0x67, 0x70, 0x72, 0x73,
+    ];
+    const AMBR_LENGTH: usize = 16;
+    const PDU_ADDRESS_IEI: usize = 23;
+    const SNSSAI_IEI: usize = 38;
+    const QOS_FLOW_DESCRIPTIONS_IEI: usize = 41;
+    const EPCO_IEI: usize = 50;
+    const DNN_IEI: usize = 103;
+
+    fn decode(data: &[u8]) -> Option<PduSessionEstablishmentAcceptMsg> {
+        PduSessionEstablishmentAcceptMsg::tlv_decode_pdu_session_establishment_accept(data.to_vec())
+    }
+
+    #[test]
+    fn decodes_accept() {
+        let accept = decode(&ACCEPT).unwrap();
+        assert_eq!(accept.pdusessionidentity, 1);
+        assert_eq!(accept.sessionambr.session_ambr_for_downlink, 0x1388);
+        assert_eq!(accept.snssai.sst, 1);
+        assert_eq!(accept.qosflowdescriptions.qosflowdescriptionscontents[0].get_5qi(), Some(5));
+        assert_eq!(accept.extendedprotocolconfigurationoptions.get_ipv4_link_mtu(), None);
+        assert_eq!(accept.dnn.to_bytes_u8(), &ACCEPT[DNN_IEI + 2..]);
+    }
+
+    #[test]
+    fn truncated_accept_is_rejected() {
+        for len in 0..ACCEPT.len() {
+            let accept = decode(&ACCEPT[..len]);
+            // Only a cut between two IEs leaves a valid message
+            if [AMBR_LENGTH + 7, PDU_ADDRESS_IEI + 15, SNSSAI_IEI + 3, QOS_FLOW_DESCRIPTIONS_IEI + 9, EPCO_IEI + 53].contains(&len) {
+                assert!(accept.is_some(), "cut at {}", len);
+            } else {
+                assert!(accept.is_none(), "cut at {}", len);
+            }
+        }
+    }
+
+    #[test]
+    fn overlong_length_is_rejected() {
+        for (offset, length) in [
+            (5, 0x40),
+            (AMBR_LENGTH, 0x70),
+            (PDU_ADDRESS_IEI + 1, 0x70),
+            (SNSSAI_IEI + 1, 0x70),
+            (QOS_FLOW_DESCRIPTIONS_IEI + 2, 0x70),
+            (EPCO_IEI + 2, 0x70),
+            (DNN_IEI + 1, 0x18),
+        ] {
+            let mut data = ACCEPT.to_vec();
+            data[offset] = length;
+            assert!(decode(&data).is_none(), "length {:#x} at {}", length, offset);
+        }
+        // A PDU address too short for its type, and PCO containers overrunning the IE
+        let mut data = ACCEPT[..PDU_ADDRESS_IEI].to_vec();
+        data.extend_from_slice(&[0x29, 0x03, 0x03, 0x20, 0x01]);
+        assert!(decode(&data).is_none());
+        let mut data = ACCEPT[..EPCO_IEI].to_vec();
+        data.extend_from_slice(&[0x7b, 0x00, 0x05, 0x80, 0x00, 0x0d, 0x04, 0x08]);
+        assert!(decode(&data).is_none());
+    }
+}
@@ -58,3 +58,48 @@ pub struct PduSessionPlainMsg {
     pub proceduretransactionidentity: ProcedureTransactionIdentity,
     pub messagetype: SessionMessageType,
 }
+
+const NAS_5GMM_EPD: u8 = 0x7e;
+const NAS_5GSM_EPD: u8 = 0x2e;
+const NAS_DL_NAS_TRANSPORT: u8 = 0x68;
+const NAS_UL_NAS_TRANSPORT: u8 = 0x67;
+const NAS_PAYLOAD_CONTAINER_N1_SM: u8 = 0x01;
+
+/**
+ * Returns the 5GSM message carried by a NAS PDU, either a plain 5GSM message or
+ * the N1 SM payload container of an UL/DL NAS TRANSPORT (3GPP TS 24501 8.2.10/8.2.11)
+ */
+pub fn n1_sm_payload(data: &[u8]) -> Option<&[u8]> {
+    match data.first() {
+        Some(&NAS_5GSM_EPD) => Some(data),
+        Some(&NAS_5GMM_EPD) => {
+            if data.len() < 6 || data[1] != 0x00 {
+                return None;
+            }
+            if data[2] != NAS_DL_NAS_TRANSPORT && data[2] != NAS_UL_NAS_TRANSPORT {
+                return None;
+            }
+            if data[3] & 0x0f != NAS_PAYLOAD_CONTAINER_N1_SM {
+                return None;
+            }
+            let length = u16::from_be_bytes([data[4], data[5]]) as usize;
+            data.get(6..6 + length)
+        }
+        _ => None,
+    }
+}
+
+impl PduSessionPlainMsg {
+    pub fn decode(data: &[u8]) -> Option<PduSessionPlainMsg> {
+        let sm = n1_sm_payload(data)?;
+        if sm.len() < 4 {
+            return None;
+        }
+        Some(PduSessionPlainMsg {
+            extendedprotocoldiscriminator: sm[0],
+            pdusessionidentity: sm[1],
+            proceduretransactionidentity: sm[2],
+            messagetype: SessionMessageType::from_u8(sm[3]),
+        })
+    }
+}
@@ -1,3 +1,7 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use crate::qos_classifier::{PacketFilterComponent, PacketFilterCtx, PacketFilterDirection};
+
 #[repr(C)]
 #[derive(Debug)]
 
@@ -14,18 +18,19 @@ pub struct QOSRulesIE {
     pub numberofpacketfilters: u8,
     pub dqrbit: u8,
     /**
-    * Rule operation code (bits 8 to 6 of octet 7)
-           Bits
-           8 7 6
-           0 0 0	Reserved
-           0 0 1	Create new QoS rule
-           0 1 0	Delete existing QoS rule
-           0 1 1	Modify existing QoS rule and add packet filters
-           1 0 0	Modify existing QoS rule and replace all packet filters
-           1 0 1	Modify existing QoS rule and delete packet filters
-           1 1 0	Modify existing QoS rule without modifying packet filters
-           1 1 1	Reserved
-    */
+     * Rule operation code (bits 8 to 6 of octet 7)
+     *
+     * | 8 7 6 |                                                          |
+     * |-------|----------------------------------------------------------|
+     * | 0 0 0 | Reserved                                                 |
+     * | 0 0 1 | Create new QoS rule                                      |
+     * | 0 1 0 | Delete existing QoS rule                                 |
+     * | 0 1 1 | Modify existing QoS rule and add packet filters          |
+     * | 1 0 0 | Modify existing QoS rule and replace all packet filters  |
+     * | 1 0 1 | Modify existing QoS rule and delete packet filters       |
+     * | 1 1 0 | Modify existing QoS rule without modifying packet filters|
+     * | 1 1 1 | Reserved                                                 |
+     */
     pub ruleoperationcode: RuleOperationCode,
     pub packetfilterlist: PacketFilterListEnum,
     pub qosruleprecedence: u8,
@@ -72,6 +77,7 @@ impl RuleOperationCode {
     }
 }
 #[derive(Debug)]
+#[allow(dead_code)]
 pub enum PacketFilterListEnum {
     PacketFilterListDeletePFList(PacketFilterListDeletePFList),
     PacketFilterListUpdatePFList(PacketFilterListUpdatePFList),
@@ -80,7 +86,7 @@ pub enum PacketFilterListEnum {
 }
 
 #[derive(Debug)]
-
+#[allow(dead_code)]
 pub struct PacketFilterSingle {
     pub packet_fliter_id: u8,
 }
@@ -102,6 +108,7 @@ pub struct PacketFilterListUpdatePFList {
 }
 
 #[derive(Debug)]
+#[allow(dead_code)]
 pub struct PacketFilterContent {
     pub packet_filter_content_type: PacketFilterComponentType,
     pub packet_filter_content_value: PacketFilterComponentValue,
@@ -182,6 +189,10 @@ impl PacketFilterComponentType {
             0b00100110 => PacketFilterComponentType::VlanStagPcpdei,
             // Ethertype = 0b00100111,
             0b00100111 => PacketFilterComponentType::Ethertype,
+            // DestinationMACAddressRange = 0b00101000,
+            0b00101000 => PacketFilterComponentType::DestinationMACAddressRange,
+            // SourceMACAddressRange = 0b00101001,
+            0b00101001 => PacketFilterComponentType::SourceMACAddressRange,
             _ => PacketFilterComponentType::Ethertype,
         }
     }
@@ -201,11 +212,15 @@ pub enum PacketFilterComponentValue {
     SingleRemotePort(Port),
     RemotePortRange(PortRange),
 
+    #[allow(dead_code)]
     SecurityParameterIndex(SecurityParameterIndex),
     TypeOfServiceTrafficClass(TypeOfServiceTrafficClass),
+    #[allow(dead_code)]
     FlowLabel(FlowLabel),
 
+    #[allow(dead_code)]
     DestinationMACAddress(MACAddress),
+    #[allow(dead_code)]
     SourceMACAddress(MACAddress),
     VlanCtagVid(VlanCtagVid),
     VlanStagVid(VlanStagVid),
@@ -266,6 +281,7 @@ pub struct PortRange {
 }
 
 #[derive(Debug)]
+#[allow(dead_code)]
 pub struct SecurityParameterIndex {
     /*
      * 对于“安全参数索引”,数据包过滤器组件值字段应编码为四个八位字节,
@@ -286,6 +302,7 @@ pub struct TypeOfServiceTrafficClass {
     mask: u8,
 }
 #[derive(Debug)]
+#[allow(dead_code)]
 pub struct FlowLabel {
     /*For "flow label type", the packet filter component value field shall be encoded as three octets which specify the IPv6 flow label. The bits 8 through 5 of the first octet shall be spare whereas the remaining 20 bits shall contain the IPv6 flow label. */
     /*
@@ -295,6 +312,7 @@ pub struct FlowLabel {
     value: u32,
 }
 #[derive(Debug)]
+#[allow(dead_code)]
 pub struct MACAddress {
     /*For "destination MAC address type" and "source MAC address type", the packet filter component value field shall be encoded as 6 octets which specify a MAC address. When the packet filter direction field indicates "bidirectional", the destination MAC address is the remote MAC address and the source MAC address is the local MAC address. */
     /*
@@ -340,18 +358,70 @@ pub struct SourceMACAddressRange {
     /*For "source MAC address range type", the packet filter component value field shall be encoded as a sequence of a 6 octet source MAC address range low limit field and a 6 octet source MAC address range high limit field. The source MAC address range low limit field shall be transmitted first. When the packet filter direction field indicates "bidirectional", the source MAC address is the local MAC address range. */
     // ...
 }
+impl PacketFilterListEnum {
+    /// Packet filters of a rule, in the form the uplink classifier takes.
+    pub fn filters(&self) -> Vec<PacketFilterCtx> {
+        match self {
+            PacketFilterListEnum::PacketFilterListUpdatePFList(list) => vec![PacketFilterCtx {
+                id: list.packet_filter_id,
+                direction: PacketFilterDirection::from_u8(list.packet_filter_direction),
+                components: list
+                    .packet_filter_content_list
+                    .iter()
+                    .map(|content| content.packet_filter_content_value.to_component())
+                    .collect(),
+            }],
+            _ => vec![],
+        }
+    }
+}
+
+fn ipv4_of(octets: &[u8]) -> Option<Ipv4Addr> {
+    <[u8; 4]>::try_from(octets).ok().map(Ipv4Addr::from)
+}
+
+fn ipv6_of(octets: &[u8]) -> Option<Ipv6Addr> {
+    <[u8; 16]>::try_from(octets).ok().map(Ipv6Addr::from)
+}
+
+impl PacketFilterComponentValue {
+    pub fn to_component(&self) -> PacketFilterComponent {
+        let component = match self {
+            PacketFilterComponentValue::MatchAll => Some(PacketFilterComponent::MatchAll),
+            PacketFilterComponentValue::IPv4RemoteAddress(filter) => ipv4_of(&filter.ipv4_address)
+                .zip(ipv4_of(&filter.ipv4_address_mask))
+                .map(|(address, mask)| PacketFilterComponent::RemoteIpv4 { address, mask }),
+            PacketFilterComponentValue::IPv4LocalAddress(filter) => ipv4_of(&filter.ipv4_address)
+                .zip(ipv4_of(&filter.ipv4_address_mask))
+                .map(|(address, mask)| PacketFilterComponent::LocalIpv4 { address, mask }),
+            PacketFilterComponentValue::IPv6RemoteAddressPrefixLength(filter) => ipv6_of(&filter.ipv6_address)
+                .map(|address| PacketFilterComponent::RemoteIpv6 { address, prefix_len: filter.prefix_length }),
+            PacketFilterComponentValue::IPv6LocalAddressPrefixLength(filter) => ipv6_of(&filter.ipv6_address)
+                .map(|address| PacketFilterComponent::LocalIpv6 { address, prefix_len: filter.prefix_length }),
+            PacketFilterComponentValue::ProtocolIdentifierNextHeader(protocol) => Some(PacketFilterComponent::Protocol(protocol.value)),
+            PacketFilterComponentValue::SingleLocalPort(port) => Some(PacketFilterComponent::LocalPorts { low: port.value, high: port.value }),
+            PacketFilterComponentValue::LocalPortRange(range) => Some(PacketFilterComponent::LocalPorts { low: range.low, high: range.high }),
+            PacketFilterComponentValue::SingleRemotePort(port) => Some(PacketFilterComponent::RemotePorts { low: port.value, high: port.value }),
+            PacketFilterComponentValue::RemotePortRange(range) => Some(PacketFilterComponent::RemotePorts { low: range.low, high: range.high }),
+            PacketFilterComponentValue::TypeOfServiceTrafficClass(tos) => Some(PacketFilterComponent::TypeOfService { value: tos.value, mask: tos.mask }),
+            _ => None,
+        };
+        component.unwrap_or(PacketFilterComponent::Unsupported)
+    }
+}
+
 impl QOSRules {
     pub fn decode(data: Vec<u8>) -> QOSRules {
         let mut index = 0;
         let length: u16 = (data[index] as u16) << 8 | data[index + 1] as u16;
-        let mut qosRulesIEList: Vec<QOSRulesIE> = vec![];
+        let mut qos_rules_ie_list: Vec<QOSRulesIE> = vec![];
         index += 2; //decoder header
         while index < length.into() {
             // { qosruleidentifer: val, LengthofQoSrule: val, numberofpacketfilters: val, dqrbit: val, ruleoperationcode: val,
             // packetfilterlist: val, qosruleprecedence: val, qosflowidentifer: val, segregation: val, spare: val }
             let qosruleidentifer: u8 = data[index];
             index += 1;
-            let LengthofQoSrule: u16 = (data[index] as u16) << 8 | data[index + 1] as u16;
+            let lengthof_qo_srule: u16 = (data[index] as u16) << 8 | data[index + 1] as u16;
             index += 2;
             // octet 7
             let numberofpacketfilters = data[index] & 0b00001111;
@@ -366,16 +436,16 @@ impl QOSRules {
                     //For the "modify existing QoS rule and delete packet filters" operation,
                     //the packet filter list shall contain a variable number of packet filter
                     //identifiers. This number shall be derived from the coding of the number of packet filters field in octet 7
-                    let mut packetFilterListDeletePF = PacketFilterListDeletePFList {
+                    let mut packet_filter_list_delete_pf = PacketFilterListDeletePFList {
                         packet_fliter_id: vec![],
                     };
-                    for i in index..(index + numberofpacketfilters as usize) {
-                        packetFilterListDeletePF
+                    for _ in 0..numberofpacketfilters {
+                        packet_filter_list_delete_pf
                             .packet_fliter_id
                             .push(data[index] & 0b00001111);
                         index += 1;
                     }
-                    PacketFilterListEnum::PacketFilterListDeletePFList(packetFilterListDeletePF)
+                    PacketFilterListEnum::PacketFilterListDeletePFList(packet_filter_list_delete_pf)
                 }
                 RuleOperationCode::DeleteExistingQosRule | RuleOperationCode::ModifyExistingQosRuleWithoutModifyPackerFilters => {
                     //Delete existing QoS rule | modify existing QoS rule without modifying packet filters
@@ -400,7 +470,8 @@ impl QOSRules {
                     //let mut packetFilterUpdatePF =
                     //PacketFilterListUpdatePFList { packet_filter_direction, packet_filter_id, length_packet_filter_contents, packet_filter_content_list: todo!() };
                     let mut packet_filter_content_list = Vec::<PacketFilterContent>::new();
-                    for i in index..(index + length_packet_filter_contents as usize) {
+                    let contents_end = index + length_packet_filter_contents as usize;
+                    while index < contents_end {
                         let filter_content_type = PacketFilterComponentType::from_u8(data[index]);
                         index += 1;
                         let filter_content_value: PacketFilterComponentValue =
@@ -409,39 +480,39 @@ impl QOSRules {
                                     PacketFilterComponentValue::MatchAll
                                 }
                                 PacketFilterComponentType::IPv4RemoteAddress => {
-                                    let ipv4Address = IPv4FilterAddress {
-                                        ipv4_address: data[index..index + 3].to_vec(),
-                                        ipv4_address_mask: data[index + 4..index + 7].to_vec(),
+                                    let ipv4_address_filter = IPv4FilterAddress {
+                                        ipv4_address: data[index..index + 4].to_vec(),
+                                        ipv4_address_mask: data[index + 4..index + 8].to_vec(),
                                     };
                                     index += 8;
-                                    PacketFilterComponentValue::IPv4RemoteAddress(ipv4Address)
+                                    PacketFilterComponentValue::IPv4RemoteAddress(ipv4_address_filter)
                                 }
                                 PacketFilterComponentType::IPv4LocalAddress => {
-                                    let ipv4Address = IPv4FilterAddress {
-                                        ipv4_address: data[index..index + 3].to_vec(),
-                                        ipv4_address_mask: data[index + 4..index + 7].to_vec(),
+                                    let ipv4_address_filter = IPv4FilterAddress {
+                                        ipv4_address: data[index..index + 4].to_vec(),
+                                        ipv4_address_mask: data[index + 4..index + 8].to_vec(),
                                     };
                                     index += 8;
-                                    PacketFilterComponentValue::IPv4LocalAddress(ipv4Address)
+                                    PacketFilterComponentValue::IPv4LocalAddress(ipv4_address_filter)
                                 }
                                 PacketFilterComponentType::IPv6RemoteAddressPrefixLength => {
-                                    let ipv6Address = IPv6FilterAddress {
-                                        ipv6_address: data[index..index + 15].to_vec(),
+                                    let ipv6_address_filter = IPv6FilterAddress {
+                                        ipv6_address: data[index..index + 16].to_vec(),
                                         prefix_length: data[index + 16],
                                     };
                                     index += 17;
                                     PacketFilterComponentValue::IPv6RemoteAddressPrefixLength(
-                                        ipv6Address,
+                                        ipv6_address_filter,
                                     )
                                 }
                                 PacketFilterComponentType::IPv6LocalAddressPrefixLength => {
-                                    let ipv6Address = IPv6FilterAddress {
-                                        ipv6_address: data[index..index + 15].to_vec(),
+                                    let ipv6_address_filter = IPv6FilterAddress {
+                                        ipv6_address: data[index..index + 16].to_vec(),
                                         prefix_length: data[index + 16],
                                     };
                                     index += 17;
                                     PacketFilterComponentValue::IPv6LocalAddressPrefixLength(
-                                        ipv6Address,
+                                        ipv6_address_filter,
                                     )
                                 }
                                 PacketFilterComponentType::ProtocolIdentifierNextHeader => {
@@ -498,10 +569,9 @@ impl QOSRules {
                                     )
                                 }
                                 PacketFilterComponentType::TypeOfServiceTrafficClass => {
+                                    let tos = TypeOfServiceTrafficClass { value: data[index], mask: data[index + 1] };
                                     index += 2;
-                                    PacketFilterComponentValue::TypeOfServiceTrafficClass(
-                                        TypeOfServiceTrafficClass { value: 0, mask: 0 },
-                                    )
+                                    PacketFilterComponentValue::TypeOfServiceTrafficClass(tos)
                                 }
                                 PacketFilterComponentType::FlowLabel => {
                                     index += 3;
@@ -559,17 +629,16 @@ impl QOSRules {
                     }
                     PacketFilterListEnum::PacketFilterListUpdatePFList(
                         PacketFilterListUpdatePFList {
-                            packet_filter_direction: packet_filter_direction,
-                            packet_filter_id: packet_filter_id,
-                            length_packet_filter_contents: length_packet_filter_contents,
-                            packet_filter_content_list: packet_filter_content_list,
+                            packet_filter_direction,
+                            packet_filter_id,
+                            length_packet_filter_contents,
+                            packet_filter_content_list,
                         },
                     )
                 }
 
                 _ => PacketFilterListEnum::PacketFilterNone,
             };
-                 println!("{:#?}",index);
 
             let qosruleprecedence = data[index];
             index += 1;
@@ -579,7 +648,7 @@ impl QOSRules {
             index += 1;
             let q_osrules_ie = QOSRulesIE {
                 qosruleidentifer,
-                lengthof_qo_srule: LengthofQoSrule,
+                lengthof_qo_srule,
                 numberofpacketfilters,
                 dqrbit,
                 ruleoperationcode,
@@ -589,13 +658,41 @@ impl QOSRules {
                 segregation,
                 spare,
             };
-            qosRulesIEList.push(q_osrules_ie);
+            qos_rules_ie_list.push(q_osrules_ie);
         }
-        
-        println!("{:#?}",length);
+
         QOSRules {
             lengthofqosrulesie: length,
-            qosrulesie: qosRulesIEList,
+            qosrulesie: qos_rules_ie_list,
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mac_address_range_is_skipped_as_a_whole() {
+        let rules = [
+            0x00, 0x1e,
+            // Rule 1, create with one packet filter
+            0x01, 0x00, 0x1b, 0x31,
+            // Bidirectional filter 1: destination MAC range, then IPv4 remote 10.0.0.1/32
+            0x31, 0x16,
+            0x28, 0x02, 0x00, 0x00, 0x00, 0x00, 0x01, 0x02, 0x00, 0x00, 0x00, 0x00, 0xff,
+            0x09, 0x0a, 0x00, 0x00, 0x01, 0xff, 0xff, 0xff, 0xff,
+            // Precedence and QFI
+            0xff, 0x01,
+        ];
+        let rules = QOSRules::decode(rules.to_vec());
+        assert_eq!(rules.qosrulesie.len(), 1);
+        assert_eq!(rules.qosrulesie[0].qosruleprecedence, 0xff);
+        assert_eq!(rules.qosrulesie[0].qosflowidentifer, 1);
+        let filters = rules.qosrulesie[0].packetfilterlist.filters();
+        assert_eq!(filters[0].components, vec![
+            PacketFilterComponent::Unsupported,
+            PacketFilterComponent::RemoteIpv4 { address: Ipv4Addr::new(10, 0, 0, 1), mask: Ipv4Addr::BROADCAST },
+        ]);
+    }
+}
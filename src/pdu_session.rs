@@ -1,59 +1,547 @@
-use crate::{msg, pdu_helper::{pdu_helper::{ExtendedProtocolDiscriminator, PDUSessionIdentity, ProcedureTransactionIdentity, SessionMessageType}, pdu_accept::{PDUSessionType, SSCMode, PDUAddress, QOSFlowDescriptions, ExtProtoCfgOpts, DNN}, qos_rules::QOSRules}};
+use crate::{config, itti::{Itti, IttiError, Mailbox}, msg, pdu_helper::{pdu_helper::{PDUSessionIdentity, SessionMessageType, PduSessionPlainMsg, n1_sm_payload}, pdu_accept::PduSessionEstablishmentAcceptMsg}, qos_classifier::{classify_uplink, DerivedQosRules, DEFAULT_RQ_TIMER}, session_store::{GtpTunnelCtx, PduSessionContext, SessionStore}, timer::{Clock, SystemClock, TimerId, TimerQueue}, tunnel_table::{FTeid, TunnelTable}};
 
 
-use std::{sync::{Arc, atomic::{AtomicBool, Ordering}}, thread};
+use std::{collections::HashMap, net::IpAddr, sync::Arc, thread::{self, JoinHandle}, time::Duration};
 
-use crossbeam::channel::{Receiver, Sender, unbounded};
-use msg::IttiMsg;
+use crossbeam::channel::{Receiver, Sender, SendError, unbounded, RecvTimeoutError};
+use msg::{GtpDirection, GtpErrorIndication, GtpPathEvent, GtpPathEventKind, GtpTunnelCfg, IttiHeader, IttiMsg, IttiTaskId, IttiTrxTag, PduSessionQuery, PduSessionQueryKind, PduSessionQueryResponse, PlainNAS5GSMessage, TunSessionCfg, UdpGtpBuffer, GsmProcedure, GsmProcedureReq, GsmProcedurePdu, GsmProcedureTimeout};
 
 
+impl GsmProcedure {
+    /**
+     * 3GPP TS 24501 10.3, Table 10.3.2: T3580/T3581/T3582 are all 16s and the
+     * procedure is aborted on the fifth expiry.
+     */
+    pub const TIMER_VALUE: Duration = Duration::from_secs(16);
+    pub const MAX_EXPIRIES: u8 = 5;
+
+    pub fn timer_name(&self) -> &'static str {
+        match self {
+            GsmProcedure::Establishment => "T3580",
+            GsmProcedure::Modification => "T3581",
+            GsmProcedure::Release => "T3582",
+        }
+    }
+
+    pub fn is_answered_by(&self, messagetype: &SessionMessageType) -> bool {
+        match self {
+            GsmProcedure::Establishment => matches!(messagetype,
+                SessionMessageType::EstablishmentAccept | SessionMessageType::EstablishmentReject),
+            GsmProcedure::Modification => matches!(messagetype,
+                SessionMessageType::ModificationCommand | SessionMessageType::ModificationReject),
+            GsmProcedure::Release => matches!(messagetype,
+                SessionMessageType::ReleaseCommand | SessionMessageType::ReleaseReject),
+        }
+    }
+}
+
+struct PendingGsmProcedure {
+    req: GsmProcedureReq,
+    origin: IttiHeader,
+    expiries: u8,
+    timer: TimerId,
+}
+
+/// UE-requested 5GSM procedures waiting for a network answer, keyed by PTI.
+pub struct GsmProcedureTimers {
+    clock: Arc<dyn Clock>,
+    timers: TimerQueue<u8>,
+    pending: HashMap<u8, PendingGsmProcedure>,
+}
+
+impl GsmProcedureTimers {
+    pub fn new(clock: Arc<dyn Clock>) -> GsmProcedureTimers {
+        GsmProcedureTimers {
+            clock,
+            timers: TimerQueue::new(),
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Sends the request back to the requester for transmission and arms its
+    /// timer. A request reusing a PTI that is still pending replaces the old one.
+    pub fn start(&mut self, itti: &Itti, task: IttiTaskId, origin: IttiHeader, req: GsmProcedureReq) {
+        self.stop(req.pti);
+        let timer = self.timers.insert(self.clock.now() + GsmProcedure::TIMER_VALUE, req.pti);
+        Self::transmit(itti, task, &origin, &req, 1);
+        self.pending.insert(req.pti, PendingGsmProcedure { req, origin, expiries: 0, timer });
+    }
+
+    pub fn stop(&mut self, pti: u8) -> Option<GsmProcedureReq> {
+        let pending = self.pending.remove(&pti)?;
+        self.timers.cancel(pending.timer);
+        Some(pending.req)
+    }
+
+    /// Stops the timer of the procedure the given network message answers.
+    pub fn on_response(&mut self, header: &PduSessionPlainMsg) {
+        let answered = match self.pending.get(&header.proceduretransactionidentity) {
+            Some(pending) => pending.req.procedure.is_answered_by(&header.messagetype),
+            None => false,
+        };
+        if answered {
+            if let Some(req) = self.stop(header.proceduretransactionidentity) {
+                println!("{} stopped for pti {}", req.procedure.timer_name(), req.pti);
+            }
+        }
+    }
+
+    pub fn next_timeout(&mut self) -> Option<Duration> {
+        self.timers.next_timeout(self.clock.now())
+    }
+
+    /// Retransmits every procedure whose timer expired, or aborts it with a
+    /// timeout back to the requester once the retransmission limit is reached.
+    pub fn poll(&mut self, itti: &Itti, task: IttiTaskId) {
+        let now = self.clock.now();
+        for (_, pti) in self.timers.pop_expired(now) {
+            let mut pending = match self.pending.remove(&pti) {
+                Some(pending) => pending,
+                None => continue,
+            };
+            pending.expiries += 1;
+            let procedure = pending.req.procedure;
+            if pending.expiries >= GsmProcedure::MAX_EXPIRIES {
+                println!("{} expired {} times, abort pti {}", procedure.timer_name(), pending.expiries, pti);
+                let timeout = IttiMsg::PduSessionMgmtGsmProcedureTimeout(GsmProcedureTimeout {
+                    procedure,
+                    pdu_session_id: pending.req.pdu_session_id,
+                    pti,
+                });
+                if let Err(e) = itti.reply(&pending.origin, task, timeout) {
+                    println!("{} timeout not delivered: {}", procedure.timer_name(), e);
+                }
+                continue;
+            }
+            println!("{} expired, retransmit pti {}", procedure.timer_name(), pti);
+            Self::transmit(itti, task, &pending.origin, &pending.req, pending.expiries + 1);
+            pending.timer = self.timers.insert(now + GsmProcedure::TIMER_VALUE, pti);
+            self.pending.insert(pti, pending);
+        }
+    }
+
+    fn transmit(itti: &Itti, task: IttiTaskId, origin: &IttiHeader, req: &GsmProcedureReq, attempt: u8) {
+        let transmit = IttiMsg::PduSessionMgmtGsmTransmit(GsmProcedurePdu {
+            procedure: req.procedure,
+            pdu_session_id: req.pdu_session_id,
+            pti: req.pti,
+            nas_pdu: req.nas_pdu.clone(),
+            attempt,
+        });
+        if let Err(e) = itti.reply(origin, task, transmit) {
+            println!("{} transmission not delivered: {}", req.procedure.timer_name(), e);
+        }
+    }
+}
+
+/// Asks the session managers a query, every instance for a query over all
+/// sessions, and merges their answers.
+pub fn query_sessions(itti: &Itti, kind: PduSessionQueryKind, timeout: Duration) -> Result<PduSessionQueryResponse, IttiError> {
+    let none = match kind {
+        PduSessionQueryKind::ListAll => PduSessionQueryResponse::Sessions(vec![]),
+        PduSessionQueryKind::ById(_) | PduSessionQueryKind::ByUeIp(_) => PduSessionQueryResponse::Session(None),
+        PduSessionQueryKind::QosFlows(_) => PduSessionQueryResponse::QosFlows(None),
+    };
+    let replies = itti.call(IttiTrxTag::PduSessionMgmt, IttiMsg::PduSessionMgmtQuery(PduSessionQuery { kind }), timeout)?;
+    Ok(replies.into_iter().fold(none, |merged, reply| match reply.msg {
+        IttiMsg::PduSessionMgmtQueryResponse(response) => merged.merge(response),
+        _ => merged,
+    }))
+}
+
 pub struct  PduSessionMgmt {
-    pub pdu_sessions: Vec<PduSession>,
-    pub trx: (Sender<i32>,Receiver<i32>)
+    pub pdu_sessions: HashMap<PDUSessionIdentity, PduSession>,
+    pub gsm_timers: GsmProcedureTimers,
+    // Also runs the RQ timers of the sessions
+    clock: Arc<dyn Clock>,
+    store: Option<SessionStore>,
+    // Loaded by `restore`, spawned once the task runs
+    restored: Vec<PduSessionContext>,
+    // Local TEIDs of the sessions, shared with the GTP-U task
+    tunnels: Arc<TunnelTable>,
+    // Set once the task runs, handed to the session actors for their uplink
+    itti: Option<(Arc<Itti>, IttiTaskId)>
 }
 
 impl PduSessionMgmt {
     pub fn default() -> PduSessionMgmt {
+        PduSessionMgmt::with_clock(Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(clock: Arc<dyn Clock>) -> PduSessionMgmt {
         PduSessionMgmt { 
-            pdu_sessions: vec![],
-            trx: unbounded()
+            pdu_sessions: HashMap::new(),
+            gsm_timers: GsmProcedureTimers::new(clock.clone()),
+            clock,
+            store: None,
+            restored: vec![],
+            tunnels: Arc::new(TunnelTable::default()),
+            itti: None
          }
     }
 
-    pub fn init_pdu_session_mgmt_task(mut self,itti_msg_queue: (Sender<IttiMsg>,Receiver<IttiMsg>)) {
+    /// Shares the tunnel table with the GTP-U task and the other instances,
+    /// which must not allocate the same TEIDs.
+    pub fn with_tunnel_table(mut self, tunnels: Arc<TunnelTable>) -> PduSessionMgmt {
+        self.tunnels = tunnels;
+        self
+    }
+
+    /// Loads the sessions found in the snapshot, re-created when the task
+    /// starts, and keeps it up to date from now on.
+    pub fn restore(&mut self, store: SessionStore) {
+        match store.load() {
+            Ok(contexts) => {
+                for ctx in contexts {
+                    println!("pdu session {} restored, dnn {}", ctx.pdu_session_id, ctx.dnn);
+                    self.restored.push(ctx);
+                }
+            },
+            Err(e) => {
+                println!("pdu session snapshot not restored: {}", e);
+            },
+        }
+        self.store = Some(store);
+    }
+
+    /// Re-creates the sessions loaded by `restore`. They keep their TEIDs,
+    /// snapshots from before the allocation only have the one of the tunnel.
+    /// A TEID bound meanwhile stays with its owner and the session comes back
+    /// without a tunnel.
+    fn spawn_restored(&mut self) {
+        for mut ctx in std::mem::take(&mut self.restored) {
+            ctx.local_teid = ctx.local_teid.or(ctx.tunnel.as_ref().map(|tunnel| tunnel.local_teid));
+            if let Some(local_teid) = ctx.local_teid {
+                let remote = ctx.tunnel.as_ref().map(|tunnel| FTeid { teid: tunnel.remote_teid, addr: tunnel.remote_addr });
+                if !self.tunnels.bind(local_teid, ctx.pdu_session_id, ctx.qfis(), remote) {
+                    println!("pdu session {} restored without its tunnel, teid {:#x} is bound already", ctx.pdu_session_id, local_teid);
+                    ctx.local_teid = None;
+                    ctx.tunnel = None;
+                }
+            }
+            self.tun_session_up(&ctx);
+            self.pdu_sessions.insert(ctx.pdu_session_id, PduSession::spawn(ctx, self.clock.clone(), self.itti.clone()));
+        }
+    }
+
+    fn persist(&self) {
+        if let Some(store) = &self.store {
+            if let Err(e) = store.save(self.pdu_sessions.values().map(|pdu_session| &pdu_session.ctx)) {
+                println!("pdu session snapshot not saved: {}", e);
+            }
+        }
+    }
+
+    /// Keeps the TUN data path, when there is one, in step with the sessions.
+    fn notify_tun(&self, msg: IttiMsg) {
+        if let Some((itti, task)) = &self.itti {
+            if itti.instances(IttiTrxTag::Tun) == 0 {
+                return;
+            }
+            if let Err(e) = itti.send_from(*task, IttiTrxTag::Tun, msg) {
+                println!("tun data path not updated: {}", e);
+            }
+        }
+    }
+
+    fn tun_session_up(&self, ctx: &PduSessionContext) {
+        self.notify_tun(IttiMsg::TunSessionUp(TunSessionCfg {
+            pdu_session_id: ctx.pdu_session_id,
+            ipv4: ctx.ipv4,
+            ipv6: ctx.ipv6,
+            dns: ctx.dns.clone(),
+            mtu: ctx.mtu,
+        }));
+    }
+
+    /// Points the local TEID of the session at the signalled remote F-TEID.
+    /// The tunnel table keeps a replaced remote until the End Marker of the
+    /// old path.
+    fn setup_tunnel(&mut self, cfg: GtpTunnelCfg) {
+        let pdu_session = match self.pdu_sessions.get_mut(&cfg.pdu_session_id) {
+            Some(pdu_session) => pdu_session,
+            None => {
+                println!("tunnel for unknown pdu session {} ignored", cfg.pdu_session_id);
+                return;
+            },
+        };
+        let local_teid = match pdu_session.ctx.local_teid {
+            Some(local_teid) => local_teid,
+            None => {
+                println!("pdu session {} has no local teid, tunnel ignored", cfg.pdu_session_id);
+                return;
+            },
+        };
+        self.tunnels.set_remote(local_teid, FTeid { teid: cfg.remote_teid, addr: cfg.remote_addr });
+        let tunnel = GtpTunnelCtx { local_teid, remote_teid: cfg.remote_teid, remote_addr: cfg.remote_addr };
+        println!("pdu session {} teid {:#x} -> {} teid {:#x}", cfg.pdu_session_id, local_teid, cfg.remote_addr, cfg.remote_teid);
+        pdu_session.ctx.tunnel = Some(tunnel.clone());
+        let _ = pdu_session.send(PduSessionCmd::Tunnel(Some(tunnel)));
+        self.persist();
+    }
+
+    /// The peer lost the context of the tunnel, so the session stops sending
+    /// on it until a new one is set up.
+    fn on_gtp_error_indication(&mut self, indication: GtpErrorIndication) {
+        let pdu_session = match self.pdu_sessions.get_mut(&indication.pdu_session_id) {
+            Some(pdu_session) => pdu_session,
+            None => return,
+        };
+        let matches = pdu_session.ctx.tunnel.as_ref().is_some_and(|tunnel| {
+            tunnel.remote_teid == indication.remote_teid && tunnel.remote_addr == indication.peer
+        });
+        if !matches {
+            return;
+        }
+        println!("pdu session {} tunnel released, error indication from {} for teid {:#x}",
+            indication.pdu_session_id, indication.peer, indication.remote_teid);
+        if let Some(tunnel) = pdu_session.ctx.tunnel.take() {
+            self.tunnels.clear_remote(tunnel.local_teid);
+        }
+        let _ = pdu_session.send(PduSessionCmd::Tunnel(None));
+        self.persist();
+    }
+
+    /// Sessions tunnelled to a peer that went down are flagged until it comes
+    /// back. A restarted peer has lost their tunnels, so they are released.
+    fn on_gtp_path_event(&mut self, event: GtpPathEvent) {
+        let on_peer: Vec<PDUSessionIdentity> = self.pdu_sessions
+            .values()
+            .filter(|pdu_session| pdu_session.ctx.tunnel.as_ref().is_some_and(|tunnel| tunnel.remote_addr == event.peer))
+            .map(|pdu_session| pdu_session.pdu_id)
+            .collect();
+        for pdu_id in on_peer {
+            match event.kind {
+                GtpPathEventKind::Down | GtpPathEventKind::Up => {
+                    if let Some(pdu_session) = self.pdu_sessions.get_mut(&pdu_id) {
+                        pdu_session.ctx.path_down = event.kind == GtpPathEventKind::Down;
+                        println!("pdu session {} path to {} {:?}", pdu_id, event.peer, event.kind);
+                    }
+                },
+                GtpPathEventKind::Restarted => {
+                    println!("pdu session {} released, peer {} restarted", pdu_id, event.peer);
+                    self.release_pdu_session(pdu_id);
+                },
+            }
+        }
+    }
+
+    pub fn query(&self, kind: &PduSessionQueryKind) -> PduSessionQueryResponse {
+        match kind {
+            PduSessionQueryKind::ListAll => {
+                let mut sessions: Vec<PduSessionContext> = self.pdu_sessions.values().map(|pdu_session| pdu_session.ctx.clone()).collect();
+                sessions.sort_by_key(|ctx| ctx.pdu_session_id);
+                PduSessionQueryResponse::Sessions(sessions)
+            },
+            PduSessionQueryKind::ById(pdu_id) => {
+                PduSessionQueryResponse::Session(self.pdu_sessions.get(pdu_id).map(|pdu_session| pdu_session.ctx.clone()))
+            },
+            PduSessionQueryKind::ByUeIp(ue_ip) => {
+                let found = self.pdu_sessions.values().find(|pdu_session| match ue_ip {
+                    IpAddr::V4(ipv4) => pdu_session.ctx.ipv4 == Some(*ipv4),
+                    // Only the interface identifier is signalled, the prefix comes from router advertisements
+                    IpAddr::V6(ipv6) => pdu_session.ctx.ipv6.is_some_and(|iid| u128::from(iid) as u64 == u128::from(*ipv6) as u64),
+                });
+                PduSessionQueryResponse::Session(found.map(|pdu_session| pdu_session.ctx.clone()))
+            },
+            PduSessionQueryKind::QosFlows(pdu_id) => {
+                PduSessionQueryResponse::QosFlows(self.pdu_sessions.get(pdu_id).map(|pdu_session| pdu_session.ctx.qos_flows.clone()))
+            },
+        }
+    }
+
+    fn establish_pdu_session(&mut self, plain_nas5_gsmessage: &PlainNAS5GSMessage) {
+        let sm = match n1_sm_payload(&plain_nas5_gsmessage.sdu) {
+            Some(sm) => sm.to_vec(),
+            None => return,
+        };
+        let mut accept = match PduSessionEstablishmentAcceptMsg::tlv_decode_pdu_session_establishment_accept(sm) {
+            Some(accept) => accept,
+            None => return,
+        };
+        let mut ctx = PduSessionContext::from_accept(&mut accept);
+        // A new accept for an existing identity replaces the old session
+        self.release_pdu_session(ctx.pdu_session_id);
+        ctx.local_teid = self.tunnels.allocate(ctx.pdu_session_id, ctx.qfis());
+        match ctx.local_teid {
+            Some(local_teid) => println!("pdu session {} local teid {:#x}", ctx.pdu_session_id, local_teid),
+            None => println!("pdu session {} has no local teid, every teid is taken", ctx.pdu_session_id),
+        }
+        self.tun_session_up(&ctx);
+        self.pdu_sessions.insert(ctx.pdu_session_id, PduSession::spawn(ctx, self.clock.clone(), self.itti.clone()));
+        self.persist();
+    }
+
+    /// Stops the 5GSM timer answered by the message and returns its header.
+    fn on_gsm_message(&mut self, plain_nas5_gsmessage: &msg::PlainNAS5GSMessage) -> Option<PduSessionPlainMsg> {
+        let header = PduSessionPlainMsg::decode(&plain_nas5_gsmessage.sdu)?;
+        self.gsm_timers.on_response(&header);
+        Some(header)
+    }
+
+    pub fn release_pdu_session(&mut self, pdu_id: PDUSessionIdentity) {
+        if let Some(mut pdu_session) = self.pdu_sessions.remove(&pdu_id) {
+            let _ = pdu_session.send(PduSessionCmd::Release);
+            pdu_session.join();
+            if let Some(local_teid) = pdu_session.ctx.local_teid {
+                self.tunnels.release(local_teid);
+            }
+            self.notify_tun(IttiMsg::TunSessionDown(pdu_id));
+            self.persist();
+        }
+    }
+
+    /// Stops every session actor without touching the snapshot, so the
+    /// sessions come back on the next start.
+    pub fn release_all(&mut self) {
+        for (_, mut pdu_session) in self.pdu_sessions.drain() {
+            let _ = pdu_session.send(PduSessionCmd::Release);
+            pdu_session.join();
+        }
+    }
+
+    pub fn init_pdu_session_mgmt_task(mut self,itti: Arc<Itti>,mailbox: Mailbox) {
+        self.itti = Some((itti.clone(), mailbox.id));
+        self.spawn_restored();
         loop {
-            match  itti_msg_queue.1.recv() {
-                Ok(msg) => {
-                    match msg {
+            let received = match self.gsm_timers.next_timeout() {
+                Some(timeout) => mailbox.recv_timeout(timeout),
+                None => mailbox.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            match  received {
+                Ok(envelope) => {
+                    match envelope.msg {
                         IttiMsg::PduSessionMgmtCreatePduSession(plain_nas5_gsmessage) => {
-                            self.pdu_sessions.push(PduSession::default(self.trx.clone()));
-                            let a = &self.pdu_sessions[0];
-                            println!("PduSessionMgmtCreatePduSession {}",plain_nas5_gsmessage.data);
-                            a.run(self.trx.clone());
+                            if config::debug_enabled() {
+                                println!("PduSessionMgmtCreatePduSession {}",plain_nas5_gsmessage.data);
+                            }
+                            match self.on_gsm_message(&plain_nas5_gsmessage) {
+                                Some(header) if header.messagetype == SessionMessageType::EstablishmentAccept => {
+                                    self.establish_pdu_session(&plain_nas5_gsmessage);
+                                },
+                                Some(_) => {},
+                                None => {
+                                    println!("PduSessionMgmtCreatePduSession without 5GSM header, ignored");
+                                },
+                            }
                         },
                         IttiMsg::PduSessionMgmtModifiyPduSession(plain_nas5_gsmessage) => {
-                            
+                            if let Some(header) = self.on_gsm_message(&plain_nas5_gsmessage) {
+                                if let Some(pdu_session) = self.pdu_sessions.get(&header.pdusessionidentity) {
+                                    let _ = pdu_session.send(PduSessionCmd::Modify(plain_nas5_gsmessage));
+                                }
+                            }
                         },
                         IttiMsg::PduSessionMgmtDestoryPduSession(plain_nas5_gsmessage) => {
-
-                            self.pdu_sessions.push(PduSession::default(self.trx.clone()));
-                        let a = &self.pdu_sessions[0];
-                        a.destory(self.trx.clone());
-                        drop(a);
+                            if let Some(header) = self.on_gsm_message(&plain_nas5_gsmessage) {
+                                self.release_pdu_session(header.pdusessionidentity);
+                            }
+                        },
+                        IttiMsg::PduSessionMgmtGsmProcedureStart(req) => {
+                            println!("{} started for pti {}", req.procedure.timer_name(), req.pti);
+                            self.gsm_timers.start(&itti, mailbox.id, envelope.header, req);
+                        },
+                        IttiMsg::PduSessionMgmtQuery(query) => {
+                            let response = IttiMsg::PduSessionMgmtQueryResponse(self.query(&query.kind));
+                            if let Err(e) = itti.reply(&envelope.header, mailbox.id, response) {
+                                println!("PduSessionMgmtQuery: {}", e);
+                            }
                         },
-                        _ => {println!("{:#?}", msg);},
+                        IttiMsg::PduSessionMgmtTunnelSetup(cfg) => {
+                            self.setup_tunnel(cfg);
+                        },
+                        IttiMsg::PduSessionMgmtGtpPathEvent(event) => {
+                            self.on_gtp_path_event(event);
+                        },
+                        IttiMsg::PduSessionMgmtGtpErrorIndication(indication) => {
+                            self.on_gtp_error_indication(indication);
+                        },
+                        IttiMsg::GtpUdpRecvFromRemoteThenToPduSessoin(udp_gtp_buffer)
+                        | IttiMsg::PduSessionMgmtSendUplink(udp_gtp_buffer) => {
+                            match self.pdu_sessions.get(&(udp_gtp_buffer.pdu_session_id as PDUSessionIdentity)) {
+                                Some(pdu_session) => {
+                                    let _ = pdu_session.send(PduSessionCmd::DataPath(udp_gtp_buffer));
+                                },
+                                None => {
+                                    println!("G-PDU for unknown pdu session {} dropped", udp_gtp_buffer.pdu_session_id);
+                                },
+                            }
+                        },
+                        IttiMsg::PduSessionMgmtStopThread => {
+                            self.release_all();
+                            break;
+                        },
+                        msg => {println!("{:#?}", msg);},
                     }
                 },
-                Err(_) => {
-                    
+                Err(RecvTimeoutError::Timeout) => {},
+                Err(RecvTimeoutError::Disconnected) => {
+                    self.release_all();
+                    break;
                 },
             }
-            
+            self.gsm_timers.poll(&itti, mailbox.id);
         }
     }
 }
 
+/// Commands a `PduSession` actor accepts on its own mailbox.
+#[derive(Debug)]
+pub enum PduSessionCmd {
+    Modify(PlainNAS5GSMessage),
+    Release,
+    DataPath(UdpGtpBuffer),
+    Tunnel(Option<GtpTunnelCtx>),
+}
+
+/// Handle held by the manager; the session state lives in the actor thread.
 pub struct  PduSession {
+    pub pdu_id: PDUSessionIdentity,
+    pub ctx: PduSessionContext,
+    mailbox: Sender<PduSessionCmd>,
+    handle: Option<JoinHandle<()>>
+}
+
+
+impl PduSession {
+    pub fn spawn(ctx: PduSessionContext, clock: Arc<dyn Clock>, itti: Option<(Arc<Itti>, IttiTaskId)>) -> PduSession {
+        let pdu_id = ctx.pdu_session_id;
+        let (mailbox, commands) = unbounded::<PduSessionCmd>();
+        let actor = PduSessionActor {
+            pdu_id,
+            derived_qos_rules: DerivedQosRules::new(clock, ctx.rq_timer.unwrap_or(DEFAULT_RQ_TIMER)),
+            ctx: ctx.clone(),
+            commands,
+            itti,
+        };
+        let handle = thread::Builder::new()
+            .name(format!("pdu-session-{}", pdu_id))
+            .spawn(move || actor.run())
+            .expect("failed to spawn pdu session thread");
+        PduSession {
+            pdu_id,
+            ctx,
+            mailbox,
+            handle: Some(handle)
+        }
+    }
+
+    pub fn send(&self, cmd: PduSessionCmd) -> Result<(), SendError<PduSessionCmd>> {
+        self.mailbox.send(cmd)
+    }
+
+    /// Waits for the actor to exit. It only does so after `Release` or once
+    /// every handle to its mailbox has been dropped.
+    pub fn join(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                println!("pdu session {} panicked", self.pdu_id);
+            }
+        }
+    }
+}
+
+struct PduSessionActor {
     // pub extendedprotocoldiscriminator: ExtendedProtocolDiscriminator,
     // pub pdusessionidentity: PDUSessionIdentity,
     // pub proceduretransactionidentity: ProcedureTransactionIdentity,
@@ -73,59 +561,381 @@ pub struct  PduSession {
     // pub qosflowdescriptions: QOSFlowDescriptions,
     // pub extendedprotocolconfigurationoptions: ExtProtoCfgOpts,
     // pub dnn: DNN,
-    pub pdu_id: i32,
-    pub qos_rules: i32,
-    pub trx: (Sender<i32>,Receiver<i32>)
+    pdu_id: PDUSessionIdentity,
+    ctx: PduSessionContext,
+    commands: Receiver<PduSessionCmd>,
+    // Task the uplink is sent from
+    itti: Option<(Arc<Itti>, IttiTaskId)>,
+    derived_qos_rules: DerivedQosRules
 }
 
+impl PduSessionActor {
+    fn run(mut self) {
+        println!("pdu session {} running {:?}", self.pdu_id, self.ctx);
+        loop {
+            let received = match self.derived_qos_rules.next_timeout() {
+                Some(timeout) => self.commands.recv_timeout(timeout),
+                None => self.commands.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            self.expire_derived_qos_rules();
+            let cmd = match received {
+                Ok(cmd) => cmd,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            match cmd {
+                PduSessionCmd::Modify(plain_nas5_gsmessage) => {
+                    println!("pdu session {} modify {}", self.pdu_id, plain_nas5_gsmessage.data);
+                },
+                PduSessionCmd::DataPath(udp_gtp_buffer) => {
+                    match udp_gtp_buffer.direction {
+                        GtpDirection::Uplink => self.send_uplink(udp_gtp_buffer),
+                        GtpDirection::Downlink => self.on_downlink(udp_gtp_buffer),
+                    }
+                },
+                PduSessionCmd::Tunnel(tunnel) => {
+                    self.ctx.tunnel = tunnel;
+                },
+                PduSessionCmd::Release => {
+                    break;
+                },
+            }
+        }
+        println!("pdu session {} destoryed", self.pdu_id);
+    }
 
-impl PduSession {
-    pub fn default(trx: (Sender<i32>,Receiver<i32>)) -> PduSession {
-        PduSession {
-            pdu_id: 1,
-            qos_rules: 1,
-            trx : trx.clone()
-        }
-    }
-    
-    pub fn run(&self, trx: (Sender<i32>,Receiver<i32>)) {
-        let running = Arc::new(AtomicBool::new(true));
-        let running1 = running.clone();
-        let running2 = running.clone();
-        let _running3 = running.clone();
-        thread::spawn(move || {
-            loop {
-                match trx.1.recv() {
-                    Ok(i) => {
-                        if i == 1 {
-                            running1.store(false, Ordering::Relaxed);
-                            println!("destoryed");
-
-                        }
-                    },
-                    Err(_) => {
-                        
-                    },
+    /// Sends an uplink packet down the tunnel, on the QoS flow of the first
+    /// QoS rule it matches.
+    fn send_uplink(&self, mut udp_gtp_buffer: UdpGtpBuffer) {
+        let (itti, task) = match &self.itti {
+            Some(itti) => itti,
+            None => return,
+        };
+        let tunnel = match &self.ctx.tunnel {
+            Some(tunnel) => tunnel,
+            None => {
+                println!("pdu session {} has no tunnel, uplink dropped", self.pdu_id);
+                return;
+            },
+        };
+        let rules = self.ctx.qos_rules.iter().chain(self.derived_qos_rules.rules());
+        let qfi = match classify_uplink(rules, &udp_gtp_buffer.payload) {
+            Some(qfi) => qfi,
+            None => {
+                println!("pdu session {} uplink matches no qos rule, dropped", self.pdu_id);
+                return;
+            },
+        };
+        udp_gtp_buffer.teid = tunnel.local_teid;
+        udp_gtp_buffer.qfi = Some(qfi);
+        if let Err(e) = itti.send_from(*task, IttiTrxTag::GtpUdp, IttiMsg::GtpUdpSendToRemote(udp_gtp_buffer)) {
+            println!("pdu session {} uplink dropped: {}", self.pdu_id, e);
+        }
+    }
+
+    fn on_downlink(&mut self, udp_gtp_buffer: UdpGtpBuffer) {
+        if let Some(qfi) = udp_gtp_buffer.qfi {
+            if udp_gtp_buffer.rqi {
+                if let Some(rule) = self.derived_qos_rules.on_downlink(&udp_gtp_buffer.payload, qfi) {
+                    println!("pdu session {} derived qos rule on qfi {}: {:?}", self.pdu_id, qfi, rule.filters);
                 }
             }
+        }
+        let (itti, task) = match &self.itti {
+            Some(itti) => itti,
+            None => return,
+        };
+        if itti.instances(IttiTrxTag::Tun) == 0 {
+            println!("pdu session {} data path {:?}", self.pdu_id, udp_gtp_buffer);
+            return;
+        }
+        if let Err(e) = itti.send_from(*task, IttiTrxTag::Tun, IttiMsg::TunSendDownlink(udp_gtp_buffer)) {
+            println!("pdu session {} downlink dropped: {}", self.pdu_id, e);
+        }
+    }
+
+    fn expire_derived_qos_rules(&mut self) {
+        for rule in self.derived_qos_rules.expire() {
+            println!("pdu session {} derived qos rule on qfi {} expired, {} left", self.pdu_id, rule.qfi, self.derived_qos_rules.len());
+        }
+    }
+}
+
+impl Drop for PduSession {
+    fn drop(&mut self) {
+        let _ = self.mailbox.send(PduSessionCmd::Release);
+        self.join();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use crate::{session_store::QosRuleCtx, timer::MockClock};
+
+    const REQUESTER: IttiTaskId = IttiTaskId { tag: IttiTrxTag::NasDecoer, instance: 0 };
+    const MGMT: IttiTaskId = IttiTaskId { tag: IttiTrxTag::PduSessionMgmt, instance: 0 };
+
+    fn start(mgmt: &mut PduSessionMgmt, itti: &Itti, procedure: GsmProcedure, pti: u8) {
+        let req = GsmProcedureReq { procedure, pdu_session_id: 1, pti, nas_pdu: vec![0x2e, 1, pti] };
+        let origin = itti.envelope(Some(REQUESTER), Some(MGMT), IttiMsg::PduSessionMgmtGsmProcedureStart(req.clone())).header;
+        mgmt.gsm_timers.start(itti, MGMT, origin, req);
+    }
+
+    fn received(mailbox: &Mailbox) -> Option<IttiMsg> {
+        mailbox.recv_timeout(Duration::from_millis(10)).ok().map(|envelope| envelope.msg)
+    }
+
+    fn assert_transmit(mailbox: &Mailbox, procedure: GsmProcedure, pti: u8, attempt: u8) {
+        match received(mailbox) {
+            Some(IttiMsg::PduSessionMgmtGsmTransmit(pdu)) => {
+                assert_eq!((pdu.procedure, pdu.pti, pdu.attempt), (procedure, pti, attempt));
+                assert_eq!(pdu.nas_pdu, vec![0x2e, 1, pti]);
+            },
+            msg => panic!("expected transmission {} of {:?}, got {:?}", attempt, procedure, msg),
+        }
+    }
+
+    #[test]
+    fn gsm_procedures_retransmit_then_abort_on_fifth_expiry() {
+        for (pti, procedure) in [GsmProcedure::Establishment, GsmProcedure::Modification, GsmProcedure::Release].into_iter().enumerate() {
+            let pti = pti as u8 + 1;
+            let itti = Itti::new();
+            let mailbox = itti.register(REQUESTER);
+            let clock = Arc::new(MockClock::new());
+            let mut mgmt = PduSessionMgmt::with_clock(clock.clone());
+
+            start(&mut mgmt, &itti, procedure, pti);
+            assert_transmit(&mailbox, procedure, pti, 1);
+            assert_eq!(mgmt.gsm_timers.next_timeout(), Some(GsmProcedure::TIMER_VALUE));
+
+            for attempt in 2..=GsmProcedure::MAX_EXPIRIES {
+                clock.advance(GsmProcedure::TIMER_VALUE - Duration::from_secs(1));
+                mgmt.gsm_timers.poll(&itti, MGMT);
+                assert!(received(&mailbox).is_none(), "{} fired early", procedure.timer_name());
+                clock.advance(Duration::from_secs(1));
+                mgmt.gsm_timers.poll(&itti, MGMT);
+                assert_transmit(&mailbox, procedure, pti, attempt);
+            }
+
+            clock.advance(GsmProcedure::TIMER_VALUE);
+            mgmt.gsm_timers.poll(&itti, MGMT);
+            match received(&mailbox) {
+                Some(IttiMsg::PduSessionMgmtGsmProcedureTimeout(timeout)) => {
+                    assert_eq!((timeout.procedure, timeout.pdu_session_id, timeout.pti), (procedure, 1, pti));
+                },
+                msg => panic!("expected {} timeout, got {:?}", procedure.timer_name(), msg),
+            }
+            assert_eq!(mgmt.gsm_timers.next_timeout(), None);
+            clock.advance(GsmProcedure::TIMER_VALUE);
+            mgmt.gsm_timers.poll(&itti, MGMT);
+            assert!(received(&mailbox).is_none());
+        }
+    }
+
+    #[test]
+    fn gsm_procedure_answer_stops_timer() {
+        let itti = Itti::new();
+        let mailbox = itti.register(REQUESTER);
+        let clock = Arc::new(MockClock::new());
+        let mut mgmt = PduSessionMgmt::with_clock(clock.clone());
+
+        start(&mut mgmt, &itti, GsmProcedure::Establishment, 7);
+        assert_transmit(&mailbox, GsmProcedure::Establishment, 7, 1);
+        // An answer to another procedure leaves T3580 running
+        mgmt.gsm_timers.on_response(&PduSessionPlainMsg {
+            extendedprotocoldiscriminator: 0x2e,
+            pdusessionidentity: 1,
+            proceduretransactionidentity: 7,
+            messagetype: SessionMessageType::ReleaseCommand,
         });
-        thread::spawn(move || {
-                while running2.load(Ordering::Relaxed) {
-                    // println!("{:#?}",RUNNING2);
-                }
-                println!("destoryed");
-            });
-        // thread::spawn(move || {
-        //         while RUNNING3.load(Ordering::Relaxed) {
-        //             // println!("{}",running_flag);
-        //         }
-        //         println!("destoryed");
-        //     });
-        
+        assert!(mgmt.gsm_timers.next_timeout().is_some());
+        mgmt.gsm_timers.on_response(&PduSessionPlainMsg {
+            extendedprotocoldiscriminator: 0x2e,
+            pdusessionidentity: 1,
+            proceduretransactionidentity: 7,
+            messagetype: SessionMessageType::EstablishmentAccept,
+        });
+        assert_eq!(mgmt.gsm_timers.next_timeout(), None);
+        clock.advance(GsmProcedure::TIMER_VALUE * 2);
+        mgmt.gsm_timers.poll(&itti, MGMT);
+        assert!(received(&mailbox).is_none());
+    }
+
+    fn session(pdu_session_id: u8) -> PduSessionContext {
+        serde_json::from_value(serde_json::json!({
+            "pdu_session_id": pdu_session_id,
+            "dnn": "internet",
+            "ipv4": format!("10.45.0.{}", pdu_session_id),
+            "ipv6": format!("::{}", pdu_session_id),
+            "qos_rules": [],
+            "qos_flows": [],
+        }))
+        .unwrap()
+    }
+
+    fn session_ids(response: PduSessionQueryResponse) -> Vec<u8> {
+        match response {
+            PduSessionQueryResponse::Sessions(sessions) => sessions.iter().map(|ctx| ctx.pdu_session_id).collect(),
+            PduSessionQueryResponse::Session(session) => session.iter().map(|ctx| ctx.pdu_session_id).collect(),
+            response => panic!("unexpected {:?}", response),
+        }
+    }
+
+    #[test]
+    fn queries_are_answered_by_the_instances_owning_the_sessions() {
+        let itti = Arc::new(Itti::new());
+        // Session 3 + i lives on instance i
+        let managers: Vec<JoinHandle<()>> = (0..3)
+            .map(|instance| {
+                let mut mgmt = PduSessionMgmt::default();
+                let ctx = session(3 + instance as u8);
+                mgmt.pdu_sessions.insert(ctx.pdu_session_id, PduSession::spawn(ctx, Arc::new(SystemClock), None));
+                let mailbox = itti.register(IttiTaskId::new(IttiTrxTag::PduSessionMgmt, instance));
+                let itti = itti.clone();
+                thread::spawn(move || mgmt.init_pdu_session_mgmt_task(itti, mailbox))
+            })
+            .collect();
+        let timeout = Duration::from_secs(5);
+        let ids_of = |kind| session_ids(query_sessions(&itti, kind, timeout).unwrap());
+        assert_eq!(ids_of(PduSessionQueryKind::ListAll), vec![3, 4, 5]);
+        assert_eq!(ids_of(PduSessionQueryKind::ByUeIp("10.45.0.4".parse().unwrap())), vec![4]);
+        assert_eq!(ids_of(PduSessionQueryKind::ByUeIp("10.45.0.9".parse().unwrap())), Vec::<u8>::new());
+        assert_eq!(ids_of(PduSessionQueryKind::ByUeIp("2001:db8:1:2::5".parse().unwrap())), vec![5]);
+        assert_eq!(ids_of(PduSessionQueryKind::ByUeIp("2001:db8:1:2::9".parse().unwrap())), Vec::<u8>::new());
+        assert_eq!(ids_of(PduSessionQueryKind::ById(5)), vec![5]);
+        assert_eq!(ids_of(PduSessionQueryKind::ById(8)), Vec::<u8>::new());
+        assert!(matches!(query_sessions(&itti, PduSessionQueryKind::QosFlows(3), timeout), Ok(PduSessionQueryResponse::QosFlows(Some(_)))));
+        for instance in 0..3 {
+            itti.send_to(IttiTaskId::new(IttiTrxTag::PduSessionMgmt, instance), IttiMsg::PduSessionMgmtStopThread).unwrap();
+        }
+        for manager in managers {
+            manager.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn restored_session_does_not_take_over_a_bound_teid() {
+        let tunnels = Arc::new(TunnelTable::default());
+        // Allocated by another instance after startup
+        let taken = tunnels.allocate(1, vec![9]).unwrap();
+        let mut mgmt = PduSessionMgmt::default().with_tunnel_table(tunnels.clone());
+        let mut ctx = session(1);
+        ctx.local_teid = Some(taken);
+        mgmt.restored.push(ctx);
+        mgmt.spawn_restored();
+        assert_eq!(tunnels.lookup(taken, |entry| entry.qfis.clone()), Some(vec![9]));
+        assert_eq!(mgmt.pdu_sessions[&1].ctx.local_teid, None);
+        // Releasing it leaves the other tunnel alone
+        mgmt.release_pdu_session(1);
+        assert!(tunnels.lookup(taken, |_| ()).is_some());
     }
 
-    pub fn destory(&self, trx: (Sender<i32>,Receiver<i32>)) {
-        trx.0.send(1);
+    #[test]
+    fn restore_recreates_sessions_and_rebinds_their_teids() {
+        let dir = std::env::temp_dir().join(format!("pdu_session-{}-restore", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let remote = FTeid { teid: 0x5678, addr: IpAddr::from([192, 0, 2, 1]) };
+        let mut tunnelled = session(1);
+        tunnelled.local_teid = Some(0x1001);
+        tunnelled.tunnel = Some(GtpTunnelCtx { local_teid: 0x1001, remote_teid: remote.teid, remote_addr: remote.addr });
+        // Allocated but the tunnel not set up yet
+        let mut allocated = session(2);
+        allocated.local_teid = Some(0x1002);
+        // Saved before local TEIDs were kept apart from the tunnel
+        let mut legacy = session(3);
+        legacy.tunnel = Some(GtpTunnelCtx { local_teid: 0x1003, remote_teid: remote.teid + 1, remote_addr: remote.addr });
+        let path = dir.join("pdu_sessions.json");
+        SessionStore::new(&path).save([tunnelled, allocated, legacy].iter()).unwrap();
+
+        let tunnels = Arc::new(TunnelTable::default());
+        let mut mgmt = PduSessionMgmt::default().with_tunnel_table(tunnels.clone());
+        mgmt.restore(SessionStore::new(&path));
+        assert!(mgmt.pdu_sessions.is_empty());
+        mgmt.spawn_restored();
+        let mut ids: Vec<u8> = mgmt.pdu_sessions.keys().copied().collect();
+        ids.sort_unstable();
+        assert_eq!(ids, vec![1, 2, 3]);
+        assert_eq!(tunnels.lookup(0x1001, |entry| (entry.pdu_session_id, entry.remote)), Some((1, Some(remote))));
+        assert_eq!(tunnels.lookup(0x1002, |entry| (entry.pdu_session_id, entry.remote)), Some((2, None)));
+        assert_eq!(tunnels.lookup(0x1003, |entry| entry.pdu_session_id), Some(3));
+        assert_eq!(mgmt.pdu_sessions[&3].ctx.local_teid, Some(0x1003));
+        // Released sessions leave the snapshot and unbind their TEIDs
+        mgmt.release_pdu_session(1);
+        assert!(tunnels.lookup(0x1001, |_| ()).is_none());
+        let mut saved: Vec<u8> = SessionStore::new(&path).load().unwrap().iter().map(|ctx| ctx.pdu_session_id).collect();
+        saved.sort_unstable();
+        assert_eq!(saved, vec![2, 3]);
+        mgmt.release_all();
+        std::fs::remove_dir_all(dir).unwrap();
     }
 
-}
\ No newline at end of file
+    fn uplink(payload: &'static [u8]) -> PduSessionCmd {
+        PduSessionCmd::DataPath(UdpGtpBuffer {
+            teid: 0,
+            pdu_session_id: 1,
+            qfi: None,
+            rqi: false,
+            peer: None,
+            direction: GtpDirection::Uplink,
+            payload: Bytes::from_static(payload),
+        })
+    }
+
+    fn uplink_sent(gtp_udp: &Mailbox) -> Option<UdpGtpBuffer> {
+        match received(gtp_udp) {
+            Some(IttiMsg::GtpUdpSendToRemote(buffer)) => Some(buffer),
+            None => None,
+            msg => panic!("expected uplink, got {:?}", msg),
+        }
+    }
+
+    /// The actor thread exits by itself, or the test fails instead of hanging.
+    fn assert_exits(pdu_session: &mut PduSession) {
+        let handle = pdu_session.handle.as_ref().unwrap();
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while !handle.is_finished() {
+            assert!(std::time::Instant::now() < deadline, "pdu session {} did not exit", pdu_session.pdu_id);
+            thread::sleep(Duration::from_millis(1));
+        }
+        pdu_session.join();
+        assert!(pdu_session.handle.is_none());
+    }
+
+    #[test]
+    fn session_actor_runs_commands_in_order_until_released() {
+        let itti = Arc::new(Itti::new());
+        let gtp_udp = itti.register(IttiTaskId::new(IttiTrxTag::GtpUdp, 0));
+        let mut ctx = session(1);
+        ctx.qos_rules.push(QosRuleCtx { qos_rule_id: 1, precedence: 255, qfi: 9, segregation: false, packet_filters: 0, default_rule: true, filters: vec![] });
+        let mut pdu_session = PduSession::spawn(ctx, Arc::new(MockClock::new()), Some((itti.clone(), MGMT)));
+        // Queued before the actor gets to any of them: the first uplink
+        // comes before the tunnel and is dropped, the second one is not
+        pdu_session.send(uplink(b"first")).unwrap();
+        pdu_session.send(PduSessionCmd::Modify(PlainNAS5GSMessage { data: serde_json::json!({}), sdu: vec![] })).unwrap();
+        pdu_session.send(PduSessionCmd::Tunnel(Some(GtpTunnelCtx { local_teid: 0x1001, remote_teid: 0x5678, remote_addr: IpAddr::from([192, 0, 2, 1]) }))).unwrap();
+        pdu_session.send(uplink(b"second")).unwrap();
+        pdu_session.send(PduSessionCmd::Tunnel(None)).unwrap();
+        pdu_session.send(uplink(b"third")).unwrap();
+        pdu_session.send(PduSessionCmd::Release).unwrap();
+        // Anything after the release is never handled, if the actor is still
+        // there to take it at all
+        let _ = pdu_session.send(PduSessionCmd::Tunnel(Some(GtpTunnelCtx { local_teid: 0x1002, remote_teid: 0x5678, remote_addr: IpAddr::from([192, 0, 2, 1]) })));
+        let _ = pdu_session.send(uplink(b"fourth"));
+        assert_exits(&mut pdu_session);
+        let sent = uplink_sent(&gtp_udp).unwrap();
+        assert_eq!((sent.teid, sent.qfi, &sent.payload[..]), (0x1001, Some(9), &b"second"[..]));
+        assert!(uplink_sent(&gtp_udp).is_none());
+        assert!(pdu_session.send(PduSessionCmd::Release).is_err());
+    }
+
+    #[test]
+    fn session_actor_exits_when_its_last_handle_is_dropped() {
+        let mut pdu_session = PduSession::spawn(session(1), Arc::new(MockClock::new()), None);
+        // Drops the only sender without the Release that dropping the handle sends
+        pdu_session.mailbox = unbounded().0;
+        assert_exits(&mut pdu_session);
+    }
+}
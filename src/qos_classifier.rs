@@ -0,0 +1,414 @@
+use std::{collections::HashMap, net::{IpAddr, Ipv4Addr, Ipv6Addr}, sync::Arc, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{msg::GtpDirection, session_store::QosRuleCtx, timer::{Clock, TimerId, TimerQueue}};
+
+const IP_PROTO_TCP: u8 = 6;
+const IP_PROTO_UDP: u8 = 17;
+const IP_PROTO_SCTP: u8 = 132;
+
+/**
+ * 3GPP TS 24501 6.2.5.1.4.2: UE derived QoS rules take precedence value 80.
+ */
+pub const DERIVED_QOS_RULE_PRECEDENCE: u8 = 80;
+/// RQ timer of a session whose accept did not carry one.
+pub const DEFAULT_RQ_TIMER: Duration = Duration::from_secs(60);
+
+/**
+ * 3GPP TS 24501 9.11.4.13: packet filter direction, bits 6 and 5.
+ * 00 is only used by pre-release 7 TFT filters and is matched both ways.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PacketFilterDirection {
+    Downlink,
+    Uplink,
+    Bidirectional,
+}
+
+impl PacketFilterDirection {
+    pub fn from_u8(direction: u8) -> PacketFilterDirection {
+        match direction {
+            0b01 => PacketFilterDirection::Downlink,
+            0b10 => PacketFilterDirection::Uplink,
+            _ => PacketFilterDirection::Bidirectional,
+        }
+    }
+
+    fn applies_to(&self, direction: GtpDirection) -> bool {
+        match self {
+            PacketFilterDirection::Downlink => direction == GtpDirection::Downlink,
+            PacketFilterDirection::Uplink => direction == GtpDirection::Uplink,
+            PacketFilterDirection::Bidirectional => true,
+        }
+    }
+}
+
+/// Packet filter components the classifier evaluates. Local is the UE side
+/// of the packet, remote the data network side.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PacketFilterComponent {
+    MatchAll,
+    RemoteIpv4 { address: Ipv4Addr, mask: Ipv4Addr },
+    LocalIpv4 { address: Ipv4Addr, mask: Ipv4Addr },
+    RemoteIpv6 { address: Ipv6Addr, prefix_len: u8 },
+    LocalIpv6 { address: Ipv6Addr, prefix_len: u8 },
+    Protocol(u8),
+    LocalPorts { low: u16, high: u16 },
+    RemotePorts { low: u16, high: u16 },
+    TypeOfService { value: u8, mask: u8 },
+    // Decoded but not evaluated, so the filter never matches
+    Unsupported,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PacketFilterCtx {
+    pub id: u8,
+    pub direction: PacketFilterDirection,
+    pub components: Vec<PacketFilterComponent>,
+}
+
+/// Header fields of an IP packet the packet filters look at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FiveTuple {
+    pub src: IpAddr,
+    pub dst: IpAddr,
+    pub protocol: u8,
+    pub src_port: Option<u16>,
+    pub dst_port: Option<u16>,
+    pub tos: u8,
+}
+
+impl FiveTuple {
+    /// Parses an IPv4 or IPv6 packet. IPv6 extension headers are not walked,
+    /// so the protocol is the first next header.
+    pub fn parse(packet: &[u8]) -> Option<FiveTuple> {
+        let (src, dst, protocol, tos, transport) = match packet.first()? >> 4 {
+            4 => {
+                let header_len = (packet[0] & 0x0f) as usize * 4;
+                if header_len < 20 || packet.len() < header_len {
+                    return None;
+                }
+                let src = Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]);
+                let dst = Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]);
+                // Only the first fragment carries the transport header
+                let fragment_offset = u16::from_be_bytes([packet[6], packet[7]]) & 0x1fff;
+                let transport = if fragment_offset == 0 { &packet[header_len..] } else { &[][..] };
+                (IpAddr::V4(src), IpAddr::V4(dst), packet[9], packet[1], transport)
+            },
+            6 => {
+                if packet.len() < 40 {
+                    return None;
+                }
+                let src: [u8; 16] = packet[8..24].try_into().ok()?;
+                let dst: [u8; 16] = packet[24..40].try_into().ok()?;
+                let tos = (packet[0] << 4) | (packet[1] >> 4);
+                (IpAddr::V6(Ipv6Addr::from(src)), IpAddr::V6(Ipv6Addr::from(dst)), packet[6], tos, &packet[40..])
+            },
+            _ => return None,
+        };
+        let (src_port, dst_port) = match protocol {
+            IP_PROTO_TCP | IP_PROTO_UDP | IP_PROTO_SCTP if transport.len() >= 4 => (
+                Some(u16::from_be_bytes([transport[0], transport[1]])),
+                Some(u16::from_be_bytes([transport[2], transport[3]])),
+            ),
+            _ => (None, None),
+        };
+        Some(FiveTuple { src, dst, protocol, src_port, dst_port, tos })
+    }
+
+    /// UE side and data network side of the packet.
+    fn local_remote(&self, direction: GtpDirection) -> ((IpAddr, Option<u16>), (IpAddr, Option<u16>)) {
+        match direction {
+            GtpDirection::Uplink => ((self.src, self.src_port), (self.dst, self.dst_port)),
+            GtpDirection::Downlink => ((self.dst, self.dst_port), (self.src, self.src_port)),
+        }
+    }
+}
+
+fn ipv4_matches(address: &IpAddr, filter: &Ipv4Addr, mask: &Ipv4Addr) -> bool {
+    match address {
+        IpAddr::V4(address) => u32::from(*address) & u32::from(*mask) == u32::from(*filter) & u32::from(*mask),
+        IpAddr::V6(_) => false,
+    }
+}
+
+fn ipv6_matches(address: &IpAddr, filter: &Ipv6Addr, prefix_len: u8) -> bool {
+    match address {
+        IpAddr::V6(address) => {
+            let mask = u128::MAX.checked_shl(128 - prefix_len.min(128) as u32).unwrap_or(0);
+            u128::from(*address) & mask == u128::from(*filter) & mask
+        },
+        IpAddr::V4(_) => false,
+    }
+}
+
+fn port_matches(port: Option<u16>, low: u16, high: u16) -> bool {
+    port.is_some_and(|port| low <= port && port <= high)
+}
+
+impl PacketFilterCtx {
+    /// A packet matches a filter when it matches every component of it.
+    pub fn matches(&self, packet: &FiveTuple, direction: GtpDirection) -> bool {
+        if !self.direction.applies_to(direction) {
+            return false;
+        }
+        let ((local, local_port), (remote, remote_port)) = packet.local_remote(direction);
+        self.components.iter().all(|component| match component {
+            PacketFilterComponent::MatchAll => true,
+            PacketFilterComponent::RemoteIpv4 { address, mask } => ipv4_matches(&remote, address, mask),
+            PacketFilterComponent::LocalIpv4 { address, mask } => ipv4_matches(&local, address, mask),
+            PacketFilterComponent::RemoteIpv6 { address, prefix_len } => ipv6_matches(&remote, address, *prefix_len),
+            PacketFilterComponent::LocalIpv6 { address, prefix_len } => ipv6_matches(&local, address, *prefix_len),
+            PacketFilterComponent::Protocol(protocol) => packet.protocol == *protocol,
+            PacketFilterComponent::LocalPorts { low, high } => port_matches(local_port, *low, *high),
+            PacketFilterComponent::RemotePorts { low, high } => port_matches(remote_port, *low, *high),
+            PacketFilterComponent::TypeOfService { value, mask } => packet.tos & mask == value & mask,
+            PacketFilterComponent::Unsupported => false,
+        })
+    }
+}
+
+/**
+ * 3GPP TS 24501 6.2.5.1.1.2: QoS rules are evaluated in increasing order of
+ * their precedence value; a packet matching none of them is sent on the
+ * flow of the default QoS rule.
+ */
+pub fn classify_uplink<'a>(rules: impl IntoIterator<Item = &'a QosRuleCtx>, packet: &[u8]) -> Option<u8> {
+    let mut rules: Vec<&QosRuleCtx> = rules.into_iter().collect();
+    rules.sort_by_key(|rule| rule.precedence);
+    let default_qfi = rules.iter().find(|rule| rule.default_rule).map(|rule| rule.qfi);
+    let packet = match FiveTuple::parse(packet) {
+        Some(packet) => packet,
+        None => return default_qfi,
+    };
+    rules
+        .iter()
+        .find(|rule| rule.filters.iter().any(|filter| filter.matches(&packet, GtpDirection::Uplink)))
+        .map(|rule| rule.qfi)
+        .or(default_qfi)
+}
+
+struct DerivedQosRule {
+    rule: QosRuleCtx,
+    timer: TimerId,
+}
+
+/**
+ * 3GPP TS 24501 6.2.5.1.4: reflective QoS. A downlink packet with the RQI
+ * set creates, or restarts the RQ timer of, a rule matching the uplink
+ * replies of its flow on the QFI it came on. The rule is deleted when its RQ
+ * timer expires.
+ */
+pub struct DerivedQosRules {
+    clock: Arc<dyn Clock>,
+    rq_timer: Duration,
+    // Keyed by the downlink packet, without its type of service
+    rules: HashMap<FiveTuple, DerivedQosRule>,
+    timers: TimerQueue<FiveTuple>,
+}
+
+impl DerivedQosRules {
+    pub fn new(clock: Arc<dyn Clock>, rq_timer: Duration) -> DerivedQosRules {
+        DerivedQosRules {
+            clock,
+            rq_timer,
+            rules: HashMap::new(),
+            timers: TimerQueue::new(),
+        }
+    }
+
+    /// Returns the rule when it was created or moved to another QFI.
+    pub fn on_downlink(&mut self, packet: &[u8], qfi: u8) -> Option<&QosRuleCtx> {
+        let key = FiveTuple { tos: 0, ..FiveTuple::parse(packet)? };
+        let timer = self.timers.insert(self.clock.now() + self.rq_timer, key);
+        let changed = match self.rules.get_mut(&key) {
+            Some(derived) => {
+                self.timers.cancel(derived.timer);
+                derived.timer = timer;
+                let changed = derived.rule.qfi != qfi;
+                derived.rule.qfi = qfi;
+                changed
+            },
+            None => {
+                self.rules.insert(key, DerivedQosRule { rule: derived_rule(&key, qfi), timer });
+                true
+            },
+        };
+        match changed {
+            true => self.rules.get(&key).map(|derived| &derived.rule),
+            false => None,
+        }
+    }
+
+    pub fn rules(&self) -> impl Iterator<Item = &QosRuleCtx> {
+        self.rules.values().map(|derived| &derived.rule)
+    }
+
+    pub fn next_timeout(&mut self) -> Option<Duration> {
+        self.timers.next_timeout(self.clock.now())
+    }
+
+    /// Deletes the rules whose RQ timer expired and returns them.
+    pub fn expire(&mut self) -> Vec<QosRuleCtx> {
+        let now = self.clock.now();
+        self.timers
+            .pop_expired(now)
+            .into_iter()
+            .filter_map(|(_, key)| self.rules.remove(&key))
+            .map(|derived| derived.rule)
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+}
+
+/// Uplink rule of a downlink packet: its destination is the local side.
+fn derived_rule(downlink: &FiveTuple, qfi: u8) -> QosRuleCtx {
+    let address = |address: IpAddr, local: bool| match (address, local) {
+        (IpAddr::V4(address), true) => PacketFilterComponent::LocalIpv4 { address, mask: Ipv4Addr::BROADCAST },
+        (IpAddr::V4(address), false) => PacketFilterComponent::RemoteIpv4 { address, mask: Ipv4Addr::BROADCAST },
+        (IpAddr::V6(address), true) => PacketFilterComponent::LocalIpv6 { address, prefix_len: 128 },
+        (IpAddr::V6(address), false) => PacketFilterComponent::RemoteIpv6 { address, prefix_len: 128 },
+    };
+    let mut components = vec![
+        address(downlink.dst, true),
+        address(downlink.src, false),
+        PacketFilterComponent::Protocol(downlink.protocol),
+    ];
+    if let Some(port) = downlink.dst_port {
+        components.push(PacketFilterComponent::LocalPorts { low: port, high: port });
+    }
+    if let Some(port) = downlink.src_port {
+        components.push(PacketFilterComponent::RemotePorts { low: port, high: port });
+    }
+    QosRuleCtx {
+        qos_rule_id: 0,
+        precedence: DERIVED_QOS_RULE_PRECEDENCE,
+        qfi,
+        segregation: false,
+        packet_filters: 1,
+        default_rule: false,
+        filters: vec![PacketFilterCtx {
+            id: 0,
+            direction: PacketFilterDirection::Uplink,
+            components,
+        }],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timer::MockClock;
+
+    const UE: [u8; 4] = [10, 45, 0, 2];
+    const SERVER: [u8; 4] = [8, 8, 8, 8];
+
+    fn ipv4(protocol: u8, src: [u8; 4], dst: [u8; 4], src_port: u16, dst_port: u16, tos: u8) -> Vec<u8> {
+        let mut packet = vec![0x45, tos, 0, 28, 0, 0, 0, 0, 64, protocol, 0, 0];
+        packet.extend_from_slice(&src);
+        packet.extend_from_slice(&dst);
+        packet.extend_from_slice(&src_port.to_be_bytes());
+        packet.extend_from_slice(&dst_port.to_be_bytes());
+        packet.extend_from_slice(&[0, 8, 0, 0]);
+        packet
+    }
+
+    fn rule(precedence: u8, qfi: u8, direction: PacketFilterDirection, components: Vec<PacketFilterComponent>) -> QosRuleCtx {
+        QosRuleCtx {
+            qos_rule_id: qfi,
+            precedence,
+            qfi,
+            segregation: false,
+            packet_filters: 1,
+            default_rule: false,
+            filters: vec![PacketFilterCtx { id: 1, direction, components }],
+        }
+    }
+
+    // Its filter only matches downlink, so uplink lands on it by falling back
+    fn default_rule() -> QosRuleCtx {
+        QosRuleCtx { default_rule: true, ..rule(255, 1, PacketFilterDirection::Downlink, vec![PacketFilterComponent::MatchAll]) }
+    }
+
+    #[test]
+    fn lowest_precedence_value_wins() {
+        let rules = vec![
+            default_rule(),
+            rule(20, 6, PacketFilterDirection::Uplink, vec![PacketFilterComponent::Protocol(IP_PROTO_UDP)]),
+            rule(10, 5, PacketFilterDirection::Bidirectional, vec![
+                PacketFilterComponent::Protocol(IP_PROTO_UDP),
+                PacketFilterComponent::RemotePorts { low: 5000, high: 5010 },
+            ]),
+            rule(15, 7, PacketFilterDirection::Downlink, vec![PacketFilterComponent::Protocol(IP_PROTO_UDP)]),
+        ];
+        assert_eq!(classify_uplink(&rules, &ipv4(IP_PROTO_UDP, UE, SERVER, 40000, 5005, 0)), Some(5));
+        assert_eq!(classify_uplink(&rules, &ipv4(IP_PROTO_UDP, UE, SERVER, 5005, 6000, 0)), Some(6));
+    }
+
+    #[test]
+    fn unmatched_packets_fall_back_to_the_default_rule() {
+        let rules = vec![default_rule(), rule(10, 5, PacketFilterDirection::Uplink, vec![PacketFilterComponent::Protocol(IP_PROTO_UDP)])];
+        assert_eq!(classify_uplink(&rules, &ipv4(IP_PROTO_TCP, UE, SERVER, 40000, 443, 0)), Some(1));
+        assert_eq!(classify_uplink(&rules, &[0x45, 0x00]), Some(1));
+        assert_eq!(classify_uplink(&rules[1..], &ipv4(IP_PROTO_TCP, UE, SERVER, 40000, 443, 0)), None);
+    }
+
+    #[test]
+    fn downlink_with_rqi_creates_an_uplink_rule() {
+        let mut derived = DerivedQosRules::new(Arc::new(MockClock::new()), DEFAULT_RQ_TIMER);
+        let created = derived.on_downlink(&ipv4(IP_PROTO_UDP, SERVER, UE, 53, 40000, 0), 5).cloned().unwrap();
+        assert_eq!((created.precedence, created.qfi), (DERIVED_QOS_RULE_PRECEDENCE, 5));
+        assert_eq!(created.filters[0].components, [
+            PacketFilterComponent::LocalIpv4 { address: UE.into(), mask: Ipv4Addr::BROADCAST },
+            PacketFilterComponent::RemoteIpv4 { address: SERVER.into(), mask: Ipv4Addr::BROADCAST },
+            PacketFilterComponent::Protocol(IP_PROTO_UDP),
+            PacketFilterComponent::LocalPorts { low: 40000, high: 40000 },
+            PacketFilterComponent::RemotePorts { low: 53, high: 53 },
+        ]);
+        let rules = [default_rule()];
+        let classify = |derived: &DerivedQosRules, packet: &[u8]| classify_uplink(rules.iter().chain(derived.rules()), packet);
+        assert_eq!(classify(&derived, &ipv4(IP_PROTO_UDP, UE, SERVER, 40000, 53, 0)), Some(5));
+        assert_eq!(classify(&derived, &ipv4(IP_PROTO_UDP, UE, SERVER, 40001, 53, 0)), Some(1));
+        assert_eq!(classify(&derived, &ipv4(IP_PROTO_TCP, UE, SERVER, 40000, 53, 0)), Some(1));
+    }
+
+    #[test]
+    fn derived_rule_follows_the_qfi_of_its_flow() {
+        let mut derived = DerivedQosRules::new(Arc::new(MockClock::new()), DEFAULT_RQ_TIMER);
+        assert!(derived.on_downlink(&ipv4(IP_PROTO_UDP, SERVER, UE, 53, 40000, 0), 5).is_some());
+        // Same flow on the same QFI, whatever its type of service
+        assert!(derived.on_downlink(&ipv4(IP_PROTO_UDP, SERVER, UE, 53, 40000, 0xb8), 5).is_none());
+        assert_eq!(derived.on_downlink(&ipv4(IP_PROTO_UDP, SERVER, UE, 53, 40000, 0), 9).map(|rule| rule.qfi), Some(9));
+        assert_eq!(derived.len(), 1);
+        assert_eq!(classify_uplink(derived.rules(), &ipv4(IP_PROTO_UDP, UE, SERVER, 40000, 53, 0)), Some(9));
+        // Not a packet, no rule
+        assert!(derived.on_downlink(&[0x60], 9).is_none());
+        assert_eq!(derived.len(), 1);
+    }
+
+    #[test]
+    fn derived_rule_expires_with_its_rq_timer() {
+        let clock = Arc::new(MockClock::new());
+        let rq_timer = Duration::from_secs(10);
+        let mut derived = DerivedQosRules::new(clock.clone(), rq_timer);
+        let downlink = ipv4(IP_PROTO_UDP, SERVER, UE, 53, 40000, 0);
+        derived.on_downlink(&downlink, 5);
+        derived.on_downlink(&ipv4(IP_PROTO_UDP, SERVER, UE, 123, 40001, 0), 6);
+        clock.advance(Duration::from_secs(6));
+        // Every downlink packet of the flow restarts its timer
+        derived.on_downlink(&downlink, 5);
+        assert_eq!(derived.next_timeout(), Some(Duration::from_secs(4)));
+        clock.advance(Duration::from_secs(4));
+        assert_eq!(derived.expire().iter().map(|rule| rule.qfi).collect::<Vec<_>>(), [6]);
+        assert_eq!(derived.next_timeout(), Some(Duration::from_secs(6)));
+        clock.advance(Duration::from_secs(5));
+        assert!(derived.expire().is_empty());
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(derived.expire().iter().map(|rule| rule.qfi).collect::<Vec<_>>(), [5]);
+        assert_eq!((derived.len(), derived.next_timeout()), (0, None));
+    }
+}
@@ -0,0 +1,256 @@
+use std::{ffi::CString, io, mem, net::IpAddr, os::fd::{AsRawFd, FromRawFd, OwnedFd}};
+
+// linux/netlink.h and linux/rtnetlink.h
+const NLMSG_ERROR: u16 = 2;
+const NLM_F_REQUEST: u16 = 0x01;
+const NLM_F_ACK: u16 = 0x04;
+const NLM_F_REPLACE: u16 = 0x100;
+const NLM_F_EXCL: u16 = 0x200;
+const NLM_F_CREATE: u16 = 0x400;
+const RTM_DELLINK: u16 = 17;
+const RTM_NEWLINK: u16 = 16;
+const RTM_NEWADDR: u16 = 20;
+const RTM_DELADDR: u16 = 21;
+const RTM_NEWROUTE: u16 = 24;
+const RTM_DELROUTE: u16 = 25;
+const RTM_NEWRULE: u16 = 32;
+const RTM_DELRULE: u16 = 33;
+const IFA_ADDRESS: u16 = 1;
+const IFA_LOCAL: u16 = 2;
+const IFLA_MTU: u16 = 4;
+const RTA_DST: u16 = 1;
+const RTA_OIF: u16 = 4;
+const RTA_TABLE: u16 = 15;
+const FRA_SRC: u16 = 2;
+const FRA_TABLE: u16 = 15;
+const FR_ACT_TO_TBL: u8 = 1;
+const RT_TABLE_UNSPEC: u8 = 0;
+pub const RT_TABLE_MAIN: u32 = 254;
+const RTPROT_STATIC: u8 = 4;
+const RT_SCOPE_UNIVERSE: u8 = 0;
+const RT_SCOPE_LINK: u8 = 253;
+const RTN_UNICAST: u8 = 1;
+const IFF_UP: u32 = 0x1;
+
+const NLMSG_HDRLEN: usize = 16;
+
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn family(addr: &IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => libc::AF_INET as u8,
+        IpAddr::V6(_) => libc::AF_INET6 as u8,
+    }
+}
+
+fn octets(addr: &IpAddr) -> Vec<u8> {
+    match addr {
+        IpAddr::V4(ipv4) => ipv4.octets().to_vec(),
+        IpAddr::V6(ipv6) => ipv6.octets().to_vec(),
+    }
+}
+
+/// One rtnetlink request: the header, the family specific message and its
+/// attributes, each padded to 4 octets.
+struct NlRequest {
+    buf: Vec<u8>,
+}
+
+impl NlRequest {
+    fn new(message_type: u16, flags: u16, body: &[u8]) -> NlRequest {
+        let mut buf = vec![0; NLMSG_HDRLEN];
+        buf[4..6].copy_from_slice(&message_type.to_ne_bytes());
+        buf[6..8].copy_from_slice(&(flags | NLM_F_REQUEST | NLM_F_ACK).to_ne_bytes());
+        buf.extend_from_slice(body);
+        buf.resize(align(buf.len()), 0);
+        NlRequest { buf }
+    }
+
+    fn attr(mut self, attr_type: u16, data: &[u8]) -> NlRequest {
+        let len = 4 + data.len();
+        self.buf.extend_from_slice(&(len as u16).to_ne_bytes());
+        self.buf.extend_from_slice(&attr_type.to_ne_bytes());
+        self.buf.extend_from_slice(data);
+        self.buf.resize(align(self.buf.len()), 0);
+        self
+    }
+
+    fn finish(mut self, seq: u32) -> Vec<u8> {
+        let len = self.buf.len() as u32;
+        self.buf[0..4].copy_from_slice(&len.to_ne_bytes());
+        self.buf[8..12].copy_from_slice(&seq.to_ne_bytes());
+        self.buf
+    }
+}
+
+/// struct ifaddrmsg
+fn ifaddrmsg(addr: &IpAddr, prefix_len: u8, index: u32) -> Vec<u8> {
+    let mut body = vec![family(addr), prefix_len, 0, RT_SCOPE_UNIVERSE];
+    body.extend_from_slice(&index.to_ne_bytes());
+    body
+}
+
+/// Table field of struct rtmsg and struct fib_rule_hdr, which only fits the
+/// first 255 tables; the RTA_TABLE or FRA_TABLE attribute carries any.
+fn table_u8(table: u32) -> u8 {
+    u8::try_from(table).unwrap_or(RT_TABLE_UNSPEC)
+}
+
+/// struct rtmsg
+fn rtmsg(dst: &IpAddr, prefix_len: u8, table: u32) -> Vec<u8> {
+    let mut body = vec![family(dst), prefix_len, 0, 0, table_u8(table), RTPROT_STATIC, RT_SCOPE_LINK, RTN_UNICAST];
+    body.extend_from_slice(&0u32.to_ne_bytes());
+    body
+}
+
+/// struct fib_rule_hdr of a rule looking up `table` for packets from `src`
+fn fib_rule_hdr(src: &IpAddr, table: u32) -> Vec<u8> {
+    let src_len = octets(src).len() as u8 * 8;
+    let mut body = vec![family(src), 0, src_len, 0, table_u8(table), 0, 0, FR_ACT_TO_TBL];
+    body.extend_from_slice(&0u32.to_ne_bytes());
+    body
+}
+
+/// struct ifinfomsg
+fn ifinfomsg(index: u32, flags: u32, change: u32) -> Vec<u8> {
+    let mut body = vec![libc::AF_UNSPEC as u8, 0, 0, 0];
+    body.extend_from_slice(&(index as i32).to_ne_bytes());
+    body.extend_from_slice(&flags.to_ne_bytes());
+    body.extend_from_slice(&change.to_ne_bytes());
+    body
+}
+
+pub fn link_index(name: &str) -> io::Result<u32> {
+    let name = CString::new(name).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "interface name with NUL"))?;
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => Err(io::Error::last_os_error()),
+        index => Ok(index),
+    }
+}
+
+/// Route netlink socket, one request at a time, each waiting for its ack.
+pub struct RtNetlink {
+    socket: OwnedFd,
+    seq: u32,
+}
+
+impl RtNetlink {
+    pub fn open() -> io::Result<RtNetlink> {
+        let fd = unsafe { libc::socket(libc::AF_NETLINK, libc::SOCK_RAW | libc::SOCK_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        let ret = unsafe {
+            libc::bind(socket.as_raw_fd(), &addr as *const libc::sockaddr_nl as *const libc::sockaddr, mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t)
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(RtNetlink { socket, seq: 0 })
+    }
+
+    fn request(&mut self, request: NlRequest) -> io::Result<()> {
+        self.seq = self.seq.wrapping_add(1);
+        let seq = self.seq;
+        let msg = request.finish(seq);
+        let sent = unsafe { libc::send(self.socket.as_raw_fd(), msg.as_ptr() as *const libc::c_void, msg.len(), 0) };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut buf = [0u8; 8192];
+        loop {
+            let len = unsafe { libc::recv(self.socket.as_raw_fd(), buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0) };
+            if len < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut msg = &buf[..len as usize];
+            while msg.len() >= NLMSG_HDRLEN {
+                let msg_len = u32::from_ne_bytes(msg[0..4].try_into().unwrap()) as usize;
+                let msg_type = u16::from_ne_bytes(msg[4..6].try_into().unwrap());
+                let msg_seq = u32::from_ne_bytes(msg[8..12].try_into().unwrap());
+                if msg_len < NLMSG_HDRLEN || msg_len > msg.len() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "truncated netlink message"));
+                }
+                if msg_type == NLMSG_ERROR && msg_seq == seq && msg_len >= NLMSG_HDRLEN + 4 {
+                    let errno = i32::from_ne_bytes(msg[NLMSG_HDRLEN..NLMSG_HDRLEN + 4].try_into().unwrap());
+                    return match errno {
+                        0 => Ok(()),
+                        errno => Err(io::Error::from_raw_os_error(-errno)),
+                    };
+                }
+                msg = &msg[align(msg_len).min(msg.len())..];
+            }
+        }
+    }
+
+    /// `ip address add|replace ADDR/PREFIX dev DEV`
+    pub fn add_address(&mut self, index: u32, addr: IpAddr, prefix_len: u8, replace: bool) -> io::Result<()> {
+        let flags = NLM_F_CREATE | if replace { NLM_F_REPLACE } else { NLM_F_EXCL };
+        let request = NlRequest::new(RTM_NEWADDR, flags, &ifaddrmsg(&addr, prefix_len, index))
+            .attr(IFA_LOCAL, &octets(&addr))
+            .attr(IFA_ADDRESS, &octets(&addr));
+        self.request(request)
+    }
+
+    /// `ip address del ADDR/PREFIX dev DEV`
+    pub fn del_address(&mut self, index: u32, addr: IpAddr, prefix_len: u8) -> io::Result<()> {
+        let request = NlRequest::new(RTM_DELADDR, 0, &ifaddrmsg(&addr, prefix_len, index))
+            .attr(IFA_LOCAL, &octets(&addr))
+            .attr(IFA_ADDRESS, &octets(&addr));
+        self.request(request)
+    }
+
+    /// `ip route add|replace DST/PREFIX dev DEV table TABLE`
+    pub fn add_route(&mut self, index: u32, dst: IpAddr, prefix_len: u8, table: u32, replace: bool) -> io::Result<()> {
+        let flags = NLM_F_CREATE | if replace { NLM_F_REPLACE } else { NLM_F_EXCL };
+        let request = NlRequest::new(RTM_NEWROUTE, flags, &rtmsg(&dst, prefix_len, table))
+            .attr(RTA_DST, &octets(&dst))
+            .attr(RTA_OIF, &index.to_ne_bytes())
+            .attr(RTA_TABLE, &table.to_ne_bytes());
+        self.request(request)
+    }
+
+    /// `ip route del DST/PREFIX dev DEV table TABLE`
+    pub fn del_route(&mut self, index: u32, dst: IpAddr, prefix_len: u8, table: u32) -> io::Result<()> {
+        let request = NlRequest::new(RTM_DELROUTE, 0, &rtmsg(&dst, prefix_len, table))
+            .attr(RTA_DST, &octets(&dst))
+            .attr(RTA_OIF, &index.to_ne_bytes())
+            .attr(RTA_TABLE, &table.to_ne_bytes());
+        self.request(request)
+    }
+
+    /// `ip rule add from SRC lookup TABLE`
+    pub fn add_rule(&mut self, src: IpAddr, table: u32) -> io::Result<()> {
+        let request = NlRequest::new(RTM_NEWRULE, NLM_F_CREATE | NLM_F_EXCL, &fib_rule_hdr(&src, table))
+            .attr(FRA_SRC, &octets(&src))
+            .attr(FRA_TABLE, &table.to_ne_bytes());
+        self.request(request)
+    }
+
+    /// `ip rule del from SRC lookup TABLE`
+    pub fn del_rule(&mut self, src: IpAddr, table: u32) -> io::Result<()> {
+        let request = NlRequest::new(RTM_DELRULE, 0, &fib_rule_hdr(&src, table))
+            .attr(FRA_SRC, &octets(&src))
+            .attr(FRA_TABLE, &table.to_ne_bytes());
+        self.request(request)
+    }
+
+    /// `ip link set dev DEV mtu MTU up`
+    pub fn set_link(&mut self, index: u32, mtu: Option<u16>) -> io::Result<()> {
+        let mut request = NlRequest::new(RTM_NEWLINK, 0, &ifinfomsg(index, IFF_UP, IFF_UP));
+        if let Some(mtu) = mtu {
+            request = request.attr(IFLA_MTU, &(mtu as u32).to_ne_bytes());
+        }
+        self.request(request)
+    }
+
+    /// `ip link del dev DEV`
+    pub fn del_link(&mut self, index: u32) -> io::Result<()> {
+        self.request(NlRequest::new(RTM_DELLINK, 0, &ifinfomsg(index, 0, 0)))
+    }
+}
@@ -0,0 +1,305 @@
+use std::{fs, io, net::{IpAddr, Ipv4Addr, Ipv6Addr}, path::PathBuf, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{pdu_helper::{pdu_accept::PduSessionEstablishmentAcceptMsg, pdu_helper::PDUSessionIdentity}, qos_classifier::PacketFilterCtx};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnssaiCtx {
+    pub sst: u8,
+    pub sd: Option<[u8; 3]>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QosRuleCtx {
+    pub qos_rule_id: u8,
+    pub precedence: u8,
+    pub qfi: u8,
+    pub segregation: bool,
+    pub packet_filters: u8,
+    #[serde(default)]
+    pub default_rule: bool,
+    #[serde(default)]
+    pub filters: Vec<PacketFilterCtx>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QosFlowCtx {
+    pub qfi: u8,
+    pub five_qi: Option<u8>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionAmbrCtx {
+    pub downlink_kbps: u64,
+    pub uplink_kbps: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GtpTunnelCtx {
+    pub local_teid: u32,
+    pub remote_teid: u32,
+    pub remote_addr: IpAddr,
+}
+
+/// Everything needed to re-create the data path of an established session.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PduSessionContext {
+    pub pdu_session_id: PDUSessionIdentity,
+    pub dnn: String,
+    pub snssai: Option<SnssaiCtx>,
+    pub ipv4: Option<Ipv4Addr>,
+    pub ipv6: Option<Ipv6Addr>,
+    pub qos_rules: Vec<QosRuleCtx>,
+    pub qos_flows: Vec<QosFlowCtx>,
+    pub session_ambr: Option<SessionAmbrCtx>,
+    /// DNS servers and link MTU from the extended PCO
+    #[serde(default)]
+    pub dns: Vec<IpAddr>,
+    #[serde(default)]
+    pub mtu: Option<u16>,
+    /// Local TEID allocated at establishment, before the tunnel is set up
+    #[serde(default)]
+    pub local_teid: Option<u32>,
+    pub tunnel: Option<GtpTunnelCtx>,
+    #[serde(default)]
+    pub rq_timer: Option<Duration>,
+    /// Echoes to the tunnel peer go unanswered; not kept in the snapshot
+    #[serde(skip)]
+    pub path_down: bool,
+}
+
+impl PduSessionContext {
+    pub fn from_accept(accept: &mut PduSessionEstablishmentAcceptMsg) -> PduSessionContext {
+        let snssai = if accept.snssai.len > 0 {
+            Some(SnssaiCtx {
+                sst: accept.snssai.sst,
+                sd: if accept.snssai.has_sd() { Some(accept.snssai.sd) } else { None },
+            })
+        } else {
+            None
+        };
+        let session_ambr = if accept.sessionambr.uint_for_session_ambr_for_downlink > 0 {
+            Some(SessionAmbrCtx {
+                downlink_kbps: accept.sessionambr.downlink_kbps(),
+                uplink_kbps: accept.sessionambr.uplink_kbps(),
+            })
+        } else {
+            None
+        };
+        let ipv4 = match accept.get_ipv4() {
+            Ok(IpAddr::V4(ipv4)) => Some(ipv4),
+            _ => None,
+        };
+        let ipv6 = match accept.get_ipv6() {
+            Ok(IpAddr::V6(ipv6)) => Some(ipv6),
+            _ => None,
+        };
+        PduSessionContext {
+            pdu_session_id: accept.pdusessionidentity,
+            dnn: accept.get_dnn_name(),
+            snssai,
+            ipv4,
+            ipv6,
+            qos_rules: accept
+                .qosrules
+                .qosrulesie
+                .iter()
+                .map(|rule| QosRuleCtx {
+                    qos_rule_id: rule.qosruleidentifer,
+                    precedence: rule.qosruleprecedence,
+                    qfi: rule.qosflowidentifer,
+                    segregation: rule.segregation != 0,
+                    packet_filters: rule.numberofpacketfilters,
+                    default_rule: rule.dqrbit != 0,
+                    filters: rule.packetfilterlist.filters(),
+                })
+                .collect(),
+            qos_flows: accept
+                .qosflowdescriptions
+                .qosflowdescriptionscontents
+                .iter()
+                .map(|flow| QosFlowCtx {
+                    qfi: flow.qfi,
+                    five_qi: flow.get_5qi(),
+                })
+                .collect(),
+            session_ambr,
+            dns: accept.get_dns_v4_address().map(IpAddr::V4).into_iter()
+                .chain(accept.get_dns_v6_address().map(IpAddr::V6))
+                .collect(),
+            mtu: accept.get_mtu(),
+            local_teid: None,
+            tunnel: None,
+            rq_timer: accept.get_rq_timer(),
+            path_down: false,
+        }
+    }
+
+    /// QFIs of the signalled QoS flows and rules.
+    pub fn qfis(&self) -> Vec<u8> {
+        let mut qfis: Vec<u8> = self.qos_flows.iter().map(|flow| flow.qfi)
+            .chain(self.qos_rules.iter().map(|rule| rule.qfi))
+            .collect();
+        qfis.sort_unstable();
+        qfis.dedup();
+        qfis
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SessionSnapshot {
+    sessions: Vec<PduSessionContext>,
+}
+
+/// JSON snapshot of the established sessions, rewritten on every change.
+pub struct SessionStore {
+    path: PathBuf,
+}
+
+impl SessionStore {
+    pub fn new(path: impl Into<PathBuf>) -> SessionStore {
+        SessionStore { path: path.into() }
+    }
+
+    /// Snapshot of one session manager instance: `pdu_sessions.json` for
+    /// instance 0, `pdu_sessions.1.json` for instance 1 and so on.
+    pub fn for_instance(path: impl Into<PathBuf>, instance: u16) -> SessionStore {
+        let path = path.into();
+        if instance == 0 {
+            return SessionStore::new(path);
+        }
+        let mut name = path.file_stem().unwrap_or_default().to_os_string();
+        name.push(format!(".{}", instance));
+        if let Some(extension) = path.extension() {
+            name.push(".");
+            name.push(extension);
+        }
+        SessionStore::new(path.with_file_name(name))
+    }
+
+    /// Returns no sessions when the snapshot does not exist yet.
+    pub fn load(&self) -> io::Result<Vec<PduSessionContext>> {
+        let data = match fs::read(&self.path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e),
+        };
+        let snapshot: SessionSnapshot = serde_json::from_slice(&data)?;
+        Ok(snapshot.sessions)
+    }
+
+    /// Writes to a temporary file first so a crash never leaves a truncated snapshot.
+    pub fn save<'a>(&self, sessions: impl Iterator<Item = &'a PduSessionContext>) -> io::Result<()> {
+        let snapshot = SessionSnapshot {
+            sessions: sessions.cloned().collect(),
+        };
+        let data = serde_json::to_vec_pretty(&snapshot)?;
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, data)?;
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qos_classifier::{PacketFilterComponent, PacketFilterDirection};
+
+    /// Empty directory of its own for each test.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("session_store-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn context(pdu_session_id: u8) -> PduSessionContext {
+        PduSessionContext {
+            pdu_session_id,
+            dnn: "internet".to_string(),
+            snssai: Some(SnssaiCtx { sst: 1, sd: Some([0, 0, 7]) }),
+            ipv4: Some(Ipv4Addr::new(10, 45, 0, pdu_session_id)),
+            ipv6: Some("::1:2".parse().unwrap()),
+            qos_rules: vec![QosRuleCtx {
+                qos_rule_id: 1,
+                precedence: 255,
+                qfi: 9,
+                segregation: false,
+                packet_filters: 1,
+                default_rule: true,
+                filters: vec![PacketFilterCtx {
+                    id: 1,
+                    direction: PacketFilterDirection::Uplink,
+                    components: vec![
+                        PacketFilterComponent::RemoteIpv4 { address: Ipv4Addr::new(10, 0, 0, 1), mask: Ipv4Addr::BROADCAST },
+                        PacketFilterComponent::RemotePorts { low: 5000, high: 5010 },
+                    ],
+                }],
+            }],
+            qos_flows: vec![QosFlowCtx { qfi: 9, five_qi: Some(9) }],
+            session_ambr: Some(SessionAmbrCtx { downlink_kbps: 100_000, uplink_kbps: 50_000 }),
+            dns: vec![IpAddr::from([8, 8, 8, 8])],
+            mtu: Some(1400),
+            local_teid: Some(0x1234),
+            tunnel: Some(GtpTunnelCtx { local_teid: 0x1234, remote_teid: 0x5678, remote_addr: IpAddr::from([192, 0, 2, 1]) }),
+            rq_timer: Some(Duration::from_secs(30)),
+            path_down: true,
+        }
+    }
+
+    // The contexts have no PartialEq, their JSON is compared instead
+    fn json(contexts: &[PduSessionContext]) -> serde_json::Value {
+        serde_json::to_value(contexts).unwrap()
+    }
+
+    #[test]
+    fn saved_sessions_load_back() {
+        let dir = test_dir("round_trip");
+        let store = SessionStore::new(dir.join("pdu_sessions.json"));
+        assert!(store.load().unwrap().is_empty());
+        let saved = vec![context(1), context(2)];
+        store.save(saved.iter()).unwrap();
+        let loaded = store.load().unwrap();
+        // Path state is not kept
+        assert!(loaded.iter().all(|ctx| !ctx.path_down));
+        let mut expected = saved.clone();
+        expected.iter_mut().for_each(|ctx| ctx.path_down = false);
+        assert_eq!(json(&loaded), json(&expected));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn save_replaces_the_snapshot_only_once_written() {
+        let dir = test_dir("tmp_rename");
+        let store = SessionStore::new(dir.join("pdu_sessions.json"));
+        store.save([context(1)].iter()).unwrap();
+        assert!(!dir.join("pdu_sessions.tmp").exists());
+        // A temporary file that cannot be written leaves the old snapshot
+        fs::create_dir(dir.join("pdu_sessions.tmp")).unwrap();
+        assert!(store.save([context(1), context(2)].iter()).is_err());
+        assert_eq!(store.load().unwrap().len(), 1);
+        // And a stale one from a crash is overwritten
+        fs::remove_dir(dir.join("pdu_sessions.tmp")).unwrap();
+        fs::write(dir.join("pdu_sessions.tmp"), "{\"sessions\": [").unwrap();
+        store.save([context(1), context(2)].iter()).unwrap();
+        assert_eq!(store.load().unwrap().len(), 2);
+        assert!(!dir.join("pdu_sessions.tmp").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn each_instance_has_its_own_snapshot() {
+        assert_eq!(SessionStore::for_instance("/var/lib/itti/pdu_sessions.json", 0).path, PathBuf::from("/var/lib/itti/pdu_sessions.json"));
+        assert_eq!(SessionStore::for_instance("/var/lib/itti/pdu_sessions.json", 1).path, PathBuf::from("/var/lib/itti/pdu_sessions.1.json"));
+        assert_eq!(SessionStore::for_instance("sessions", 12).path, PathBuf::from("sessions.12"));
+        // Nor do their temporary files collide
+        let dir = test_dir("instances");
+        let (first, second) = (SessionStore::for_instance(dir.join("pdu_sessions.json"), 0), SessionStore::for_instance(dir.join("pdu_sessions.json"), 1));
+        first.save([context(1)].iter()).unwrap();
+        second.save([context(2), context(3)].iter()).unwrap();
+        assert_eq!(first.load().unwrap().len(), 1);
+        assert_eq!(second.load().unwrap().len(), 2);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
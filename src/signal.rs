@@ -0,0 +1,72 @@
+use std::{io, mem, sync::Arc, thread, time::Duration};
+
+use crate::{itti::Itti, msg::{IttiTrxTag, PduSessionQueryKind, PduSessionQueryResponse}, pdu_session::query_sessions};
+
+fn handled_signals() -> libc::sigset_t {
+    unsafe {
+        let mut set: libc::sigset_t = mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGINT);
+        libc::sigaddset(&mut set, libc::SIGTERM);
+        libc::sigaddset(&mut set, libc::SIGUSR1);
+        set
+    }
+}
+
+/// Prints the queue of every task, e.g. to find the one a burst piles up on,
+/// and the sessions of every session manager.
+fn dump_state(itti: &Itti) {
+    for stats in itti.stats() {
+        println!("itti stats: {}", stats);
+    }
+    if itti.instances(IttiTrxTag::PduSessionMgmt) == 0 {
+        return;
+    }
+    match query_sessions(itti, PduSessionQueryKind::ListAll, Duration::from_secs(1)) {
+        Ok(PduSessionQueryResponse::Sessions(sessions)) => {
+            println!("itti sessions: {}", sessions.len());
+            for ctx in sessions {
+                println!("itti session {}: dnn {} ipv4 {:?} ipv6 {:?} local teid {:?} {} QoS flows",
+                    ctx.pdu_session_id, ctx.dnn, ctx.ipv4, ctx.ipv6, ctx.local_teid, ctx.qos_flows.len());
+            }
+        },
+        Ok(response) => println!("itti sessions: unexpected {:?}", response),
+        Err(e) => println!("itti sessions: {}", e),
+    }
+}
+
+/// Shuts the ITTI tasks down on the first SIGINT or SIGTERM, and prints the
+/// mailbox statistics and the PDU sessions on SIGUSR1. The shutdown result is
+/// picked up by whoever waits in `Itti::wait_stopped`.
+///
+/// The signals are blocked in the calling thread and picked up with
+/// `sigwait` on a dedicated thread, so nothing runs in signal handler
+/// context. Call this before spawning any task: threads inherit the mask,
+/// and an unblocked thread would still receive the default action.
+pub fn handle_signals(itti: Arc<Itti>) -> io::Result<()> {
+    let set = handled_signals();
+    let ret = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut()) };
+    if ret != 0 {
+        return Err(io::Error::from_raw_os_error(ret));
+    }
+    thread::Builder::new()
+        .name("signal".to_string())
+        .spawn(move || loop {
+            let mut signal: libc::c_int = 0;
+            let ret = unsafe { libc::sigwait(&set, &mut signal) };
+            if ret != 0 {
+                println!("sigwait failed: {}", io::Error::from_raw_os_error(ret));
+                return;
+            }
+            if signal == libc::SIGUSR1 {
+                dump_state(&itti);
+                continue;
+            }
+            println!("received signal {}, shutting down", signal);
+            if !itti.shutdown() {
+                println!("shutdown incomplete, a task is still running");
+            }
+            return;
+        })?;
+    Ok(())
+}
@@ -0,0 +1,139 @@
+use std::{any::Any, collections::VecDeque, panic::{self, AssertUnwindSafe}, sync::Arc, time::{Duration, Instant}};
+
+use serde::{Deserialize, Deserializer};
+
+use crate::{itti::{Itti, Mailbox, MailboxConfig}, msg::IttiTaskId};
+
+/// What happens when a task panics. Restarts are one-for-one: only the task
+/// that panicked is restarted, with a fresh mailbox, and the messages queued
+/// on its old mailbox are lost.
+///
+/// Configured as `"never"` or `{ "one_for_one": { "max_restarts": 5, "window_secs": 60 } }`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum RestartPolicy {
+    Never,
+    /// Gives up once the task panicked `max_restarts` times within `window`
+    OneForOne {
+        max_restarts: usize,
+        #[serde(rename = "window_secs", deserialize_with = "secs")]
+        window: Duration,
+    },
+}
+
+impl Default for RestartPolicy {
+    fn default() -> RestartPolicy {
+        RestartPolicy::OneForOne { max_restarts: 5, window: Duration::from_secs(60) }
+    }
+}
+
+fn secs<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    u64::deserialize(deserializer).map(Duration::from_secs)
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    match panic.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => match panic.downcast_ref::<String>() {
+            Some(message) => message,
+            None => "unknown panic",
+        },
+    }
+}
+
+/// Runs a task on the calling thread, restarting it under `policy` when it
+/// panics. `task` is called again for every restart, so it must build the
+/// task state from scratch. Returns once the task returns, is given up on, or
+/// panics while ITTI is shutting down.
+pub fn supervise<F>(itti: Arc<Itti>, id: IttiTaskId, config: MailboxConfig, policy: RestartPolicy, mut task: F)
+where
+    F: FnMut(Arc<Itti>, Mailbox),
+{
+    let mut restarts: VecDeque<Instant> = VecDeque::new();
+    loop {
+        let mut mailbox = itti.register_with(id, config);
+        let in_flight = mailbox.track_in_flight();
+        let panic = match panic::catch_unwind(AssertUnwindSafe(|| task(itti.clone(), mailbox))) {
+            Ok(_) => return,
+            Err(panic) => panic,
+        };
+        match in_flight.lock().unwrap().take() {
+            Some(envelope) => println!("task {} panicked: {} while handling {:?} (correlation {})",
+                id, panic_message(&*panic), envelope.msg, envelope.header.correlation_id),
+            None => println!("task {} panicked: {} before receiving any message", id, panic_message(&*panic)),
+        }
+        if itti.is_stopping() {
+            return;
+        }
+        let (max_restarts, window) = match policy {
+            RestartPolicy::Never => (0, Duration::ZERO),
+            RestartPolicy::OneForOne { max_restarts, window } => (max_restarts, window),
+        };
+        let now = Instant::now();
+        while let Some(restart) = restarts.front() {
+            if now.duration_since(*restart) <= window {
+                break;
+            }
+            restarts.pop_front();
+        }
+        if restarts.len() >= max_restarts {
+            println!("task {} not restarted: {} restarts within {:?}", id, restarts.len(), window);
+            itti.unregister(id);
+            return;
+        }
+        restarts.push_back(now);
+        println!("restarting task {}", id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, thread};
+
+    use super::*;
+    use crate::msg::IttiTrxTag;
+
+    const TASK: IttiTaskId = IttiTaskId { tag: IttiTrxTag::NetIfMgmt, instance: 0 };
+
+    /// Runs a task that panics on each of its first `panics` runs, sleeping
+    /// `run_for` first, and returns how many times it ran.
+    fn runs(policy: RestartPolicy, panics: usize, run_for: Duration) -> usize {
+        let itti = Arc::new(Itti::new());
+        let runs = Cell::new(0);
+        supervise(itti.clone(), TASK, MailboxConfig::unbounded(), policy, |_, _| {
+            runs.set(runs.get() + 1);
+            thread::sleep(run_for);
+            if runs.get() <= panics {
+                panic!("run {}", runs.get());
+            }
+        });
+        runs.get()
+    }
+
+    #[test]
+    fn never_does_not_restart() {
+        assert_eq!(runs(RestartPolicy::Never, 10, Duration::ZERO), 1);
+    }
+
+    #[test]
+    fn one_for_one_gives_up_after_max_restarts_within_window() {
+        let policy = RestartPolicy::OneForOne { max_restarts: 3, window: Duration::from_secs(60) };
+        assert_eq!(runs(policy, 10, Duration::ZERO), 4);
+        assert_eq!(runs(policy, 3, Duration::ZERO), 4);
+    }
+
+    #[test]
+    fn one_for_one_forgets_restarts_out_of_window() {
+        let policy = RestartPolicy::OneForOne { max_restarts: 1, window: Duration::from_millis(20) };
+        assert_eq!(runs(policy, 3, Duration::from_millis(40)), 4);
+    }
+
+    #[test]
+    fn policy_from_config() {
+        assert_eq!(serde_json::from_str::<RestartPolicy>(r#""never""#).unwrap(), RestartPolicy::Never);
+        assert_eq!(
+            serde_json::from_str::<RestartPolicy>(r#"{ "one_for_one": { "max_restarts": 2, "window_secs": 30 } }"#).unwrap(),
+            RestartPolicy::OneForOne { max_restarts: 2, window: Duration::from_secs(30) },
+        );
+    }
+}
@@ -0,0 +1,355 @@
+use std::{cmp::Reverse, collections::{BinaryHeap, HashMap}, sync::{Arc, Mutex}, thread, time::{Duration, Instant}};
+
+use crossbeam::channel::RecvTimeoutError;
+
+use crate::{itti::{Itti, Mailbox}, msg::{IttiMsg, IttiTaskId, TimerExpiry, TimerStartReq}};
+
+/// Source of time for everything that arms timers, so tests can swap the
+/// wall clock for a `MockClock` and advance it instantly.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+pub struct MockClock {
+    base: Instant,
+    offset: Mutex<Duration>,
+}
+
+impl MockClock {
+    pub fn new() -> MockClock {
+        MockClock {
+            base: Instant::now(),
+            offset: Mutex::new(Duration::ZERO),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.offset.lock().unwrap() += duration;
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> MockClock {
+        MockClock::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.base + *self.offset.lock().unwrap()
+    }
+
+    /// Returns at once, with the time moved on.
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration);
+    }
+}
+
+pub type TimerId = u64;
+
+/// Min-heap of deadlines. Cancelled timers are left in the heap and skipped
+/// lazily when they reach the top.
+pub struct TimerQueue<T> {
+    heap: BinaryHeap<Reverse<(Instant, TimerId)>>,
+    entries: HashMap<TimerId, T>,
+    next_id: TimerId,
+}
+
+impl<T> TimerQueue<T> {
+    pub fn new() -> TimerQueue<T> {
+        TimerQueue {
+            heap: BinaryHeap::new(),
+            entries: HashMap::new(),
+            next_id: 1,
+        }
+    }
+
+    pub fn insert(&mut self, deadline: Instant, value: T) -> TimerId {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.heap.push(Reverse((deadline, id)));
+        self.entries.insert(id, value);
+        id
+    }
+
+    pub fn cancel(&mut self, id: TimerId) -> Option<T> {
+        self.entries.remove(&id)
+    }
+
+    pub fn next_deadline(&mut self) -> Option<Instant> {
+        while let Some(Reverse((deadline, id))) = self.heap.peek() {
+            if self.entries.contains_key(id) {
+                return Some(*deadline);
+            }
+            self.heap.pop();
+        }
+        None
+    }
+
+    /// Time left until the earliest deadline, zero if it has already passed.
+    pub fn next_timeout(&mut self, now: Instant) -> Option<Duration> {
+        self.next_deadline()
+            .map(|deadline| deadline.saturating_duration_since(now))
+    }
+
+    pub fn pop_expired(&mut self, now: Instant) -> Vec<(TimerId, T)> {
+        let mut expired = vec![];
+        while let Some(deadline) = self.next_deadline() {
+            if deadline > now {
+                break;
+            }
+            let Reverse((_, id)) = self.heap.pop().unwrap();
+            if let Some(value) = self.entries.remove(&id) {
+                expired.push((id, value));
+            }
+        }
+        expired
+    }
+}
+
+/// Timers are named by their target task and the `id` it picked.
+type TimerKey = (IttiTaskId, u64);
+
+struct ArmedTimer {
+    queue_id: TimerId,
+    deadline: Instant,
+    period: Option<Duration>,
+    expirations: u64,
+}
+
+/// The ITTI timer task: arms timers on `TimerStart`, and posts `TimerExpired`
+/// to the target task's mailbox when they run out.
+///
+/// In virtual time the clock only moves on `TimerAdvance`, which fires every
+/// timer falling within the step in deadline order, so tests never sleep.
+pub struct TimerService {
+    clock: Arc<dyn Clock>,
+    virtual_clock: Option<Arc<MockClock>>,
+    queue: TimerQueue<TimerKey>,
+    armed: HashMap<TimerKey, ArmedTimer>,
+}
+
+impl Default for TimerService {
+    fn default() -> TimerService {
+        TimerService {
+            clock: Arc::new(SystemClock),
+            virtual_clock: None,
+            queue: TimerQueue::new(),
+            armed: HashMap::new(),
+        }
+    }
+}
+
+impl TimerService {
+    pub fn virtual_time(clock: Arc<MockClock>) -> TimerService {
+        TimerService {
+            clock: clock.clone(),
+            virtual_clock: Some(clock),
+            queue: TimerQueue::new(),
+            armed: HashMap::new(),
+        }
+    }
+
+    fn start(&mut self, req: TimerStartReq) {
+        let key = (req.target_tag, req.id);
+        self.cancel(key);
+        let deadline = self.clock.now() + req.duration;
+        self.armed.insert(key, ArmedTimer {
+            queue_id: self.queue.insert(deadline, key),
+            deadline,
+            // A zero period would fire forever within a single poll
+            period: if req.periodic && !req.duration.is_zero() { Some(req.duration) } else { None },
+            expirations: 0,
+        });
+    }
+
+    fn cancel(&mut self, key: TimerKey) {
+        if let Some(timer) = self.armed.remove(&key) {
+            self.queue.cancel(timer.queue_id);
+        }
+    }
+
+    fn next_timeout(&mut self) -> Option<Duration> {
+        match self.virtual_clock {
+            Some(_) => None,
+            None => self.queue.next_timeout(self.clock.now()),
+        }
+    }
+
+    /// Fires every timer due by now. Periodic timers are re-armed from their
+    /// previous deadline so they do not drift.
+    fn poll(&mut self, itti: &Itti, task: IttiTaskId) {
+        let now = self.clock.now();
+        while let Some(deadline) = self.queue.next_deadline() {
+            if deadline > now {
+                break;
+            }
+            for (_, key) in self.queue.pop_expired(deadline) {
+                let timer = match self.armed.get_mut(&key) {
+                    Some(timer) => timer,
+                    None => continue,
+                };
+                timer.expirations += 1;
+                let expiry = TimerExpiry { id: key.1, expirations: timer.expirations };
+                match timer.period {
+                    Some(period) => {
+                        timer.deadline += period;
+                        timer.queue_id = self.queue.insert(timer.deadline, key);
+                    },
+                    None => {
+                        self.armed.remove(&key);
+                    },
+                }
+                let envelope = itti.envelope(Some(task), Some(key.0), IttiMsg::TimerExpired(expiry));
+                if let Err(e) = itti.send_envelope(envelope) {
+                    // Nobody is left to cancel the timers of a task that is gone
+                    println!("timer {} of {} dropped: {}", key.1, key.0, e);
+                    self.cancel(key);
+                }
+            }
+        }
+    }
+
+    pub fn init_timer_task(mut self, itti: Arc<Itti>, mailbox: Mailbox) {
+        loop {
+            let received = match self.next_timeout() {
+                Some(timeout) => mailbox.recv_timeout(timeout),
+                None => mailbox.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            match received {
+                Ok(envelope) => {
+                    match envelope.msg {
+                        IttiMsg::TimerStart(req) => self.start(req),
+                        IttiMsg::TimerCancel(req) => self.cancel((req.target_tag, req.id)),
+                        IttiMsg::TimerAdvance(duration) => {
+                            match &self.virtual_clock {
+                                Some(clock) => clock.advance(duration),
+                                None => println!("TimerAdvance ignored outside virtual time"),
+                            }
+                        },
+                        IttiMsg::TimerStopThread => break,
+                        msg => {println!("{:#?}", msg);},
+                    }
+                },
+                Err(RecvTimeoutError::Timeout) => {},
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+            self.poll(&itti, mailbox.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::{self, JoinHandle};
+
+    use super::*;
+    use crate::msg::{IttiTrxTag, TimerCancelReq};
+
+    const TARGET: IttiTaskId = IttiTaskId { tag: IttiTrxTag::PduSessionMgmt, instance: 0 };
+
+    struct VirtualTimers {
+        itti: Arc<Itti>,
+        target: Mailbox,
+        task: JoinHandle<()>,
+    }
+
+    impl VirtualTimers {
+        fn start() -> VirtualTimers {
+            let itti = Arc::new(Itti::new());
+            let target = itti.register(TARGET);
+            let mailbox = itti.register(IttiTrxTag::Timer);
+            let task = {
+                let itti = itti.clone();
+                thread::spawn(move || TimerService::virtual_time(Arc::new(MockClock::new())).init_timer_task(itti, mailbox))
+            };
+            VirtualTimers { itti, target, task }
+        }
+
+        fn send(&self, msg: IttiMsg) {
+            self.itti.send(IttiTrxTag::Timer, msg).unwrap();
+        }
+
+        fn arm(&self, id: u64, secs: u64, periodic: bool) {
+            self.send(IttiMsg::TimerStart(TimerStartReq { id, duration: Duration::from_secs(secs), periodic, target_tag: TARGET }));
+        }
+
+        fn advance(&self, secs: u64) {
+            self.send(IttiMsg::TimerAdvance(Duration::from_secs(secs)));
+        }
+
+        /// Expiries received as (id, expirations), waiting a little for the
+        /// timer task to handle what was sent before.
+        fn expired(&self) -> Vec<(u64, u64)> {
+            let mut expired = vec![];
+            while let Ok(envelope) = self.target.recv_timeout(Duration::from_millis(50)) {
+                match envelope.msg {
+                    IttiMsg::TimerExpired(expiry) => expired.push((expiry.id, expiry.expirations)),
+                    msg => panic!("unexpected {:?}", msg),
+                }
+            }
+            expired
+        }
+
+        fn stop(self) {
+            self.send(IttiMsg::TimerStopThread);
+            self.task.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn one_shot_timers_fire_once_in_deadline_order() {
+        let timers = VirtualTimers::start();
+        timers.arm(1, 10, false);
+        timers.arm(2, 5, false);
+        timers.advance(4);
+        assert_eq!(timers.expired(), vec![]);
+        timers.advance(16);
+        assert_eq!(timers.expired(), vec![(2, 1), (1, 1)]);
+        timers.advance(60);
+        assert_eq!(timers.expired(), vec![]);
+        timers.stop();
+    }
+
+    #[test]
+    fn periodic_timer_fires_every_period_within_a_step() {
+        let timers = VirtualTimers::start();
+        timers.arm(1, 5, true);
+        timers.advance(12);
+        assert_eq!(timers.expired(), vec![(1, 1), (1, 2)]);
+        timers.advance(3);
+        assert_eq!(timers.expired(), vec![(1, 3)]);
+        timers.send(IttiMsg::TimerCancel(TimerCancelReq { id: 1, target_tag: TARGET }));
+        timers.advance(60);
+        assert_eq!(timers.expired(), vec![]);
+        timers.stop();
+    }
+
+    #[test]
+    fn cancelled_and_replaced_timers_do_not_fire() {
+        let timers = VirtualTimers::start();
+        timers.arm(1, 10, false);
+        timers.arm(2, 10, false);
+        timers.send(IttiMsg::TimerCancel(TimerCancelReq { id: 1, target_tag: TARGET }));
+        timers.advance(5);
+        // Restarting timer 2 moves its deadline to 5 + 10
+        timers.arm(2, 10, false);
+        timers.advance(9);
+        assert_eq!(timers.expired(), vec![]);
+        timers.advance(1);
+        assert_eq!(timers.expired(), vec![(2, 1)]);
+        timers.stop();
+    }
+}
@@ -0,0 +1,276 @@
+use std::{fs::File, io::{self, BufRead, BufReader, BufWriter, Write}, path::Path, sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{itti::Itti, msg::{IttiEnvelope, IttiMsg, IttiTaskId, IttiTrxTag}, timer::{Clock, SystemClock}};
+
+/// One line of a trace file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TraceRecord {
+    /// Microseconds since the recorder was created
+    pub at_us: u64,
+    pub src: Option<IttiTaskId>,
+    pub dst: Option<IttiTaskId>,
+    pub correlation_id: u64,
+    pub msg: IttiMsg,
+}
+
+/// Appends every envelope it is given to a JSON lines file.
+pub struct TraceRecorder {
+    start: Instant,
+    out: Mutex<BufWriter<File>>,
+}
+
+impl TraceRecorder {
+    pub fn create(path: impl AsRef<Path>) -> io::Result<TraceRecorder> {
+        Ok(TraceRecorder {
+            start: Instant::now(),
+            out: Mutex::new(BufWriter::new(File::create(path)?)),
+        })
+    }
+
+    pub fn record(&self, envelope: &IttiEnvelope) {
+        let record = TraceRecord {
+            at_us: envelope.header.enqueued_at.saturating_duration_since(self.start).as_micros() as u64,
+            src: envelope.header.src,
+            dst: envelope.header.dst,
+            correlation_id: envelope.header.correlation_id,
+            msg: envelope.msg.clone(),
+        };
+        let mut out = self.out.lock().unwrap();
+        // Flushed per record so the trace survives a crash of the process
+        let written = serde_json::to_writer(&mut *out, &record)
+            .map_err(io::Error::from)
+            .and_then(|_| out.write_all(b"\n"))
+            .and_then(|_| out.flush());
+        if let Err(e) = written {
+            println!("itti trace: {}", e);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum ReplaySpeed {
+    Original,
+    /// Original timing divided by the factor
+    Accelerated(f64),
+    AsFastAsPossible,
+}
+
+/// Feeds a recorded trace back into a running set of tasks.
+///
+/// By default only the messages that entered from outside the task set
+/// (no `src`) are replayed, since the tasks produce the others again. With
+/// `only_dst` every message recorded for that task is replayed instead, which
+/// drives a single task, e.g. the session manager, without its producers.
+/// Messages are sharded again on replay, so the instance counts may differ
+/// from the recording.
+pub struct TraceReplay {
+    pub records: Vec<TraceRecord>,
+    pub speed: ReplaySpeed,
+    pub only_dst: Option<IttiTrxTag>,
+    // Paces the replay, virtual time in tests
+    clock: Arc<dyn Clock>,
+}
+
+impl TraceReplay {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<TraceReplay> {
+        let mut records = vec![];
+        for line in BufReader::new(File::open(path)?).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            records.push(serde_json::from_str::<TraceRecord>(&line)?);
+        }
+        records.sort_by_key(|record| record.at_us);
+        Ok(TraceReplay {
+            records,
+            speed: ReplaySpeed::Original,
+            only_dst: None,
+            clock: Arc::new(SystemClock),
+        })
+    }
+
+    fn selected(&self, record: &TraceRecord) -> bool {
+        // The replay ends with a shutdown of its own
+        if record.msg.is_stop_thread() {
+            return false;
+        }
+        // Recorded once per instance, and sent to every instance again from
+        // the copy of instance 0
+        if record.msg.to_all_instances() && record.dst.is_some_and(|dst| dst.instance != 0) {
+            return false;
+        }
+        match self.only_dst {
+            Some(tag) => record.dst.map(|dst| dst.tag) == Some(tag),
+            None => record.src.is_none() && record.dst.is_some(),
+        }
+    }
+
+    /// Blocks until every selected record has been sent, returns how many were.
+    pub fn run(&self, itti: &Itti) -> usize {
+        let start = self.clock.now();
+        let mut sent = 0;
+        for record in self.records.iter().filter(|record| self.selected(record)) {
+            let offset = Duration::from_micros(record.at_us);
+            let due = match self.speed {
+                ReplaySpeed::Original => Some(offset),
+                ReplaySpeed::Accelerated(factor) if factor > 0.0 => Some(offset.div_f64(factor)),
+                ReplaySpeed::Accelerated(_) | ReplaySpeed::AsFastAsPossible => None,
+            };
+            if let Some(due) = due {
+                let elapsed = self.clock.now().saturating_duration_since(start);
+                if due > elapsed {
+                    self.clock.sleep(due - elapsed);
+                }
+            }
+            // Routed again, as the instances may not be those of the recording
+            let envelope = itti.envelope(record.src, None, record.msg.clone());
+            let result = match record.dst {
+                Some(dst) => itti.send_routed(dst.tag, envelope),
+                None => itti.send_envelope(envelope),
+            };
+            match result {
+                Ok(_) => sent += 1,
+                Err(e) => println!("itti replay: record {} not sent: {}", record.correlation_id, e),
+            }
+        }
+        sent
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{itti::Mailbox, timer::MockClock};
+
+    const TUN: IttiTaskId = IttiTaskId { tag: IttiTrxTag::Tun, instance: 0 };
+    const TIMER: IttiTaskId = IttiTaskId { tag: IttiTrxTag::Timer, instance: 0 };
+    const MGMT: IttiTaskId = IttiTaskId { tag: IttiTrxTag::PduSessionMgmt, instance: 0 };
+
+    /// Virtual time that also keeps how long each wait was.
+    #[derive(Default)]
+    struct LoggedClock {
+        clock: MockClock,
+        sleeps: Mutex<Vec<Duration>>,
+    }
+
+    impl Clock for LoggedClock {
+        fn now(&self) -> Instant {
+            self.clock.now()
+        }
+
+        fn sleep(&self, duration: Duration) {
+            self.sleeps.lock().unwrap().push(duration);
+            self.clock.sleep(duration);
+        }
+    }
+
+    fn trace_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("trace-{}-{}.jsonl", std::process::id(), name))
+    }
+
+    fn record(at_ms: u64, src: Option<IttiTaskId>, dst: IttiTaskId, msg: IttiMsg) -> TraceRecord {
+        TraceRecord { at_us: at_ms * 1000, src, dst: Some(dst), correlation_id: at_ms, msg }
+    }
+
+    fn replay(records: Vec<TraceRecord>, speed: ReplaySpeed, clock: Arc<dyn Clock>) -> TraceReplay {
+        TraceReplay { records, speed, only_dst: None, clock }
+    }
+
+    fn tun_sessions_down(mailbox: &Mailbox) -> Vec<u8> {
+        let mut ids = vec![];
+        while let Ok(envelope) = mailbox.recv_timeout(Duration::from_millis(10)) {
+            match envelope.msg {
+                IttiMsg::TunSessionDown(id) => ids.push(id),
+                msg => panic!("unexpected {:?}", msg),
+            }
+        }
+        ids
+    }
+
+    #[test]
+    fn recorder_writes_one_line_per_envelope() {
+        let path = trace_path("record");
+        let itti = Itti::new();
+        let tun = itti.register(TUN);
+        let _timer = itti.register(TIMER);
+        itti.set_trace(TraceRecorder::create(&path).unwrap());
+        itti.send_to(TUN, IttiMsg::TunSessionDown(1)).unwrap();
+        itti.send_from(MGMT, IttiTrxTag::Tun, IttiMsg::TunSessionDown(2)).unwrap();
+        itti.send_to(TIMER, IttiMsg::TimerAdvance(Duration::from_secs(1))).unwrap();
+        assert_eq!(tun_sessions_down(&tun), vec![1, 2]);
+        let data = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = data.lines().collect();
+        assert_eq!(lines.len(), 3);
+        let records: Vec<TraceRecord> = lines.iter().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(records.iter().map(|record| (record.src, record.dst)).collect::<Vec<_>>(),
+            vec![(None, Some(TUN)), (Some(MGMT), Some(TUN)), (None, Some(TIMER))]);
+        assert!(matches!(records[2].msg, IttiMsg::TimerAdvance(advance) if advance == Duration::from_secs(1)));
+        assert!(records.windows(2).all(|pair| pair[0].correlation_id < pair[1].correlation_id && pair[0].at_us <= pair[1].at_us));
+        // And is read back as it was written
+        assert_eq!(TraceReplay::open(&path).unwrap().records.len(), 3);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn replay_delivers_records_in_recorded_order() {
+        let path = trace_path("order");
+        // Lines of several writers may interleave out of order
+        let lines: Vec<String> = [30, 10, 20]
+            .into_iter()
+            .map(|at_ms| serde_json::to_string(&record(at_ms, None, TUN, IttiMsg::TunSessionDown(at_ms as u8))).unwrap())
+            .collect();
+        std::fs::write(&path, lines.join("\n\n") + "\n").unwrap();
+        let mut trace = TraceReplay::open(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        trace.speed = ReplaySpeed::AsFastAsPossible;
+        let itti = Itti::new();
+        let tun = itti.register(TUN);
+        assert_eq!(trace.run(&itti), 3);
+        assert_eq!(tun_sessions_down(&tun), vec![10, 20, 30]);
+    }
+
+    #[test]
+    fn replay_selects_external_records_or_those_of_one_task() {
+        let records = vec![
+            record(1, None, TUN, IttiMsg::TunSessionDown(1)),
+            // Produced again by the session manager
+            record(2, Some(MGMT), TUN, IttiMsg::TunSessionDown(2)),
+            record(3, None, TIMER, IttiMsg::TimerAdvance(Duration::from_secs(1))),
+            record(4, None, TUN, IttiMsg::TunStopThread),
+        ];
+        let itti = Itti::new();
+        let tun = itti.register(TUN);
+        let timer = itti.register(TIMER);
+        let mut trace = replay(records, ReplaySpeed::AsFastAsPossible, Arc::new(MockClock::new()));
+        assert_eq!(trace.run(&itti), 2);
+        assert_eq!(tun_sessions_down(&tun), vec![1]);
+        assert!(matches!(timer.recv_timeout(Duration::from_millis(10)).unwrap().msg, IttiMsg::TimerAdvance(_)));
+        trace.only_dst = Some(IttiTrxTag::Tun);
+        assert_eq!(trace.run(&itti), 2);
+        assert_eq!(tun_sessions_down(&tun), vec![1, 2]);
+        assert!(timer.recv_timeout(Duration::from_millis(10)).is_err());
+    }
+
+    #[test]
+    fn replay_keeps_the_recorded_pace_in_virtual_time() {
+        let records: Vec<TraceRecord> = [0, 1000, 3000].into_iter().map(|at_ms| record(at_ms, None, TUN, IttiMsg::TunSessionDown(0))).collect();
+        let itti = Itti::new();
+        let _tun = itti.register(TUN);
+        for (speed, sleeps) in [
+            (ReplaySpeed::Original, vec![Duration::from_secs(1), Duration::from_secs(2)]),
+            (ReplaySpeed::Accelerated(2.0), vec![Duration::from_millis(500), Duration::from_secs(1)]),
+            (ReplaySpeed::Accelerated(0.0), vec![]),
+            (ReplaySpeed::AsFastAsPossible, vec![]),
+        ] {
+            let clock = Arc::new(LoggedClock::default());
+            let start = clock.now();
+            assert_eq!(replay(records.clone(), speed, clock.clone()).run(&itti), 3);
+            let total: Duration = sleeps.iter().sum();
+            assert_eq!(*clock.sleeps.lock().unwrap(), sleeps, "{:?}", speed);
+            assert_eq!(clock.now() - start, total, "{:?}", speed);
+        }
+    }
+}
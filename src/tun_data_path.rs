@@ -0,0 +1,407 @@
+use std::{collections::HashMap, io, net::{IpAddr, Ipv4Addr, Ipv6Addr}, sync::{Arc, RwLock, atomic::{AtomicBool, Ordering}}, thread::{self, JoinHandle}, time::Duration};
+
+use bytes::{Bytes, BytesMut};
+
+use crate::{config::{TunConfig, TunMode}, itti::{Itti, Mailbox}, msg::{GtpDirection, IttiMsg, IttiTaskId, IttiTrxTag, TunSessionCfg, UdpGtpBuffer}, rtnetlink::{link_index, RtNetlink}, tun_device::{add_ipv6_address, del_ipv6_address, set_ipv4_address, set_mtu, set_up, TunDevice}};
+
+/// Largest packet the kernel can hand over, whatever the MTU.
+const TUN_MAX_PACKET: usize = 65535;
+/// Packets are received back to back into chunks of this size.
+const TUN_RECV_CHUNK: usize = 16 * TUN_MAX_PACKET;
+/// How often a reader looks at its stop flag while the interface is idle.
+const TUN_POLL_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// PDU session of each UE address, IPv6 by interface identifier.
+type TunSessions = Arc<RwLock<HashMap<IpAddr, u8>>>;
+
+/// The network only assigns the interface identifier, so IPv6 sources are
+/// matched on their lower 64 bits whatever the prefix.
+fn session_key(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V4(_) => addr,
+        IpAddr::V6(ipv6) => IpAddr::V6(Ipv6Addr::from(u128::from(ipv6) & u64::MAX as u128)),
+    }
+}
+
+fn link_local(interface_identifier: Ipv6Addr) -> Ipv6Addr {
+    Ipv6Addr::from((0xfe80u128 << 112) | (u128::from(interface_identifier) & u64::MAX as u128))
+}
+
+fn source_address(packet: &[u8]) -> Option<IpAddr> {
+    match packet.first()? >> 4 {
+        4 if packet.len() >= 20 => {
+            let src: [u8; 4] = packet[12..16].try_into().ok()?;
+            Some(IpAddr::V4(Ipv4Addr::from(src)))
+        },
+        6 if packet.len() >= 40 => {
+            let src: [u8; 16] = packet[8..24].try_into().ok()?;
+            Some(IpAddr::V6(Ipv6Addr::from(src)))
+        },
+        _ => None,
+    }
+}
+
+/// Reads one interface on its own thread and sends every packet up as the
+/// uplink of the session owning its source address.
+struct TunReceiver {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl TunReceiver {
+    fn spawn(itti: Arc<Itti>, task: IttiTaskId, device: Arc<TunDevice>, sessions: TunSessions) -> io::Result<TunReceiver> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let handle = thread::Builder::new()
+            .name(format!("tun-rx-{}", device.name()))
+            .spawn(move || {
+                // Split off a shared chunk, see the GTP-U receiver
+                let mut packet = BytesMut::new();
+                while !stopped.load(Ordering::Relaxed) {
+                    match device.poll_readable(TUN_POLL_TIMEOUT) {
+                        Ok(true) => {},
+                        Ok(false) => continue,
+                        Err(e) => {
+                            println!("tun {}: {}", device.name(), e);
+                            break;
+                        },
+                    }
+                    if packet.capacity() < TUN_MAX_PACKET {
+                        packet.clear();
+                        packet.reserve(TUN_RECV_CHUNK);
+                    }
+                    packet.resize(TUN_MAX_PACKET, 0);
+                    match device.recv(&mut packet) {
+                        Ok(len) => on_packet(&itti, task, &sessions, packet.split_to(len).freeze()),
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::Interrupted => {},
+                        Err(e) => println!("tun {} receive: {}", device.name(), e),
+                    }
+                }
+            })?;
+        Ok(TunReceiver { stop, handle: Some(handle) })
+    }
+}
+
+impl Drop for TunReceiver {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn on_packet(itti: &Itti, task: IttiTaskId, sessions: &TunSessions, payload: Bytes) {
+    let src = match source_address(&payload) {
+        Some(src) => src,
+        None => return,
+    };
+    let pdu_session_id = match sessions.read().unwrap().get(&session_key(src)) {
+        Some(pdu_session_id) => *pdu_session_id,
+        // Kernel chatter from addresses no session owns
+        None => return,
+    };
+    let buffer = UdpGtpBuffer {
+        teid: 0,
+        pdu_session_id,
+        qfi: None,
+        rqi: false,
+        peer: None,
+        direction: GtpDirection::Uplink,
+        payload,
+    };
+    if let Err(e) = itti.send_from(task, IttiTrxTag::PduSessionMgmt, IttiMsg::PduSessionMgmtSendUplink(buffer)) {
+        println!("tun: uplink of pdu session {} dropped: {}", pdu_session_id, e);
+    }
+}
+
+struct TunInterface {
+    // Only kept to be dropped first, the reader holds the device
+    _receiver: TunReceiver,
+    device: Arc<TunDevice>,
+}
+
+/// Data path of the PDU sessions on the host: brings up a TUN interface
+/// with the address and MTU of each accepted session, sends what the host
+/// writes to it as uplink and writes the downlink of the session to it.
+///
+/// Only needs CAP_NET_ADMIN, so it can run unprivileged in its own user and
+/// network namespace, e.g. `unshare -rn rust_itti --config tun.json`.
+pub struct TunDataPath {
+    config: TunConfig,
+    sessions: TunSessions,
+    // By PDU session ID, or the one shared interface under 0
+    interfaces: HashMap<u8, TunInterface>,
+    up: HashMap<u8, TunSessionCfg>,
+    // Policy routing, opened with the first session that has a table
+    netlink: Option<RtNetlink>,
+}
+
+impl TunDataPath {
+    pub fn new(config: TunConfig) -> TunDataPath {
+        TunDataPath {
+            config,
+            sessions: Arc::new(RwLock::new(HashMap::new())),
+            interfaces: HashMap::new(),
+            up: HashMap::new(),
+            netlink: None,
+        }
+    }
+
+    fn interface_id(&self, pdu_session_id: u8) -> u8 {
+        match self.config.mode {
+            TunMode::PerSession => pdu_session_id,
+            TunMode::Shared => 0,
+        }
+    }
+
+    /// None as well when `route_table + id` is past the last table.
+    fn route_table(&self, pdu_session_id: u8) -> Option<u32> {
+        let table = self.config.route_table?;
+        match self.config.mode {
+            TunMode::PerSession => table.checked_add(pdu_session_id as u32),
+            TunMode::Shared => Some(table),
+        }
+    }
+
+    fn netlink(&mut self) -> io::Result<&mut RtNetlink> {
+        if self.netlink.is_none() {
+            self.netlink = Some(RtNetlink::open()?);
+        }
+        Ok(self.netlink.as_mut().unwrap())
+    }
+
+    /// Traffic from the session address goes out of its interface whatever
+    /// the main table says.
+    fn add_policy_route(&mut self, name: &str, ipv4: Ipv4Addr, table: u32) -> io::Result<()> {
+        let index = link_index(name)?;
+        let netlink = self.netlink()?;
+        netlink.add_route(index, IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0, table, true)?;
+        netlink.add_rule(IpAddr::V4(ipv4), table)
+    }
+
+    fn open_interface(&self, itti: &Arc<Itti>, task: IttiTaskId, name: &str, mtu: u16) -> io::Result<TunInterface> {
+        let device = Arc::new(TunDevice::create(name)?);
+        set_mtu(device.name(), mtu)?;
+        set_up(device.name(), true)?;
+        let receiver = TunReceiver::spawn(itti.clone(), task, device.clone(), self.sessions.clone())?;
+        println!("tun: {} up, mtu {}", device.name(), mtu);
+        Ok(TunInterface { _receiver: receiver, device })
+    }
+
+    fn session_up(&mut self, itti: &Arc<Itti>, task: IttiTaskId, cfg: TunSessionCfg) {
+        // A session set up again is replaced
+        self.session_down(cfg.pdu_session_id);
+        let id = self.interface_id(cfg.pdu_session_id);
+        if !self.interfaces.contains_key(&id) {
+            let (name, mtu) = match self.config.mode {
+                TunMode::PerSession => (format!("{}{}", self.config.name, cfg.pdu_session_id), cfg.mtu.unwrap_or(self.config.mtu)),
+                TunMode::Shared => (self.config.name.clone(), self.config.mtu),
+            };
+            match self.open_interface(itti, task, &name, mtu) {
+                Ok(interface) => {
+                    self.interfaces.insert(id, interface);
+                },
+                Err(e) => {
+                    println!("tun: {} for pdu session {} not created: {}", name, cfg.pdu_session_id, e);
+                    return;
+                },
+            }
+        }
+        let name = self.interfaces[&id].device.name().to_string();
+        if let Some(ipv4) = cfg.ipv4 {
+            let label = match self.config.mode {
+                TunMode::PerSession => name.clone(),
+                TunMode::Shared => format!("{}:{}", name, cfg.pdu_session_id),
+            };
+            if let Err(e) = set_ipv4_address(&label, ipv4, 32) {
+                println!("tun: {} address {}: {}", label, ipv4, e);
+            }
+            match self.route_table(cfg.pdu_session_id) {
+                Some(table) => {
+                    if let Err(e) = self.add_policy_route(&name, ipv4, table) {
+                        println!("tun: {} routing from {} in table {}: {}", name, ipv4, table, e);
+                    }
+                },
+                None if self.config.route_table.is_some() => println!("tun: no routing table left for pdu session {}", cfg.pdu_session_id),
+                None => {},
+            }
+        }
+        if let Some(ipv6) = cfg.ipv6 {
+            if let Err(e) = add_ipv6_address(&name, link_local(ipv6), 64) {
+                println!("tun: {} address {}: {}", name, link_local(ipv6), e);
+            }
+        }
+        {
+            let mut sessions = self.sessions.write().unwrap();
+            for addr in cfg.ipv4.map(IpAddr::V4).into_iter().chain(cfg.ipv6.map(IpAddr::V6)) {
+                sessions.insert(session_key(addr), cfg.pdu_session_id);
+            }
+        }
+        println!("tun: pdu session {} on {}, ipv4 {:?}, ipv6 {:?}, dns {:?}", cfg.pdu_session_id, name, cfg.ipv4, cfg.ipv6, cfg.dns);
+        self.up.insert(cfg.pdu_session_id, cfg);
+    }
+
+    fn session_down(&mut self, pdu_session_id: u8) {
+        let cfg = match self.up.remove(&pdu_session_id) {
+            Some(cfg) => cfg,
+            None => return,
+        };
+        self.sessions.write().unwrap().retain(|_, owner| *owner != pdu_session_id);
+        // The default route of the table goes with its interface, or is
+        // shared by every session
+        if let (Some(ipv4), Some(table)) = (cfg.ipv4, self.route_table(pdu_session_id)) {
+            if let Err(e) = self.netlink().and_then(|netlink| netlink.del_rule(IpAddr::V4(ipv4), table)) {
+                println!("tun: rule from {} to table {} not removed: {}", ipv4, table, e);
+            }
+        }
+        let id = self.interface_id(pdu_session_id);
+        match self.config.mode {
+            TunMode::PerSession => {
+                if let Some(interface) = self.interfaces.remove(&id) {
+                    println!("tun: {} down", interface.device.name());
+                }
+            },
+            TunMode::Shared => {
+                let name = match self.interfaces.get(&id) {
+                    Some(interface) => interface.device.name().to_string(),
+                    None => return,
+                };
+                if cfg.ipv4.is_some() {
+                    let label = format!("{}:{}", name, pdu_session_id);
+                    if let Err(e) = set_up(&label, false) {
+                        println!("tun: {} not removed: {}", label, e);
+                    }
+                }
+                if let Some(ipv6) = cfg.ipv6 {
+                    let _ = del_ipv6_address(&name, link_local(ipv6), 64);
+                }
+            },
+        }
+        println!("tun: pdu session {} down", pdu_session_id);
+    }
+
+    fn send_downlink(&self, buffer: &UdpGtpBuffer) {
+        let interface = match self.interfaces.get(&self.interface_id(buffer.pdu_session_id)) {
+            Some(interface) if self.up.contains_key(&buffer.pdu_session_id) => interface,
+            _ => {
+                println!("tun: downlink for pdu session {} without interface dropped", buffer.pdu_session_id);
+                return;
+            },
+        };
+        if let Err(e) = interface.device.send(&buffer.payload) {
+            println!("tun {} send: {}", interface.device.name(), e);
+        }
+    }
+
+    pub fn init_tun_task(mut self, itti: Arc<Itti>, mailbox: Mailbox) {
+        while let Ok(envelope) = mailbox.recv() {
+            match envelope.msg {
+                IttiMsg::TunSessionUp(cfg) => self.session_up(&itti, mailbox.id, cfg),
+                IttiMsg::TunSessionDown(pdu_session_id) => self.session_down(pdu_session_id),
+                IttiMsg::TunSendDownlink(buffer) => self.send_downlink(&buffer),
+                IttiMsg::TunStopThread => {
+                    break;
+                },
+                msg => {println!("{:#?}", msg);},
+            }
+        }
+        let up: Vec<u8> = self.up.keys().copied().collect();
+        for pdu_session_id in up {
+            self.session_down(pdu_session_id);
+        }
+        self.interfaces.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+
+    use super::*;
+
+    fn config(mode: TunMode, route_table: Option<u32>) -> TunConfig {
+        TunConfig {
+            mode,
+            name: "pdutest".to_string(),
+            mtu: 1400,
+            route_table,
+            mailbox: crate::itti::MailboxConfig::unbounded(),
+            restart: Default::default(),
+        }
+    }
+
+    fn ipv6_packet(src: Ipv6Addr) -> Vec<u8> {
+        let mut packet = vec![0x60, 0, 0, 0, 0, 0, 17, 64];
+        packet.extend_from_slice(&src.octets());
+        packet.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        packet
+    }
+
+    #[test]
+    fn source_address_of_ipv4_and_ipv6_packets() {
+        let mut ipv4 = vec![0x45, 0, 0, 20, 0, 0, 0, 0, 64, 17, 0, 0, 10, 45, 0, 2, 8, 8, 8, 8];
+        assert_eq!(source_address(&ipv4), Some(IpAddr::from([10, 45, 0, 2])));
+        assert_eq!(source_address(&ipv4[..19]), None);
+        let ipv6 = ipv6_packet("2001:db8::1".parse().unwrap());
+        assert_eq!(source_address(&ipv6), Some("2001:db8::1".parse().unwrap()));
+        assert_eq!(source_address(&ipv6[..39]), None);
+        ipv4[0] = 0x55;
+        assert_eq!(source_address(&ipv4), None);
+        assert_eq!(source_address(&[]), None);
+    }
+
+    #[test]
+    fn ipv6_sources_match_on_the_interface_identifier() {
+        let iid: Ipv6Addr = "::1234:5678:9abc:def0".parse().unwrap();
+        let key = session_key(IpAddr::V6(iid));
+        for src in ["2001:db8::1234:5678:9abc:def0", "fd00:1:2:3:1234:5678:9abc:def0"] {
+            let src = source_address(&ipv6_packet(src.parse().unwrap())).unwrap();
+            assert_eq!(session_key(src), key);
+        }
+        assert_ne!(session_key("2001:db8::1234:5678:9abc:def1".parse().unwrap()), key);
+        assert_eq!(link_local(iid), "fe80::1234:5678:9abc:def0".parse::<Ipv6Addr>().unwrap());
+        let ipv4 = IpAddr::from([10, 45, 0, 2]);
+        assert_eq!(session_key(ipv4), ipv4);
+    }
+
+    #[test]
+    fn route_table_per_session_or_shared() {
+        assert_eq!(TunDataPath::new(config(TunMode::PerSession, None)).route_table(1), None);
+        assert_eq!(TunDataPath::new(config(TunMode::PerSession, Some(100))).route_table(5), Some(105));
+        assert_eq!(TunDataPath::new(config(TunMode::Shared, Some(100))).route_table(5), Some(100));
+        assert_eq!(TunDataPath::new(config(TunMode::PerSession, Some(u32::MAX - 4))).route_table(4), Some(u32::MAX));
+        assert_eq!(TunDataPath::new(config(TunMode::PerSession, Some(u32::MAX - 4))).route_table(5), None);
+    }
+
+    /// Creates interfaces, addresses, routes and rules, so it runs in a
+    /// network namespace of its own: `unshare -rn cargo test -- --ignored`
+    #[test]
+    #[ignore]
+    fn session_traffic_leaves_through_its_interface() {
+        let itti = Arc::new(Itti::new());
+        let task = IttiTaskId::new(IttiTrxTag::Tun, 0);
+        let uplink = itti.register(IttiTaskId::new(IttiTrxTag::PduSessionMgmt, 0));
+        // Past 255, so the table only fits the netlink attributes
+        let mut tun = TunDataPath::new(config(TunMode::PerSession, Some(1000)));
+        let ipv4 = Ipv4Addr::new(10, 45, 0, 2);
+        tun.session_up(&itti, task, TunSessionCfg { pdu_session_id: 1, ipv4: Some(ipv4), ipv6: None, dns: vec![], mtu: None });
+        assert!(link_index("pdutest1").is_ok());
+
+        // The namespace has no default route, only the rule of the session
+        // leads to one
+        let socket = UdpSocket::bind((ipv4, 0)).unwrap();
+        socket.send_to(b"uplink", "192.0.2.1:9").unwrap();
+        let buffer = match uplink.recv_timeout(Duration::from_secs(2)).unwrap().msg {
+            IttiMsg::PduSessionMgmtSendUplink(buffer) => buffer,
+            msg => panic!("unexpected {:?}", msg),
+        };
+        assert_eq!((buffer.pdu_session_id, source_address(&buffer.payload)), (1, Some(IpAddr::V4(ipv4))));
+        assert!(buffer.payload.ends_with(b"uplink"));
+
+        tun.session_down(1);
+        assert!(link_index("pdutest1").is_err());
+        let rule_left = tun.netlink().unwrap().del_rule(IpAddr::V4(ipv4), 1001);
+        assert_eq!(rule_left.map_err(|e| e.raw_os_error()), Err(Some(libc::ENOENT)));
+    }
+}
@@ -0,0 +1,155 @@
+use std::{fs::{File, OpenOptions}, io::{self, Read, Write}, mem, net::{Ipv4Addr, Ipv6Addr}, os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd}, time::Duration};
+
+const TUN_CLONE_DEVICE: &str = "/dev/net/tun";
+// _IOW('T', 202, int), not exported by every libc 0.2 release
+#[cfg(not(any(target_arch = "mips", target_arch = "mips64", target_arch = "powerpc", target_arch = "powerpc64", target_arch = "sparc64")))]
+const TUNSETIFF: libc::c_ulong = 0x400454ca;
+#[cfg(any(target_arch = "mips", target_arch = "mips64", target_arch = "powerpc", target_arch = "powerpc64", target_arch = "sparc64"))]
+const TUNSETIFF: libc::c_ulong = 0x800454ca;
+
+fn ifreq(name: &str) -> io::Result<libc::ifreq> {
+    if name.is_empty() || name.len() >= libc::IFNAMSIZ {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("bad interface name {:?}", name)));
+    }
+    let mut req: libc::ifreq = unsafe { mem::zeroed() };
+    for (dst, src) in req.ifr_name.iter_mut().zip(name.bytes()) {
+        *dst = src as libc::c_char;
+    }
+    Ok(req)
+}
+
+fn ioctl<T>(fd: RawFd, request: libc::c_ulong, arg: &mut T) -> io::Result<()> {
+    let ret = unsafe { libc::ioctl(fd, request as _, arg as *mut T) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Socket the interface ioctls are issued on.
+fn control_socket(family: libc::c_int) -> io::Result<OwnedFd> {
+    let fd = unsafe { libc::socket(family, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+fn sockaddr_v4(addr: Ipv4Addr) -> libc::sockaddr {
+    let sin = libc::sockaddr_in {
+        sin_family: libc::AF_INET as libc::sa_family_t,
+        sin_port: 0,
+        sin_addr: libc::in_addr { s_addr: u32::from_ne_bytes(addr.octets()) },
+        sin_zero: [0; 8],
+    };
+    // sockaddr_in and sockaddr have the same size
+    unsafe { mem::transmute(sin) }
+}
+
+/// Layer 3 TUN interface, packets are read and written without the packet
+/// information header. The interface goes away when the device is dropped.
+pub struct TunDevice {
+    file: File,
+    name: String,
+}
+
+impl TunDevice {
+    /// Creates the interface, or attaches to it if it exists and is free.
+    /// Needs CAP_NET_ADMIN, which an unprivileged user namespace provides.
+    pub fn create(name: &str) -> io::Result<TunDevice> {
+        let file = OpenOptions::new().read(true).write(true).open(TUN_CLONE_DEVICE)?;
+        let mut req = ifreq(name)?;
+        req.ifr_ifru.ifru_flags = (libc::IFF_TUN | libc::IFF_NO_PI) as libc::c_short;
+        ioctl(file.as_raw_fd(), TUNSETIFF, &mut req)?;
+        // The kernel may have completed a pattern such as `pdu%d`
+        let name = req.ifr_name.iter()
+            .take_while(|c| **c != 0)
+            .map(|c| *c as u8 as char)
+            .collect();
+        Ok(TunDevice { file, name })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Waits until a packet can be read, false on timeout.
+    pub fn poll_readable(&self, timeout: Duration) -> io::Result<bool> {
+        let mut fds = libc::pollfd { fd: self.file.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+        let ret = unsafe { libc::poll(&mut fds, 1, timeout.as_millis() as libc::c_int) };
+        match ret {
+            ret if ret < 0 => {
+                let e = io::Error::last_os_error();
+                match e.kind() {
+                    io::ErrorKind::Interrupted => Ok(false),
+                    _ => Err(e),
+                }
+            },
+            0 => Ok(false),
+            _ => Ok(true),
+        }
+    }
+
+    pub fn recv(&self, packet: &mut [u8]) -> io::Result<usize> {
+        (&self.file).read(packet)
+    }
+
+    pub fn send(&self, packet: &[u8]) -> io::Result<usize> {
+        (&self.file).write(packet)
+    }
+}
+
+/// Sets the MTU of an interface.
+pub fn set_mtu(name: &str, mtu: u16) -> io::Result<()> {
+    let socket = control_socket(libc::AF_INET)?;
+    let mut req = ifreq(name)?;
+    req.ifr_ifru.ifru_mtu = mtu as libc::c_int;
+    ioctl(socket.as_raw_fd(), libc::SIOCSIFMTU, &mut req)
+}
+
+/// Brings an interface up or down. Taking down an alias label such as
+/// `pdu:1` deletes its address.
+pub fn set_up(name: &str, up: bool) -> io::Result<()> {
+    let socket = control_socket(libc::AF_INET)?;
+    let mut req = ifreq(name)?;
+    ioctl(socket.as_raw_fd(), libc::SIOCGIFFLAGS, &mut req)?;
+    unsafe {
+        match up {
+            true => req.ifr_ifru.ifru_flags |= (libc::IFF_UP | libc::IFF_RUNNING) as libc::c_short,
+            false => req.ifr_ifru.ifru_flags &= !(libc::IFF_UP as libc::c_short),
+        }
+    }
+    ioctl(socket.as_raw_fd(), libc::SIOCSIFFLAGS, &mut req)
+}
+
+/// Sets the IPv4 address of an interface, or adds one under an alias label
+/// such as `pdu:1`.
+pub fn set_ipv4_address(label: &str, addr: Ipv4Addr, prefix_len: u8) -> io::Result<()> {
+    let socket = control_socket(libc::AF_INET)?;
+    let mut req = ifreq(label)?;
+    req.ifr_ifru.ifru_addr = sockaddr_v4(addr);
+    ioctl(socket.as_raw_fd(), libc::SIOCSIFADDR, &mut req)?;
+    let mask = u32::MAX.checked_shl(32 - prefix_len.min(32) as u32).unwrap_or(0);
+    req.ifr_ifru.ifru_netmask = sockaddr_v4(Ipv4Addr::from(mask));
+    ioctl(socket.as_raw_fd(), libc::SIOCSIFNETMASK, &mut req)
+}
+
+fn ipv6_address(name: &str, addr: Ipv6Addr, prefix_len: u8, request: libc::c_ulong) -> io::Result<()> {
+    let socket = control_socket(libc::AF_INET6)?;
+    let mut req = ifreq(name)?;
+    ioctl(socket.as_raw_fd(), libc::SIOCGIFINDEX, &mut req)?;
+    let mut req6 = libc::in6_ifreq {
+        ifr6_addr: libc::in6_addr { s6_addr: addr.octets() },
+        ifr6_prefixlen: prefix_len as u32,
+        ifr6_ifindex: unsafe { req.ifr_ifru.ifru_ifindex },
+    };
+    ioctl(socket.as_raw_fd(), request, &mut req6)
+}
+
+pub fn add_ipv6_address(name: &str, addr: Ipv6Addr, prefix_len: u8) -> io::Result<()> {
+    ipv6_address(name, addr, prefix_len, libc::SIOCSIFADDR)
+}
+
+pub fn del_ipv6_address(name: &str, addr: Ipv6Addr, prefix_len: u8) -> io::Result<()> {
+    ipv6_address(name, addr, prefix_len, libc::SIOCDIFADDR)
+}
@@ -0,0 +1,324 @@
+use std::{collections::{hash_map::RandomState, HashMap, HashSet, VecDeque}, hash::{BuildHasher, Hasher}, net::{IpAddr, SocketAddr}, sync::{atomic::Ordering, Arc, Mutex}, time::{Duration, Instant}};
+
+use crossbeam::epoch::{self, Atomic, Owned};
+
+use crate::{gtp_helper::gtpu::{Teid, GTPU_PORT}, timer::{Clock, SystemClock}};
+
+/// How long a released TEID is kept back, so late G-PDUs of the old session
+/// are not delivered to a new one.
+pub const TEID_REUSE_GRACE: Duration = Duration::from_secs(60);
+
+/// 3GPP TS 29281 5.1: TEID and address of the remote end of a tunnel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FTeid {
+    pub teid: Teid,
+    pub addr: IpAddr,
+}
+
+impl FTeid {
+    pub fn socket_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.addr, GTPU_PORT)
+    }
+}
+
+/// What a local TEID is bound to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TunnelEntry {
+    pub pdu_session_id: u8,
+    pub qfis: Vec<u8>,
+    /// Unknown until the remote F-TEID is signalled
+    pub remote: Option<FTeid>,
+    /// Remote replaced by a handover, which may still send until its End Marker
+    pub draining: Option<FTeid>,
+}
+
+struct TeidAllocator {
+    next: Teid,
+    // Released TEIDs by the time they may be handed out again, oldest first
+    released: VecDeque<(Instant, Teid)>,
+    quarantined: HashMap<Teid, Instant>,
+}
+
+impl TeidAllocator {
+    /// Starts at a random TEID, so they cannot be guessed from the start of
+    /// the allocation.
+    fn new() -> TeidAllocator {
+        let next = match RandomState::new().build_hasher().finish() as Teid {
+            0 => 1,
+            next => next,
+        };
+        TeidAllocator { next, released: VecDeque::new(), quarantined: HashMap::new() }
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some(&(until, teid)) = self.released.front() {
+            if until > now {
+                break;
+            }
+            self.released.pop_front();
+            // A TEID bound and released again since has a later deadline
+            if self.quarantined.get(&teid) == Some(&until) {
+                self.quarantined.remove(&teid);
+            }
+        }
+    }
+
+    /// TEID 0 is left out, it is the one of the path messages.
+    fn allocate(&mut self, entries: &HashMap<Teid, TunnelEntry>) -> Option<Teid> {
+        for _ in 0..=entries.len() + self.quarantined.len() {
+            let teid = self.next;
+            self.next = match self.next.wrapping_add(1) {
+                0 => 1,
+                next => next,
+            };
+            if !entries.contains_key(&teid) && !self.quarantined.contains_key(&teid) {
+                return Some(teid);
+            }
+        }
+        None
+    }
+}
+
+/// Local TEIDs of the N3 tunnels, shared by the session managers that fill
+/// it and the GTP-U task that reads it for every packet. Readers never lock:
+/// writers copy the map, change the copy and swap it in, and the old map is
+/// freed once no reader still holds it.
+pub struct TunnelTable {
+    entries: Atomic<HashMap<Teid, TunnelEntry>>,
+    allocator: Mutex<TeidAllocator>,
+    clock: Arc<dyn Clock>,
+    grace: Duration,
+}
+
+impl Default for TunnelTable {
+    fn default() -> TunnelTable {
+        TunnelTable::with_clock(Arc::new(SystemClock), TEID_REUSE_GRACE)
+    }
+}
+
+impl TunnelTable {
+    pub fn with_clock(clock: Arc<dyn Clock>, grace: Duration) -> TunnelTable {
+        TunnelTable {
+            entries: Atomic::new(HashMap::new()),
+            allocator: Mutex::new(TeidAllocator::new()),
+            clock,
+            grace,
+        }
+    }
+
+    fn update<R>(&self, f: impl FnOnce(&mut TeidAllocator, &mut HashMap<Teid, TunnelEntry>) -> R) -> R {
+        // The allocator lock also serialises the writers
+        let mut allocator = self.allocator.lock().unwrap();
+        let guard = epoch::pin();
+        let current = self.entries.load(Ordering::Acquire, &guard);
+        // Safety: the map is never null, and cannot be freed while pinned
+        let mut entries = unsafe { current.deref() }.clone();
+        let result = f(&mut allocator, &mut entries);
+        let replaced = self.entries.swap(Owned::new(entries), Ordering::AcqRel, &guard);
+        // Safety: unreachable from the table now, readers pinned before the
+        // swap keep it alive until they unpin
+        unsafe { guard.defer_destroy(replaced) };
+        result
+    }
+
+    pub fn lookup<R>(&self, teid: Teid, f: impl FnOnce(&TunnelEntry) -> R) -> Option<R> {
+        let guard = epoch::pin();
+        // Safety: see `update`
+        let entries = unsafe { self.entries.load(Ordering::Acquire, &guard).deref() };
+        entries.get(&teid).map(f)
+    }
+
+    /// Local TEID and session of the tunnel whose remote end is `remote`.
+    pub fn find_by_remote(&self, remote: FTeid) -> Option<(Teid, u8)> {
+        let guard = epoch::pin();
+        let entries = unsafe { self.entries.load(Ordering::Acquire, &guard).deref() };
+        entries.iter()
+            .find(|(_, entry)| entry.remote == Some(remote))
+            .map(|(teid, entry)| (*teid, entry.pdu_session_id))
+    }
+
+    /// Addresses of every remote end, current or draining.
+    pub fn remote_peers(&self) -> HashSet<IpAddr> {
+        let guard = epoch::pin();
+        let entries = unsafe { self.entries.load(Ordering::Acquire, &guard).deref() };
+        entries.values()
+            .flat_map(|entry| entry.remote.iter().chain(entry.draining.iter()))
+            .map(|remote| remote.addr)
+            .collect()
+    }
+
+    /// Binds a new local TEID to the session, `None` once every TEID is taken.
+    pub fn allocate(&self, pdu_session_id: u8, qfis: Vec<u8>) -> Option<Teid> {
+        let now = self.clock.now();
+        self.update(|allocator, entries| {
+            allocator.expire(now);
+            let teid = allocator.allocate(entries)?;
+            entries.insert(teid, TunnelEntry { pdu_session_id, qfis, remote: None, draining: None });
+            Some(teid)
+        })
+    }
+
+    /// Binds a TEID that was allocated before, by a session restored from a
+    /// snapshot. False when the TEID is bound already, possibly by another
+    /// instance since session ids are only unique per instance, which keeps it.
+    pub fn bind(&self, teid: Teid, pdu_session_id: u8, qfis: Vec<u8>, remote: Option<FTeid>) -> bool {
+        self.update(|allocator, entries| {
+            if entries.contains_key(&teid) {
+                return false;
+            }
+            allocator.quarantined.remove(&teid);
+            entries.insert(teid, TunnelEntry { pdu_session_id, qfis, remote, draining: None });
+            true
+        })
+    }
+
+    /// Points the tunnel at a new remote end. The one it replaces keeps
+    /// draining until its End Marker. False for an unknown TEID.
+    pub fn set_remote(&self, teid: Teid, remote: FTeid) -> bool {
+        self.update(|_, entries| match entries.get_mut(&teid) {
+            Some(entry) => {
+                match entry.remote {
+                    Some(old) if old != remote => entry.draining = Some(old),
+                    _ => {},
+                }
+                entry.remote = Some(remote);
+                true
+            },
+            None => false,
+        })
+    }
+
+    /// Keeps the TEID bound but stops sending on the tunnel.
+    pub fn clear_remote(&self, teid: Teid) {
+        self.update(|_, entries| {
+            if let Some(entry) = entries.get_mut(&teid) {
+                entry.remote = None;
+                entry.draining = None;
+            }
+        })
+    }
+
+    /// The End Marker of the old path came, returns the remote it flushed.
+    pub fn flush_draining(&self, teid: Teid) -> Option<FTeid> {
+        if self.lookup(teid, |entry| entry.draining.is_none()).unwrap_or(true) {
+            return None;
+        }
+        self.update(|_, entries| entries.get_mut(&teid).and_then(|entry| entry.draining.take()))
+    }
+
+    /// Unbinds the TEID, which is not allocated again before the grace period.
+    pub fn release(&self, teid: Teid) -> Option<TunnelEntry> {
+        let until = self.clock.now() + self.grace;
+        self.update(|allocator, entries| {
+            let entry = entries.remove(&teid)?;
+            allocator.released.push_back((until, teid));
+            allocator.quarantined.insert(teid, until);
+            Some(entry)
+        })
+    }
+}
+
+impl Drop for TunnelTable {
+    fn drop(&mut self) {
+        // Safety: no reader is left once the table itself is dropped
+        unsafe { drop(self.entries.load(Ordering::Relaxed, epoch::unprotected()).into_owned()) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timer::MockClock;
+
+    const GRACE: Duration = Duration::from_secs(10);
+
+    fn remote(teid: Teid, last_octet: u8) -> FTeid {
+        FTeid { teid, addr: IpAddr::from([192, 0, 2, last_octet]) }
+    }
+
+    #[test]
+    fn teid_0_is_never_allocated() {
+        let table = TunnelTable::default();
+        table.allocator.lock().unwrap().next = Teid::MAX;
+        assert_eq!(table.allocate(1, vec![]), Some(Teid::MAX));
+        assert_eq!(table.allocate(1, vec![]), Some(1));
+        assert_eq!(table.lookup(0, |_| ()), None);
+    }
+
+    #[test]
+    fn released_teid_is_not_reused_within_the_grace_period() {
+        let clock = Arc::new(MockClock::new());
+        let table = TunnelTable::with_clock(clock.clone(), GRACE);
+        table.allocator.lock().unwrap().next = 1;
+        assert_eq!(table.allocate(1, vec![9]), Some(1));
+        assert_eq!(table.allocate(2, vec![9]), Some(2));
+        assert_eq!(table.release(1).map(|entry| entry.pdu_session_id), Some(1));
+        assert_eq!(table.release(1), None);
+        // Wrap around so that 1 is next in line again
+        table.allocator.lock().unwrap().next = 1;
+        assert_eq!(table.allocate(3, vec![9]), Some(3));
+        clock.advance(GRACE - Duration::from_secs(1));
+        table.allocator.lock().unwrap().next = 1;
+        assert_eq!(table.allocate(4, vec![9]), Some(4));
+        clock.advance(Duration::from_secs(1));
+        table.allocator.lock().unwrap().next = 1;
+        assert_eq!(table.allocate(5, vec![9]), Some(1));
+        assert_eq!(table.lookup(1, |entry| entry.pdu_session_id), Some(5));
+    }
+
+    #[test]
+    fn teid_released_again_keeps_its_later_deadline() {
+        let clock = Arc::new(MockClock::new());
+        let table = TunnelTable::with_clock(clock.clone(), GRACE);
+        let teid = table.allocate(1, vec![]).unwrap();
+        table.release(teid);
+        clock.advance(GRACE / 2);
+        // Restored from a snapshot and released once more
+        assert!(table.bind(teid, 1, vec![], Some(remote(100, 1))));
+        table.release(teid);
+        clock.advance(GRACE / 2);
+        table.allocator.lock().unwrap().next = teid;
+        assert_ne!(table.allocate(2, vec![]), Some(teid));
+        clock.advance(GRACE / 2);
+        table.allocator.lock().unwrap().next = teid;
+        assert_eq!(table.allocate(3, vec![]), Some(teid));
+    }
+
+    #[test]
+    fn bind_does_not_take_over_a_bound_teid() {
+        let table = TunnelTable::default();
+        let teid = table.allocate(1, vec![9]).unwrap();
+        assert!(!table.bind(teid, 2, vec![5], Some(remote(100, 1))));
+        assert!(!table.bind(teid, 1, vec![5], Some(remote(100, 1))));
+        assert_eq!(table.lookup(teid, |entry| (entry.pdu_session_id, entry.qfis.clone(), entry.remote)), Some((1, vec![9], None)));
+    }
+
+    #[test]
+    fn allocation_starts_at_a_random_teid() {
+        let starts: HashSet<Teid> = (0..8).map(|_| TunnelTable::default().allocate(1, vec![]).unwrap()).collect();
+        assert!(starts.len() > 1);
+        assert!(!starts.contains(&0));
+    }
+
+    #[test]
+    fn replaced_remote_drains_until_flushed() {
+        let table = TunnelTable::default();
+        let teid = table.allocate(1, vec![9]).unwrap();
+        let (source, target) = (remote(100, 1), remote(200, 2));
+        assert!(table.set_remote(teid, source));
+        // Signalling the same remote again is not a handover
+        assert!(table.set_remote(teid, source));
+        assert_eq!(table.lookup(teid, |entry| entry.draining), Some(None));
+        assert!(table.set_remote(teid, target));
+        assert_eq!(table.lookup(teid, |entry| (entry.remote, entry.draining)), Some((Some(target), Some(source))));
+        assert_eq!(table.remote_peers(), HashSet::from([source.addr, target.addr]));
+        assert_eq!(table.find_by_remote(source), None);
+        assert_eq!(table.find_by_remote(target), Some((teid, 1)));
+        assert_eq!(table.flush_draining(teid), Some(source));
+        assert_eq!(table.flush_draining(teid), None);
+        assert_eq!(table.remote_peers(), HashSet::from([target.addr]));
+        assert!(!table.set_remote(teid + 1, target));
+        table.clear_remote(teid);
+        assert_eq!(table.lookup(teid, |entry| (entry.remote, entry.draining)), Some((None, None)));
+    }
+}